@@ -0,0 +1,385 @@
+// Android sparse images, as produced by img2simg, served read-only on top of another AsyncDisk.
+//
+// The image is a header followed by chunks that each describe a run of blocks: raw data stored in
+// the file, a repeated 4 byte fill pattern, blocks nobody cares about (read as zeroes), or a CRC
+// of the data so far. CRCs aren't checked.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use crate::disk::{self, AsyncDisk, DiskFuture};
+
+const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const MAJOR_VERSION: u16 = 1;
+const HEADER_LEN: usize = 28;
+const CHUNK_HEADER_LEN: usize = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+#[derive(Debug)]
+pub enum Error {
+    BadMagic(u32),
+    UnsupportedVersion(u16),
+    /// The header or chunk header sizes are smaller than the format's.
+    BadHeaderSize,
+    /// The block size isn't a non-zero multiple of 4.
+    BadBlockSize(u32),
+    UnknownChunkType(u16),
+    /// The chunk at this index has a total size that doesn't match its type and length.
+    BadChunkSize(u32),
+    /// The chunks describe this many blocks instead of the number in the header.
+    BlockCountMismatch(u64),
+    /// Sparse images can't be modified.
+    ReadOnly,
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BadMagic(magic) => write!(f, "bad magic {:#x}", magic),
+            UnsupportedVersion(v) => write!(f, "unsupported major version {}", v),
+            BadHeaderSize => write!(f, "header sizes are too small"),
+            BadBlockSize(size) => write!(f, "bad block size {}", size),
+            UnknownChunkType(t) => write!(f, "unknown chunk type {:#x}", t),
+            BadChunkSize(index) => write!(f, "chunk {} has a bad size", index),
+            BlockCountMismatch(blocks) => {
+                write!(f, "chunks cover {} blocks, not the header's count", blocks)
+            }
+            ReadOnly => write!(f, "sparse images are read-only"),
+            Io(e) => write!(f, "image I/O failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(b)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Chunk {
+    // Data stored at this offset in the image file.
+    Raw(u64),
+    Fill([u8; 4]),
+    DontCare,
+}
+
+#[derive(Debug)]
+struct ChunkRun {
+    chunk: Chunk,
+    len: u64,
+}
+
+/// An Android sparse image presented as the disk it expands to.
+pub struct AndroidSparse {
+    file: Box<dyn AsyncDisk>,
+    // Runs keyed by their offset in the expanded disk.
+    chunks: BTreeMap<u64, ChunkRun>,
+    size: u64,
+}
+
+impl AndroidSparse {
+    /// Reads the header and chunk list of the sparse image in `file`.
+    pub async fn open(file: Box<dyn AsyncDisk>) -> Result<AndroidSparse> {
+        let header = file
+            .read_at(0, vec![0u8; HEADER_LEN])
+            .await
+            .map_err(Error::Io)?;
+        let magic = le32(&header, 0);
+        if magic != SPARSE_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        let major_version = le16(&header, 4);
+        if major_version != MAJOR_VERSION {
+            return Err(Error::UnsupportedVersion(major_version));
+        }
+        let header_len = le16(&header, 8) as u64;
+        let chunk_header_len = le16(&header, 10) as u64;
+        if header_len < HEADER_LEN as u64 || chunk_header_len < CHUNK_HEADER_LEN as u64 {
+            return Err(Error::BadHeaderSize);
+        }
+        let block_size = le32(&header, 12);
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(Error::BadBlockSize(block_size));
+        }
+        let total_blocks = le32(&header, 16) as u64;
+        let total_chunks = le32(&header, 20);
+
+        let mut chunks = BTreeMap::new();
+        let mut file_offset = header_len;
+        let mut blocks = 0u64;
+        for index in 0..total_chunks {
+            let chunk_header = file
+                .read_at(file_offset, vec![0u8; CHUNK_HEADER_LEN])
+                .await
+                .map_err(Error::Io)?;
+            let chunk_type = le16(&chunk_header, 0);
+            let chunk_blocks = le32(&chunk_header, 4) as u64;
+            let total_size = le32(&chunk_header, 8) as u64;
+            let data_offset = file_offset + chunk_header_len;
+            let len = chunk_blocks * block_size as u64;
+            let (chunk, data_len) = match chunk_type {
+                CHUNK_TYPE_RAW => (Some(Chunk::Raw(data_offset)), len),
+                CHUNK_TYPE_FILL => {
+                    let pattern = file
+                        .read_at(data_offset, vec![0u8; 4])
+                        .await
+                        .map_err(Error::Io)?;
+                    let mut fill = [0u8; 4];
+                    fill.copy_from_slice(&pattern);
+                    (Some(Chunk::Fill(fill)), 4)
+                }
+                CHUNK_TYPE_DONT_CARE => (Some(Chunk::DontCare), 0),
+                CHUNK_TYPE_CRC32 => (None, 4),
+                t => return Err(Error::UnknownChunkType(t)),
+            };
+            if total_size != chunk_header_len + data_len {
+                return Err(Error::BadChunkSize(index));
+            }
+            if let Some(chunk) = chunk {
+                // Stop at the first chunk past the end, before the offsets can overflow.
+                let end = blocks
+                    .checked_add(chunk_blocks)
+                    .filter(|&end| end <= total_blocks)
+                    .ok_or(Error::BlockCountMismatch(
+                        blocks.saturating_add(chunk_blocks),
+                    ))?;
+                if len > 0 {
+                    chunks.insert(blocks * block_size as u64, ChunkRun { chunk, len });
+                }
+                blocks = end;
+            }
+            file_offset += total_size;
+        }
+        if blocks != total_blocks {
+            return Err(Error::BlockCountMismatch(blocks));
+        }
+
+        Ok(AndroidSparse {
+            file,
+            chunks,
+            size: total_blocks * block_size as u64,
+        })
+    }
+
+    async fn read(&self, offset: u64, mut buf: Vec<u8>) -> Result<Vec<u8>> {
+        disk::check_range(offset, buf.len() as u64, self.size).map_err(Error::Io)?;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let disk_offset = offset + pos as u64;
+            // The chunks cover the whole disk, so one always starts at or before any offset.
+            let (&start, run) = self.chunks.range(..=disk_offset).next_back().unwrap();
+            let in_chunk = disk_offset - start;
+            let count = ((run.len - in_chunk) as usize).min(buf.len() - pos);
+            let dst = &mut buf[pos..pos + count];
+            match run.chunk {
+                Chunk::Raw(data_offset) => {
+                    let data = self
+                        .file
+                        .read_at(data_offset + in_chunk, vec![0u8; count])
+                        .await
+                        .map_err(Error::Io)?;
+                    dst.copy_from_slice(&data);
+                }
+                Chunk::Fill(pattern) => {
+                    // Chunks start on block boundaries and blocks are a multiple of 4 bytes.
+                    for (i, b) in dst.iter_mut().enumerate() {
+                        *b = pattern[(in_chunk as usize + i) % 4];
+                    }
+                }
+                Chunk::DontCare => dst.iter_mut().for_each(|b| *b = 0),
+            }
+            pos += count;
+        }
+        Ok(buf)
+    }
+}
+
+impl AsyncDisk for AndroidSparse {
+    fn read_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, Vec<u8>> {
+        Box::pin(async move { Ok(self.read(offset, buf).await?) })
+    }
+
+    fn write_at(&self, _offset: u64, _buf: Vec<u8>) -> DiskFuture<'_, ()> {
+        Box::pin(async { Err(Error::ReadOnly.into()) })
+    }
+
+    fn flush(&self) -> DiskFuture<'_, ()> {
+        // Nothing is ever written.
+        Box::pin(async { Ok(()) })
+    }
+
+    fn punch_hole(&self, _offset: u64, _len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async { Err(Error::ReadOnly.into()) })
+    }
+
+    fn write_zeroes_at(&self, _offset: u64, _len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async { Err(Error::ReadOnly.into()) })
+    }
+
+    fn get_len(&self) -> DiskFuture<'_, u64> {
+        let size = self.size;
+        Box::pin(async move { Ok(size) })
+    }
+
+    fn set_len(&self, _len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async { Err(Error::ReadOnly.into()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use tempfile::tempfile;
+
+    use crate::blocking::BlockingPool;
+    use crate::disk::SingleFileDisk;
+
+    const BLOCK: usize = 16;
+
+    fn header(total_blocks: u32, total_chunks: u32) -> Vec<u8> {
+        let mut h = SPARSE_MAGIC.to_le_bytes().to_vec();
+        h.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        h.extend_from_slice(&0u16.to_le_bytes());
+        h.extend_from_slice(&(HEADER_LEN as u16).to_le_bytes());
+        h.extend_from_slice(&(CHUNK_HEADER_LEN as u16).to_le_bytes());
+        h.extend_from_slice(&(BLOCK as u32).to_le_bytes());
+        h.extend_from_slice(&total_blocks.to_le_bytes());
+        h.extend_from_slice(&total_chunks.to_le_bytes());
+        h.extend_from_slice(&0u32.to_le_bytes());
+        h
+    }
+
+    fn chunk(chunk_type: u16, blocks: u32, data: &[u8]) -> Vec<u8> {
+        let mut c = chunk_type.to_le_bytes().to_vec();
+        c.extend_from_slice(&0u16.to_le_bytes());
+        c.extend_from_slice(&blocks.to_le_bytes());
+        c.extend_from_slice(&((CHUNK_HEADER_LEN + data.len()) as u32).to_le_bytes());
+        c.extend_from_slice(data);
+        c
+    }
+
+    fn open(image: &[u8]) -> Result<AndroidSparse> {
+        let mut file = tempfile().unwrap();
+        file.write_all(image).unwrap();
+        let disk = SingleFileDisk::new(file, Arc::new(BlockingPool::new(1)));
+        block_on(AndroidSparse::open(Box::new(disk)))
+    }
+
+    // One raw block of 1s, two blocks filled with 1 2 3 4, a don't-care block, a CRC and two raw
+    // blocks of 5s.
+    fn image() -> Vec<u8> {
+        [
+            header(6, 5),
+            chunk(CHUNK_TYPE_RAW, 1, &[1u8; BLOCK]),
+            chunk(CHUNK_TYPE_FILL, 2, &[1, 2, 3, 4]),
+            chunk(CHUNK_TYPE_DONT_CARE, 1, &[]),
+            chunk(CHUNK_TYPE_CRC32, 0, &[0xaa; 4]),
+            chunk(CHUNK_TYPE_RAW, 2, &[5u8; 2 * BLOCK]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn read_chunks() {
+        let disk = open(&image()).unwrap();
+        block_on(async {
+            assert_eq!(disk.get_len().await.unwrap(), 6 * BLOCK as u64);
+            let buf = disk.read_at(0, vec![9u8; 6 * BLOCK]).await.unwrap();
+            let fill: Vec<u8> = [1, 2, 3, 4].repeat(2 * BLOCK / 4);
+            let expected = [&[1u8; BLOCK][..], &fill, &[0; BLOCK], &[5; 2 * BLOCK]].concat();
+            assert_eq!(buf, expected);
+            // Unaligned reads across chunks.
+            let buf = disk.read_at(14, vec![0u8; 5]).await.unwrap();
+            assert_eq!(buf, [1, 1, 1, 2, 3]);
+            let buf = disk.read_at(62, vec![0u8; 4]).await.unwrap();
+            assert_eq!(buf, [0, 0, 5, 5]);
+            assert!(disk
+                .read_at(6 * BLOCK as u64 - 1, vec![0u8; 2])
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn read_only() {
+        let disk = open(&image()).unwrap();
+        block_on(async {
+            assert!(disk.write_at(0, vec![0u8; 4]).await.is_err());
+            assert!(disk.punch_hole(0, 4).await.is_err());
+            assert!(disk.set_len(0).await.is_err());
+            disk.flush().await.unwrap();
+            assert_eq!(disk.read_at(0, vec![0u8; 1]).await.unwrap(), [1]);
+        });
+    }
+
+    #[test]
+    fn bad_images() {
+        let mut bad_magic = image();
+        bad_magic[0] = 0;
+        match open(&bad_magic) {
+            Err(Error::BadMagic(_)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        let short_count = [header(7, 1), chunk(CHUNK_TYPE_DONT_CARE, 6, &[])].concat();
+        match open(&short_count) {
+            Err(Error::BlockCountMismatch(6)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        let truncated_raw = [header(1, 1), chunk(CHUNK_TYPE_RAW, 1, &[0u8; 4])].concat();
+        match open(&truncated_raw) {
+            Err(Error::BadChunkSize(0)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        let long_count = [header(7, 1), chunk(CHUNK_TYPE_DONT_CARE, 8, &[])].concat();
+        match open(&long_count) {
+            Err(Error::BlockCountMismatch(8)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        // Huge blocks and chunks whose offsets don't fit in a u64.
+        let mut huge = header(7, 4);
+        huge[12..16].copy_from_slice(&0xffff_fffcu32.to_le_bytes());
+        for _ in 0..4 {
+            huge.extend(chunk(CHUNK_TYPE_DONT_CARE, u32::MAX, &[]));
+        }
+        match open(&huge) {
+            Err(Error::BlockCountMismatch(_)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        let unknown = [header(1, 1), chunk(0x1234, 1, &[])].concat();
+        match open(&unknown) {
+            Err(Error::UnknownChunkType(0x1234)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+    }
+}
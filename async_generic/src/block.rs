@@ -0,0 +1,385 @@
+// Virtio block requests and the device that hands them to an async backend.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::rc::Rc;
+
+use crate::device::{DeviceFuture, VirtioDevice};
+
+pub const SECTOR_SHIFT: u8 = 9;
+pub const SECTOR_SIZE: u64 = 1 << SECTOR_SHIFT;
+
+// Request types from the virtio spec.
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Status written to the last byte of every request.
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+const VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+// sector: le64, num_sectors: le32, flags: le32.
+const SEGMENT_SIZE: usize = 16;
+
+/// A request's data buffers in descriptor order.
+pub type SgList = Vec<Vec<u8>>;
+
+/// Total bytes in `bufs`.
+pub fn sg_len(bufs: &[Vec<u8>]) -> usize {
+    bufs.iter().map(Vec::len).sum()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// A read or write that isn't a whole number of sectors.
+    PartialSector(usize),
+    /// A discard or write-zeroes payload that isn't a list of segments.
+    InvalidSegments(usize),
+    /// The buffer for the device id is too short.
+    IdBufferTooSmall(usize),
+    /// The request goes past the end of the disk.
+    OutOfRange { sector: u64, num_sectors: u64 },
+    /// A request type the device or its backend doesn't implement.
+    Unsupported(u32),
+    /// The backend failed.
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            PartialSector(len) => write!(f, "{} bytes isn't a multiple of the sector size", len),
+            InvalidSegments(len) => write!(f, "{} bytes isn't a list of segments", len),
+            IdBufferTooSmall(len) => write!(f, "id buffer of {} bytes is too small", len),
+            OutOfRange {
+                sector,
+                num_sectors,
+            } => write!(
+                f,
+                "{} sectors at sector {} are past the end of the disk",
+                num_sectors, sector
+            ),
+            Unsupported(req_type) => write!(f, "unsupported request type {}", req_type),
+            Io(e) => write!(f, "backend failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// The virtio-blk status reported to the driver for this error.
+    pub fn status(&self) -> u8 {
+        match self {
+            Error::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            _ => VIRTIO_BLK_S_IOERR,
+        }
+    }
+}
+
+/// A range of sectors to discard or zero.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub sector: u64,
+    pub num_sectors: u32,
+    /// The backend may deallocate the range instead of writing zeroes.
+    pub unmap: bool,
+}
+
+impl Segment {
+    fn parse(bytes: &[u8]) -> Segment {
+        let mut sector = [0u8; 8];
+        let mut num_sectors = [0u8; 4];
+        let mut flags = [0u8; 4];
+        sector.copy_from_slice(&bytes[0..8]);
+        num_sectors.copy_from_slice(&bytes[8..12]);
+        flags.copy_from_slice(&bytes[12..16]);
+        Segment {
+            sector: u64::from_le_bytes(sector),
+            num_sectors: u32::from_le_bytes(num_sectors),
+            unmap: u32::from_le_bytes(flags) & VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP != 0,
+        }
+    }
+
+    // The segment's range in bytes must fit in a u64 for `offset` and `len` to be meaningful.
+    fn check(&self) -> Result<()> {
+        let end = self.sector.checked_add(u64::from(self.num_sectors));
+        if end.and_then(|end| end.checked_mul(SECTOR_SIZE)).is_none() {
+            return Err(Error::OutOfRange {
+                sector: self.sector,
+                num_sectors: u64::from(self.num_sectors),
+            });
+        }
+        Ok(())
+    }
+
+    /// Byte offset of the first sector. Segments from `Request::parse` have already been checked
+    /// to fit.
+    pub fn offset(&self) -> u64 {
+        self.sector << SECTOR_SHIFT
+    }
+
+    /// Length in bytes.
+    pub fn len(&self) -> u64 {
+        u64::from(self.num_sectors) << SECTOR_SHIFT
+    }
+}
+
+/// A parsed virtio-blk request.
+#[derive(Debug)]
+pub enum Request {
+    /// Read into `bufs`, which the backend fills and returns.
+    Read {
+        sector: u64,
+        bufs: SgList,
+    },
+    /// Write the contents of `bufs`.
+    Write {
+        sector: u64,
+        bufs: SgList,
+    },
+    Flush,
+    Discard {
+        segments: Vec<Segment>,
+    },
+    WriteZeroes {
+        segments: Vec<Segment>,
+    },
+    /// Return the device's id, at most `VIRTIO_BLK_ID_BYTES` long.
+    GetId,
+}
+
+impl Request {
+    /// Builds a request from the header's type and sector, the device-readable data `out` and
+    /// the lengths of the device-writable buffers `in_lens`, not counting the status byte.
+    pub fn parse(req_type: u32, sector: u64, out: SgList, in_lens: &[usize]) -> Result<Request> {
+        match req_type {
+            VIRTIO_BLK_T_IN => {
+                let len = in_lens.iter().sum();
+                check_sectors(len)?;
+                Ok(Request::Read {
+                    sector,
+                    bufs: in_lens.iter().map(|&len| vec![0u8; len]).collect(),
+                })
+            }
+            VIRTIO_BLK_T_OUT => {
+                check_sectors(sg_len(&out))?;
+                Ok(Request::Write { sector, bufs: out })
+            }
+            VIRTIO_BLK_T_FLUSH => Ok(Request::Flush),
+            VIRTIO_BLK_T_GET_ID => {
+                let len = in_lens.iter().sum();
+                if len < VIRTIO_BLK_ID_BYTES {
+                    return Err(Error::IdBufferTooSmall(len));
+                }
+                Ok(Request::GetId)
+            }
+            VIRTIO_BLK_T_DISCARD => Ok(Request::Discard {
+                segments: parse_segments(out)?,
+            }),
+            VIRTIO_BLK_T_WRITE_ZEROES => Ok(Request::WriteZeroes {
+                segments: parse_segments(out)?,
+            }),
+            t => Err(Error::Unsupported(t)),
+        }
+    }
+
+    /// The request type from the virtio spec.
+    pub fn req_type(&self) -> u32 {
+        match self {
+            Request::Read { .. } => VIRTIO_BLK_T_IN,
+            Request::Write { .. } => VIRTIO_BLK_T_OUT,
+            Request::Flush => VIRTIO_BLK_T_FLUSH,
+            Request::Discard { .. } => VIRTIO_BLK_T_DISCARD,
+            Request::WriteZeroes { .. } => VIRTIO_BLK_T_WRITE_ZEROES,
+            Request::GetId => VIRTIO_BLK_T_GET_ID,
+        }
+    }
+}
+
+fn check_sectors(len: usize) -> Result<()> {
+    if !(len as u64).is_multiple_of(SECTOR_SIZE) {
+        return Err(Error::PartialSector(len));
+    }
+    Ok(())
+}
+
+fn parse_segments(out: SgList) -> Result<Vec<Segment>> {
+    let bytes = out.concat();
+    if bytes.is_empty() || !bytes.len().is_multiple_of(SEGMENT_SIZE) {
+        return Err(Error::InvalidSegments(bytes.len()));
+    }
+    let segments: Vec<Segment> = bytes.chunks(SEGMENT_SIZE).map(Segment::parse).collect();
+    for segment in &segments {
+        segment.check()?;
+    }
+    Ok(segments)
+}
+
+/// What the device writes back for a request.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u8,
+    /// Data for the device-writable buffers: the sectors read or the id.
+    pub data: SgList,
+}
+
+impl Response {
+    /// Bytes written to the driver, the data plus the status byte.
+    pub fn used_len(&self) -> usize {
+        sg_len(&self.data) + 1
+    }
+}
+
+/// A request as the driver lays it out, before it's parsed.
+pub struct RawRequest {
+    pub req_type: u32,
+    pub sector: u64,
+    /// The device-readable buffers after the header.
+    pub out: SgList,
+    /// Lengths of the device-writable buffers before the status byte.
+    pub in_lens: Vec<usize>,
+}
+
+/// Requests waiting for the device and the responses it has finished, in order.
+#[derive(Default)]
+pub struct RequestQueue {
+    pending: RefCell<VecDeque<RawRequest>>,
+    used: RefCell<Vec<Response>>,
+}
+
+impl RequestQueue {
+    pub fn submit(&self, request: RawRequest) {
+        self.pending.borrow_mut().push_back(request);
+    }
+
+    pub fn take_used(&self) -> Vec<Response> {
+        self.used.borrow_mut().drain(..).collect()
+    }
+
+    pub fn pop(&self) -> Option<RawRequest> {
+        self.pending.borrow_mut().pop_front()
+    }
+
+    pub fn push_used(&self, response: Response) {
+        self.used.borrow_mut().push(response);
+    }
+}
+
+/// A block device that passes each request to `process_op` and turns its result into a status.
+pub struct Block<F, Fut>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Result<SgList>>,
+{
+    process_op: F,
+    queue: Rc<RequestQueue>,
+}
+
+impl<F, Fut> Block<F, Fut>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Result<SgList>>,
+{
+    pub fn new(f: F, queue: Rc<RequestQueue>) -> Self {
+        Block {
+            process_op: f,
+            queue,
+        }
+    }
+
+    /// Answers every request waiting in the queue. Requests that don't parse get the error's
+    /// status without reaching the backend.
+    pub async fn process_queue(&self) {
+        while let Some(raw) = self.queue.pop() {
+            let response = match Request::parse(raw.req_type, raw.sector, raw.out, &raw.in_lens) {
+                Ok(request) => self.do_op(request).await,
+                Err(e) => Response {
+                    status: e.status(),
+                    data: Vec::new(),
+                },
+            };
+            self.queue.push_used(response);
+        }
+    }
+
+    /// Runs `request` on the backend. The backend returns the filled buffers for reads, the id for
+    /// get-id and nothing for the other requests.
+    pub async fn do_op(&self, request: Request) -> Response {
+        match (self.process_op)(request).await {
+            Ok(data) => Response {
+                status: VIRTIO_BLK_S_OK,
+                data,
+            },
+            Err(e) => Response {
+                status: e.status(),
+                data: Vec::new(),
+            },
+        }
+    }
+}
+
+impl<F, Fut> VirtioDevice for Block<F, Fut>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Result<SgList>>,
+{
+    fn run(&self) -> DeviceFuture<'_> {
+        Box::pin(self.process_queue())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment_bytes(sector: u64, num_sectors: u32, flags: u32) -> Vec<u8> {
+        let mut bytes = sector.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&num_sectors.to_le_bytes());
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parse_segments() {
+        let out = vec![segment_bytes(8, 2, 0), segment_bytes(1, 1, 1)];
+        let request = Request::parse(VIRTIO_BLK_T_DISCARD, 0, out, &[]).unwrap();
+        assert_eq!(request.req_type(), VIRTIO_BLK_T_DISCARD);
+        match request {
+            Request::Discard { segments } => {
+                assert_eq!(segments[0].offset(), 8 * SECTOR_SIZE);
+                assert_eq!(segments[0].len(), 2 * SECTOR_SIZE);
+                assert!(!segments[0].unmap);
+                assert!(segments[1].unmap);
+            }
+            r => panic!("unexpected request {:?}", r),
+        }
+    }
+
+    #[test]
+    fn segment_past_u64() {
+        for (sector, num_sectors) in [(u64::MAX >> 8, 1), (u64::MAX >> 9, 2), (u64::MAX, 0)] {
+            let out = vec![segment_bytes(sector, num_sectors, 0)];
+            let e = Request::parse(VIRTIO_BLK_T_WRITE_ZEROES, 0, out, &[]).unwrap_err();
+            match e {
+                Error::OutOfRange { sector: s, .. } if s == sector => (),
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert_eq!(e.status(), VIRTIO_BLK_S_IOERR);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Threads for running blocking calls on behalf of futures.
+
+use std::future::Future;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use futures::channel::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs closures on a fixed set of threads and hands their results back to the awaiting future.
+/// The futures don't depend on any executor, they are woken from the pool's threads.
+pub struct BlockingPool {
+    sender: Mutex<Option<Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl BlockingPool {
+    pub fn new(threads: usize) -> BlockingPool {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || worker(receiver))
+            })
+            .collect();
+        BlockingPool {
+            sender: Mutex::new(Some(sender)),
+            workers,
+        }
+    }
+
+    /// Runs `f` on a pool thread. The future fails if `f` panics.
+    pub fn spawn<T, F>(&self, f: F) -> impl Future<Output = io::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // The receiver is gone if the future was dropped, nobody wants the result.
+            let _ = tx.send(f());
+        });
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            // Workers only exit once the sender is dropped.
+            sender.send(job).unwrap();
+        }
+        async move {
+            rx.await
+                .map_err(|_| io::Error::other("blocking call didn't complete"))
+        }
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            // A panicking job drops its sender, failing only its own future.
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        // Closing the channel lets the workers finish the queued jobs and exit.
+        self.sender.lock().unwrap().take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
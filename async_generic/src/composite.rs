@@ -0,0 +1,333 @@
+// A virtual disk made of other disks placed at fixed offsets.
+
+use std::fmt;
+use std::io;
+
+use crate::disk::{self, AsyncDisk, DiskFuture};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The component at this offset overlaps the one before it.
+    Overlap(u64),
+    /// The component at this offset ends past the end of the disk.
+    PastEnd(u64),
+    /// A write to this offset, which isn't backed by any component.
+    WriteToGap(u64),
+    /// Composite disks can't be resized.
+    Resize,
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Overlap(offset) => write!(f, "component at {:#x} overlaps the previous one", offset),
+            PastEnd(offset) => write!(f, "component at {:#x} ends past the disk", offset),
+            WriteToGap(offset) => write!(f, "write to {:#x} isn't backed by a component", offset),
+            Resize => write!(f, "composite disks can't be resized"),
+            Io(e) => write!(f, "component I/O failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+struct Component {
+    offset: u64,
+    len: u64,
+    disk: Box<dyn AsyncDisk>,
+}
+
+// Part of an access that falls in one component, or in a gap if `component` is `None`.
+struct Piece {
+    component: Option<usize>,
+    // Offset in the component, or in the composite disk for a gap.
+    offset: u64,
+    len: u64,
+}
+
+/// Presents components, such as partitions kept in separate files, as one disk. Each component
+/// covers `[offset, offset + its length)` of the disk. Ranges not covered by any component read
+/// as zeroes and can't be written.
+pub struct CompositeDisk {
+    components: Vec<Component>,
+    len: u64,
+}
+
+impl CompositeDisk {
+    /// Places each disk at its offset. The composite disk is `len` bytes, or ends with the last
+    /// component if `len` is `None`.
+    pub async fn new(
+        mut components: Vec<(u64, Box<dyn AsyncDisk>)>,
+        len: Option<u64>,
+    ) -> Result<CompositeDisk> {
+        components.sort_by_key(|(offset, _)| *offset);
+        let mut placed: Vec<Component> = Vec::with_capacity(components.len());
+        for (offset, disk) in components {
+            if let Some(prev) = placed.last() {
+                if prev.offset + prev.len > offset {
+                    return Err(Error::Overlap(offset));
+                }
+            }
+            let component_len = disk.get_len().await.map_err(Error::Io)?;
+            // Checked once here, so every `offset + len` after this fits.
+            if offset.checked_add(component_len).is_none() {
+                return Err(Error::PastEnd(offset));
+            }
+            placed.push(Component {
+                offset,
+                len: component_len,
+                disk,
+            });
+        }
+        let end = placed.last().map_or(0, |c| c.offset + c.len);
+        let len = len.unwrap_or(end);
+        if let Some(c) = placed.iter().find(|c| c.offset + c.len > len) {
+            return Err(Error::PastEnd(c.offset));
+        }
+        Ok(CompositeDisk {
+            components: placed,
+            len,
+        })
+    }
+
+    // Splits `[offset, offset + len)` into the components and gaps it touches.
+    fn split(&self, offset: u64, len: u64) -> Result<Vec<Piece>> {
+        disk::check_range(offset, len, self.len).map_err(Error::Io)?;
+        let end = offset + len;
+        let mut pieces = Vec::new();
+        let mut pos = offset;
+        // The first component that ends after `pos`.
+        let mut index = self.components.partition_point(|c| c.offset + c.len <= pos);
+        while pos < end {
+            match self.components.get(index) {
+                Some(c) if c.offset <= pos => {
+                    let count = (c.offset + c.len).min(end) - pos;
+                    pieces.push(Piece {
+                        component: Some(index),
+                        offset: pos - c.offset,
+                        len: count,
+                    });
+                    pos += count;
+                    index += 1;
+                }
+                next => {
+                    let gap_end = next.map_or(end, |c| c.offset.min(end));
+                    pieces.push(Piece {
+                        component: None,
+                        offset: pos,
+                        len: gap_end - pos,
+                    });
+                    pos = gap_end;
+                }
+            }
+        }
+        Ok(pieces)
+    }
+
+    async fn read(&self, offset: u64, mut buf: Vec<u8>) -> Result<Vec<u8>> {
+        let mut pos = 0;
+        for piece in self.split(offset, buf.len() as u64)? {
+            let len = piece.len as usize;
+            match piece.component {
+                Some(i) => {
+                    let data = self.components[i]
+                        .disk
+                        .read_at(piece.offset, vec![0u8; len])
+                        .await
+                        .map_err(Error::Io)?;
+                    buf[pos..pos + len].copy_from_slice(&data);
+                }
+                None => buf[pos..pos + len].iter_mut().for_each(|b| *b = 0),
+            }
+            pos += len;
+        }
+        Ok(buf)
+    }
+
+    async fn write(&self, offset: u64, buf: Vec<u8>) -> Result<()> {
+        let pieces = self.split(offset, buf.len() as u64)?;
+        if let Some(gap) = pieces.iter().find(|p| p.component.is_none()) {
+            return Err(Error::WriteToGap(gap.offset));
+        }
+        let mut pos = 0;
+        for piece in pieces {
+            let len = piece.len as usize;
+            let i = piece.component.unwrap();
+            self.components[i]
+                .disk
+                .write_at(piece.offset, buf[pos..pos + len].to_vec())
+                .await
+                .map_err(Error::Io)?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    // Zeroes the parts of the range in components, gaps are zeroes already.
+    async fn zero(&self, offset: u64, len: u64, punch: bool) -> Result<()> {
+        for piece in self.split(offset, len)? {
+            if let Some(i) = piece.component {
+                let disk = &self.components[i].disk;
+                let zeroed = if punch {
+                    disk.punch_hole(piece.offset, piece.len).await
+                } else {
+                    disk.write_zeroes_at(piece.offset, piece.len).await
+                };
+                zeroed.map_err(Error::Io)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush_all(&self) -> Result<()> {
+        for c in &self.components {
+            c.disk.flush().await.map_err(Error::Io)?;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncDisk for CompositeDisk {
+    fn read_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, Vec<u8>> {
+        Box::pin(async move { Ok(self.read(offset, buf).await?) })
+    }
+
+    fn write_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.write(offset, buf).await?) })
+    }
+
+    fn flush(&self) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.flush_all().await?) })
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.zero(offset, len, true).await?) })
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.zero(offset, len, false).await?) })
+    }
+
+    fn get_len(&self) -> DiskFuture<'_, u64> {
+        let len = self.len;
+        Box::pin(async move { Ok(len) })
+    }
+
+    fn set_len(&self, _len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async { Err(Error::Resize.into()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use tempfile::tempfile;
+
+    use crate::blocking::BlockingPool;
+    use crate::disk::SingleFileDisk;
+
+    fn part(contents: &[u8], pool: &Arc<BlockingPool>) -> (File, Box<dyn AsyncDisk>) {
+        let mut file = tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        let disk = Box::new(SingleFileDisk::new(file.try_clone().unwrap(), pool.clone()));
+        (file, disk)
+    }
+
+    // [0, 100) from a, [100, 150) a gap, [150, 250) from b, [250, 300) a gap.
+    fn composite(pool: &Arc<BlockingPool>) -> CompositeDisk {
+        let (_, a) = part(&[1u8; 100], pool);
+        let (_, b) = part(&[2u8; 100], pool);
+        block_on(CompositeDisk::new(vec![(150, b), (0, a)], Some(300))).unwrap()
+    }
+
+    #[test]
+    fn read_across_components() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let disk = composite(&pool);
+        block_on(async {
+            assert_eq!(disk.get_len().await.unwrap(), 300);
+            let buf = disk.read_at(0, vec![9u8; 300]).await.unwrap();
+            assert_eq!(
+                buf,
+                [&[1u8; 100][..], &[0; 50], &[2; 100], &[0; 50]].concat()
+            );
+            assert!(disk.read_at(299, vec![0u8; 2]).await.is_err());
+        });
+    }
+
+    #[test]
+    fn write_across_boundary() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let (a_file, a) = part(&[1u8; 100], &pool);
+        let (b_file, b) = part(&[2u8; 100], &pool);
+        let disk = block_on(CompositeDisk::new(vec![(0, a), (100, b)], None)).unwrap();
+        block_on(async {
+            disk.write_at(90, vec![5u8; 20]).await.unwrap();
+            let buf = disk.read_at(85, vec![0u8; 30]).await.unwrap();
+            assert_eq!(buf, [&[1u8; 5][..], &[5; 20], &[2; 5]].concat());
+            disk.write_zeroes_at(95, 10).await.unwrap();
+        });
+        let mut a_data = vec![0u8; 100];
+        let mut b_data = vec![0u8; 100];
+        std::os::unix::fs::FileExt::read_exact_at(&a_file, &mut a_data, 0).unwrap();
+        std::os::unix::fs::FileExt::read_exact_at(&b_file, &mut b_data, 0).unwrap();
+        assert_eq!(a_data[90..], [5, 5, 5, 5, 5, 0, 0, 0, 0, 0]);
+        assert_eq!(b_data[..12], [0, 0, 0, 0, 0, 5, 5, 5, 5, 5, 2, 2]);
+    }
+
+    #[test]
+    fn gaps() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let disk = composite(&pool);
+        block_on(async {
+            assert!(disk.write_at(140, vec![1u8; 20]).await.is_err());
+            // Nothing was written to b either.
+            assert_eq!(disk.read_at(150, vec![0u8; 10]).await.unwrap(), [2u8; 10]);
+            disk.punch_hole(90, 70).await.unwrap();
+            let buf = disk.read_at(80, vec![9u8; 90]).await.unwrap();
+            assert_eq!(buf, [&[1u8; 10][..], &[0; 70], &[2; 10]].concat());
+        });
+    }
+
+    #[test]
+    fn bad_layouts() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let (_, a) = part(&[1u8; 100], &pool);
+        let (_, b) = part(&[2u8; 100], &pool);
+        match block_on(CompositeDisk::new(vec![(0, a), (50, b)], None)) {
+            Err(Error::Overlap(50)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        let (_, a) = part(&[1u8; 100], &pool);
+        match block_on(CompositeDisk::new(vec![(10, a)], Some(100))) {
+            Err(Error::PastEnd(10)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+        let (_, a) = part(&[1u8; 100], &pool);
+        let (_, b) = part(&[2u8; 100], &pool);
+        match block_on(CompositeDisk::new(vec![(0, a), (u64::MAX - 10, b)], None)) {
+            Err(Error::PastEnd(offset)) => assert_eq!(offset, u64::MAX - 10),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// Devices driven through trait objects, and the manager that runs them.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::future::poll_fn;
+use futures::stream::{FuturesUnordered, StreamExt};
+
+/// The future returned by `VirtioDevice` methods.
+pub type DeviceFuture<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+/// A device the VMM drives without knowing its type. Methods return boxed futures so the trait
+/// stays object safe and the caller picks the executor they run on.
+pub trait VirtioDevice {
+    /// Processes everything waiting in the device's queues.
+    fn run(&self) -> DeviceFuture<'_>;
+}
+
+/// Identifies a device plugged into a `DeviceManager`.
+pub type DeviceId = u32;
+
+struct Slot {
+    device: Rc<dyn VirtioDevice>,
+    kicked: bool,
+    // The device's loop, waiting for a kick.
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct ManagerState {
+    next_id: DeviceId,
+    devices: HashMap<DeviceId, Slot>,
+    // Plugged in but not yet picked up by `run`.
+    added: Vec<(DeviceId, Rc<dyn VirtioDevice>)>,
+    shutdown: bool,
+    run_waker: Option<Waker>,
+}
+
+impl ManagerState {
+    fn wake_run(&mut self) {
+        if let Some(waker) = self.run_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Runs devices concurrently on one executor. Each device gets a loop that processes its queues
+/// and then waits for a kick. Devices can be plugged and unplugged while the manager runs. Clones
+/// share the same devices, so a clone can control the manager from another task.
+#[derive(Clone, Default)]
+pub struct DeviceManager {
+    state: Rc<RefCell<ManagerState>>,
+}
+
+impl DeviceManager {
+    /// Creates a manager with `devices` plugged in.
+    pub fn new(devices: Vec<Box<dyn VirtioDevice>>) -> DeviceManager {
+        let manager = DeviceManager::default();
+        for device in devices {
+            manager.add(device);
+        }
+        manager
+    }
+
+    /// Plugs in `device`. It processes its queues as soon as the manager picks it up.
+    pub fn add(&self, device: Box<dyn VirtioDevice>) -> DeviceId {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        let device: Rc<dyn VirtioDevice> = Rc::from(device);
+        state.devices.insert(
+            id,
+            Slot {
+                device: device.clone(),
+                kicked: false,
+                waker: None,
+            },
+        );
+        state.added.push((id, device));
+        state.wake_run();
+        id
+    }
+
+    /// Unplugs device `id`, returning it or `None` if there is no such device. A device in the
+    /// middle of processing its queues keeps going until it's done, as long as `run` is polled,
+    /// and then isn't run again.
+    pub fn remove(&self, id: DeviceId) -> Option<Rc<dyn VirtioDevice>> {
+        let slot = {
+            let mut state = self.state.borrow_mut();
+            // A device `run` hasn't picked up yet is never run.
+            state.added.retain(|(added, _)| *added != id);
+            state.devices.remove(&id)?
+        };
+        if let Some(waker) = slot.waker {
+            waker.wake();
+        }
+        Some(slot.device)
+    }
+
+    /// Tells device `id` there are new requests, returning false if there is no such device.
+    pub fn kick(&self, id: DeviceId) -> bool {
+        let mut state = self.state.borrow_mut();
+        match state.devices.get_mut(&id) {
+            Some(slot) => {
+                slot.kicked = true;
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The devices currently plugged in.
+    pub fn device_ids(&self) -> Vec<DeviceId> {
+        let mut ids: Vec<DeviceId> = self.state.borrow().devices.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Unplugs every device and makes `run` return once the busy ones have finished. Dropping the
+    /// `run` future instead drops whatever the devices were in the middle of.
+    pub fn shutdown(&self) {
+        let ids = self.device_ids();
+        for id in ids {
+            self.remove(id);
+        }
+        let mut state = self.state.borrow_mut();
+        state.shutdown = true;
+        state.wake_run();
+    }
+
+    /// Drives the loops of all devices, including those plugged in later, until `shutdown` has
+    /// been called and no device is still busy.
+    pub async fn run(&self) {
+        let mut loops = FuturesUnordered::new();
+        poll_fn(|cx| {
+            let added = {
+                let mut state = self.state.borrow_mut();
+                state.run_waker = Some(cx.waker().clone());
+                std::mem::take(&mut state.added)
+            };
+            for (id, device) in added {
+                loops.push(self.device_loop(id, device));
+            }
+            while let Poll::Ready(Some(())) = loops.poll_next_unpin(cx) {}
+            let state = self.state.borrow();
+            if state.shutdown && state.added.is_empty() && loops.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn device_loop(&self, id: DeviceId, device: Rc<dyn VirtioDevice>) {
+        loop {
+            device.run().await;
+            if !poll_fn(|cx| self.poll_kick(id, cx)).await {
+                return;
+            }
+        }
+    }
+
+    // Ready with true once device `id` is kicked, or false once it's unplugged.
+    fn poll_kick(&self, id: DeviceId, cx: &mut Context) -> Poll<bool> {
+        let mut state = self.state.borrow_mut();
+        match state.devices.get_mut(&id) {
+            None => Poll::Ready(false),
+            Some(slot) if slot.kicked => {
+                slot.kicked = false;
+                Poll::Ready(true)
+            }
+            Some(slot) => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::future::join;
+
+    // Waits for a value from another device, or sends one.
+    enum Pipe {
+        Receiver(RefCell<Option<oneshot::Receiver<u32>>>),
+        Sender(RefCell<Option<oneshot::Sender<u32>>>),
+    }
+
+    struct PipeDevice {
+        pipe: Pipe,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl VirtioDevice for PipeDevice {
+        fn run(&self) -> DeviceFuture<'_> {
+            Box::pin(async move {
+                match &self.pipe {
+                    Pipe::Receiver(rx) => {
+                        let rx = rx.borrow_mut().take().unwrap();
+                        let value = rx.await.unwrap();
+                        self.log.borrow_mut().push(format!("received {}", value));
+                    }
+                    Pipe::Sender(tx) => {
+                        let tx = tx.borrow_mut().take().unwrap();
+                        self.log.borrow_mut().push("sending".to_string());
+                        tx.send(7).unwrap();
+                    }
+                }
+            })
+        }
+    }
+
+    // Counts how many times it was run.
+    struct CountDevice(Rc<Cell<u32>>);
+
+    impl VirtioDevice for CountDevice {
+        fn run(&self) -> DeviceFuture<'_> {
+            self.0.set(self.0.get() + 1);
+            Box::pin(async {})
+        }
+    }
+
+    // Lets the manager run before continuing.
+    async fn yield_now() {
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn devices_run_concurrently() {
+        let (tx, rx) = oneshot::channel();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        // Run one after the other, the receiver would never finish.
+        let manager = DeviceManager::new(vec![
+            Box::new(PipeDevice {
+                pipe: Pipe::Receiver(RefCell::new(Some(rx))),
+                log: log.clone(),
+            }),
+            Box::new(PipeDevice {
+                pipe: Pipe::Sender(RefCell::new(Some(tx))),
+                log: log.clone(),
+            }),
+        ]);
+        let control = async {
+            yield_now().await;
+            manager.shutdown();
+        };
+        block_on(join(manager.run(), control));
+        assert_eq!(*log.borrow(), vec!["sending", "received 7"]);
+    }
+
+    #[test]
+    fn remove_before_run() {
+        let count = Rc::new(Cell::new(0));
+        let manager = DeviceManager::default();
+        let id = manager.add(Box::new(CountDevice(count.clone())));
+        assert!(manager.remove(id).is_some());
+        manager.add(Box::new(CountDevice(count.clone())));
+        manager.shutdown();
+        block_on(manager.run());
+        assert_eq!(count.get(), 0);
+    }
+
+    #[test]
+    fn remove_while_busy() {
+        let (tx, rx) = oneshot::channel();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let manager = DeviceManager::default();
+        let id = manager.add(Box::new(PipeDevice {
+            pipe: Pipe::Receiver(RefCell::new(Some(rx))),
+            log: log.clone(),
+        }));
+        let control = async {
+            yield_now().await;
+            // The device is waiting for the value when it's unplugged.
+            assert!(manager.remove(id).is_some());
+            yield_now().await;
+            // `run` has to keep the busy device going past the shutdown.
+            manager.shutdown();
+            yield_now().await;
+            assert!(log.borrow().is_empty());
+            tx.send(3).unwrap();
+        };
+        block_on(join(manager.run(), control));
+        assert_eq!(*log.borrow(), vec!["received 3"]);
+    }
+
+    #[test]
+    fn hotplug() {
+        let first = Rc::new(Cell::new(0));
+        let second = Rc::new(Cell::new(0));
+        let manager = DeviceManager::new(vec![Box::new(CountDevice(first.clone()))]);
+        let control = async {
+            yield_now().await;
+            assert_eq!(first.get(), 1);
+            assert!(manager.kick(0));
+            yield_now().await;
+            assert_eq!(first.get(), 2);
+
+            let id = manager.add(Box::new(CountDevice(second.clone())));
+            assert_eq!(manager.device_ids(), vec![0, id]);
+            yield_now().await;
+            assert_eq!(second.get(), 1);
+            assert!(manager.remove(id).is_some());
+            assert!(!manager.kick(id));
+            assert!(manager.remove(id).is_none());
+            yield_now().await;
+            assert_eq!(second.get(), 1);
+            manager.shutdown();
+        };
+        block_on(join(manager.run(), control));
+        assert_eq!(first.get(), 2);
+        assert!(manager.device_ids().is_empty());
+    }
+}
@@ -0,0 +1,436 @@
+// Disk images accessed through futures, and the glue that runs block requests on them.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::block::{self, Block, Request, RequestQueue, SgList};
+use crate::blocking::BlockingPool;
+
+/// The future returned by every `AsyncDisk` method.
+pub type DiskFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + 'a>>;
+
+/// A disk image whose operations complete asynchronously. Buffers are passed by value so an
+/// implementation can hand them to another thread or the kernel while the future is pending.
+pub trait AsyncDisk {
+    /// Reads `buf.len()` bytes at `offset`, returning the filled buffer. Reading past the end of
+    /// the disk fails.
+    fn read_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, Vec<u8>>;
+    /// Writes all of `buf` at `offset`.
+    fn write_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, ()>;
+    /// Makes the writes so far durable.
+    fn flush(&self) -> DiskFuture<'_, ()>;
+    /// Deallocates `len` bytes at `offset`. The range reads as zeroes afterwards.
+    fn punch_hole(&self, offset: u64, len: u64) -> DiskFuture<'_, ()>;
+    /// Zeroes `len` bytes at `offset`, keeping them allocated.
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> DiskFuture<'_, ()>;
+    /// The size of the disk in bytes.
+    fn get_len(&self) -> DiskFuture<'_, u64>;
+    /// Grows or shrinks the disk to `len` bytes.
+    fn set_len(&self, len: u64) -> DiskFuture<'_, ()>;
+}
+
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+    // Safe because fallocate doesn't touch memory and the return value is checked.
+    let ret = unsafe {
+        libc::fallocate64(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off64_t,
+            len as libc::off64_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The end of `[offset, offset + len)`, if it fits in a u64.
+fn range_end(offset: u64, len: u64) -> io::Result<u64> {
+    offset
+        .checked_add(len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "range overflows"))
+}
+
+/// Checks that `[offset, offset + len)` is inside a disk of `size` bytes.
+pub fn check_range(offset: u64, len: u64, size: u64) -> io::Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("offset {:#x} is past the end of the disk", offset),
+        )),
+    }
+}
+
+/// Splits a checked `[offset, offset + len)` at multiples of `cluster_size`, a power of two, into
+/// `(offset, len)` pieces.
+pub fn cluster_chunks(offset: u64, len: u64, cluster_size: u64) -> Vec<(u64, u64)> {
+    let mut chunks = Vec::new();
+    let mut pos = offset;
+    let end = offset + len;
+    while pos < end {
+        let count = (cluster_size - (pos & (cluster_size - 1))).min(end - pos);
+        chunks.push((pos, count));
+        pos += count;
+    }
+    chunks
+}
+
+// Zeroes with plain writes, for file systems without FALLOC_FL_ZERO_RANGE.
+fn write_zeroes(file: &File, mut offset: u64, len: u64) -> io::Result<()> {
+    let zeroes = [0u8; 4096];
+    let end = range_end(offset, len)?;
+    while offset < end {
+        let chunk = (end - offset).min(zeroes.len() as u64) as usize;
+        file.write_all_at(&zeroes[..chunk], offset)?;
+        offset += chunk as u64;
+    }
+    Ok(())
+}
+
+/// The future a `DiskBlock` hands each request to.
+pub type RequestFuture = Pin<Box<dyn Future<Output = block::Result<SgList>>>>;
+
+/// A block device backed by any `AsyncDisk`. Every disk type shares this one `Block` type.
+pub type DiskBlock = Block<Box<dyn Fn(Request) -> RequestFuture>, RequestFuture>;
+
+/// Creates a block device that runs the requests from `queue` on `disk`.
+pub fn disk_block(disk: Rc<dyn AsyncDisk>, queue: Rc<RequestQueue>) -> DiskBlock {
+    Block::new(
+        Box::new(move |request| {
+            let disk = disk.clone();
+            Box::pin(async move { process_request(&*disk, request).await })
+        }),
+        queue,
+    )
+}
+
+/// A raw image: disk offsets are file offsets. The blocking file calls run on a `BlockingPool`,
+/// so the futures can be polled from any executor without stalling it. The file's length is
+/// cached after the first `get_len`, so the file must only be resized through the disk.
+pub struct SingleFileDisk {
+    file: Arc<File>,
+    pool: Arc<BlockingPool>,
+    // None until the length is first needed, or after an operation that might have changed it.
+    len: Cell<Option<u64>>,
+}
+
+impl SingleFileDisk {
+    pub fn new(file: File, pool: Arc<BlockingPool>) -> SingleFileDisk {
+        SingleFileDisk {
+            file: Arc::new(file),
+            pool,
+            len: Cell::new(None),
+        }
+    }
+
+    fn run<T, F>(&self, f: F) -> DiskFuture<'_, T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&File) -> io::Result<T> + Send + 'static,
+    {
+        let file = self.file.clone();
+        let result = self.pool.spawn(move || f(&file));
+        Box::pin(async move { result.await? })
+    }
+}
+
+impl AsyncDisk for SingleFileDisk {
+    fn read_at(&self, offset: u64, mut buf: Vec<u8>) -> DiskFuture<'_, Vec<u8>> {
+        self.run(move |file| {
+            file.read_exact_at(&mut buf, offset)?;
+            Ok(buf)
+        })
+    }
+
+    fn write_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, ()> {
+        let end = match range_end(offset, buf.len() as u64) {
+            Ok(end) => end,
+            Err(e) => return Box::pin(async move { Err(e) }),
+        };
+        let write = self.run(move |file| file.write_all_at(&buf, offset));
+        Box::pin(async move {
+            let result = write.await;
+            // Writing past the end grows the file, even if only part of the write made it.
+            if let Some(len) = self.len.get() {
+                if end > len {
+                    self.len.set(if result.is_ok() { Some(end) } else { None });
+                }
+            }
+            result
+        })
+    }
+
+    fn flush(&self) -> DiskFuture<'_, ()> {
+        self.run(|file| file.sync_data())
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        self.run(move |file| {
+            fallocate(
+                file,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                len,
+            )
+        })
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        let end = match range_end(offset, len) {
+            Ok(end) => end,
+            Err(e) => return Box::pin(async move { Err(e) }),
+        };
+        // Only the fallback grows the file, so look the length up again.
+        if self.len.get().is_some_and(|disk_len| end > disk_len) {
+            self.len.set(None);
+        }
+        self.run(move |file| {
+            match fallocate(
+                file,
+                libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE,
+                offset,
+                len,
+            ) {
+                Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                    write_zeroes(file, offset, len)
+                }
+                r => r,
+            }
+        })
+    }
+
+    fn get_len(&self) -> DiskFuture<'_, u64> {
+        if let Some(len) = self.len.get() {
+            return Box::pin(async move { Ok(len) });
+        }
+        let metadata = self.run(|file| Ok(file.metadata()?.len()));
+        Box::pin(async move {
+            let len = metadata.await?;
+            self.len.set(Some(len));
+            Ok(len)
+        })
+    }
+
+    fn set_len(&self, len: u64) -> DiskFuture<'_, ()> {
+        let resize = self.run(move |file| file.set_len(len));
+        Box::pin(async move {
+            let result = resize.await;
+            self.len.set(if result.is_ok() { Some(len) } else { None });
+            result
+        })
+    }
+}
+
+/// Runs a block request on `disk`, checking that it stays inside the disk. Disks have no id, so
+/// get-id is unsupported.
+pub async fn process_request(disk: &dyn AsyncDisk, request: Request) -> block::Result<SgList> {
+    let disk_len = disk.get_len().await.map_err(block::Error::Io)?;
+    // The guest picks the sector, so the byte offset can overflow.
+    let sector_offset = |sector: u64, len: usize| {
+        sector
+            .checked_mul(block::SECTOR_SIZE)
+            .ok_or(block::Error::OutOfRange {
+                sector,
+                num_sectors: len as u64 >> block::SECTOR_SHIFT,
+            })
+    };
+    let check = |offset: u64, len: u64| {
+        if offset.checked_add(len).is_none_or(|end| end > disk_len) {
+            return Err(block::Error::OutOfRange {
+                sector: offset >> block::SECTOR_SHIFT,
+                num_sectors: len >> block::SECTOR_SHIFT,
+            });
+        }
+        Ok(())
+    };
+    match request {
+        Request::Read { sector, bufs } => {
+            let mut offset = sector_offset(sector, block::sg_len(&bufs))?;
+            check(offset, block::sg_len(&bufs) as u64)?;
+            let mut filled = Vec::with_capacity(bufs.len());
+            for buf in bufs {
+                let len = buf.len() as u64;
+                filled.push(disk.read_at(offset, buf).await.map_err(block::Error::Io)?);
+                offset += len;
+            }
+            Ok(filled)
+        }
+        Request::Write { sector, bufs } => {
+            let mut offset = sector_offset(sector, block::sg_len(&bufs))?;
+            check(offset, block::sg_len(&bufs) as u64)?;
+            for buf in bufs {
+                let len = buf.len() as u64;
+                disk.write_at(offset, buf).await.map_err(block::Error::Io)?;
+                offset += len;
+            }
+            Ok(Vec::new())
+        }
+        Request::Flush => {
+            disk.flush().await.map_err(block::Error::Io)?;
+            Ok(Vec::new())
+        }
+        Request::Discard { segments } => {
+            for segment in segments {
+                check(segment.offset(), segment.len())?;
+                disk.punch_hole(segment.offset(), segment.len())
+                    .await
+                    .map_err(block::Error::Io)?;
+            }
+            Ok(Vec::new())
+        }
+        Request::WriteZeroes { segments } => {
+            for segment in segments {
+                check(segment.offset(), segment.len())?;
+                let zeroed = if segment.unmap {
+                    disk.punch_hole(segment.offset(), segment.len()).await
+                } else {
+                    disk.write_zeroes_at(segment.offset(), segment.len()).await
+                };
+                zeroed.map_err(block::Error::Io)?;
+            }
+            Ok(Vec::new())
+        }
+        r @ Request::GetId => Err(block::Error::Unsupported(r.req_type())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use futures::executor::block_on;
+    use tempfile::tempfile;
+
+    fn disk_with(contents: &[u8]) -> SingleFileDisk {
+        let mut file = tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        SingleFileDisk::new(file, Arc::new(BlockingPool::new(2)))
+    }
+
+    #[test]
+    fn read_write() {
+        let disk = disk_with(&[1u8; 8192]);
+        block_on(async {
+            disk.write_at(100, vec![2u8; 50]).await.unwrap();
+            let buf = disk.read_at(90, vec![0u8; 70]).await.unwrap();
+            assert_eq!(buf[..10], [1u8; 10]);
+            assert_eq!(buf[10..60], [2u8; 50][..]);
+            assert_eq!(buf[60..], [1u8; 10]);
+            disk.flush().await.unwrap();
+            assert!(disk.read_at(8190, vec![0u8; 4]).await.is_err());
+            let e = disk.write_at(u64::MAX - 1, vec![0u8; 4]).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn zeroes_and_holes() {
+        let disk = disk_with(&[1u8; 3 * 4096]);
+        block_on(async {
+            disk.write_zeroes_at(10, 20).await.unwrap();
+            disk.punch_hole(4096, 4096).await.unwrap();
+            let buf = disk.read_at(0, vec![0u8; 3 * 4096]).await.unwrap();
+            assert_eq!(buf[..10], [1u8; 10]);
+            assert!(buf[10..30].iter().all(|&b| b == 0));
+            assert!(buf[30..4096].iter().all(|&b| b == 1));
+            assert!(buf[4096..8192].iter().all(|&b| b == 0));
+            assert!(buf[8192..].iter().all(|&b| b == 1));
+            assert_eq!(disk.get_len().await.unwrap(), 3 * 4096);
+            let e = disk.write_zeroes_at(u64::MAX - 1, 4).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn ranges() {
+        assert!(check_range(10, 90, 100).is_ok());
+        assert!(check_range(10, 91, 100).is_err());
+        assert!(check_range(u64::MAX, 2, u64::MAX).is_err());
+        assert_eq!(
+            cluster_chunks(60, 80, 64),
+            vec![(60, 4), (64, 64), (128, 12)]
+        );
+        assert!(cluster_chunks(64, 0, 64).is_empty());
+    }
+
+    #[test]
+    fn resize() {
+        let disk = disk_with(&[]);
+        block_on(async {
+            assert_eq!(disk.get_len().await.unwrap(), 0);
+            disk.set_len(1 << 20).await.unwrap();
+            assert_eq!(disk.get_len().await.unwrap(), 1 << 20);
+            let buf = disk.read_at((1 << 20) - 4, vec![1u8; 4]).await.unwrap();
+            assert_eq!(buf, vec![0u8; 4]);
+            // Writes and zeroes past the end grow the disk.
+            disk.write_at(1 << 20, vec![1u8; 4]).await.unwrap();
+            assert_eq!(disk.get_len().await.unwrap(), (1 << 20) + 4);
+            disk.write_zeroes_at((1 << 20) + 4, 4).await.unwrap();
+            assert_eq!(
+                disk.get_len().await.unwrap(),
+                disk.file.metadata().unwrap().len()
+            );
+            disk.set_len(4096).await.unwrap();
+            assert_eq!(disk.get_len().await.unwrap(), 4096);
+        });
+    }
+
+    #[test]
+    fn requests() {
+        let disk = disk_with(&[0u8; 4 * 512]);
+        block_on(async {
+            let write = Request::Write {
+                sector: 1,
+                bufs: vec![vec![3u8; 512], vec![4u8; 512]],
+            };
+            process_request(&disk, write).await.unwrap();
+            let read = Request::Read {
+                sector: 2,
+                bufs: vec![vec![0u8; 512]],
+            };
+            let data = process_request(&disk, read).await.unwrap();
+            assert_eq!(data, vec![vec![4u8; 512]]);
+
+            let past_end = Request::Read {
+                sector: 3,
+                bufs: vec![vec![0u8; 1024]],
+            };
+            match process_request(&disk, past_end).await {
+                Err(block::Error::OutOfRange { sector: 3, .. }) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+
+            // The byte offset of this sector doesn't fit in a u64.
+            let huge_sector = u64::MAX >> 8;
+            let overflow = Request::Write {
+                sector: huge_sector,
+                bufs: vec![vec![5u8; 512]],
+            };
+            match process_request(&disk, overflow).await {
+                Err(block::Error::OutOfRange { sector, .. }) if sector == huge_sector => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+            let data = process_request(
+                &disk,
+                Request::Read {
+                    sector: 0,
+                    bufs: vec![vec![1u8; 512]],
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(data, vec![vec![0u8; 512]]);
+        });
+    }
+}
@@ -0,0 +1,141 @@
+// Eventfds that can be waited on from async code.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+
+use crate::blocking::BlockingPool;
+
+/// A counter that one side adds to and the other waits on, like a queue kick from the guest.
+pub struct EventFd {
+    file: Arc<File>,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<EventFd> {
+        // Safe because eventfd doesn't touch memory and the return value is checked.
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because `fd` is a new eventfd nothing else owns.
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(EventFd {
+            file: Arc::new(file),
+        })
+    }
+
+    /// Another handle to the same counter.
+    pub fn try_clone(&self) -> io::Result<EventFd> {
+        Ok(EventFd {
+            file: Arc::new(self.file.try_clone()?),
+        })
+    }
+
+    /// Adds `val` to the counter, waking a waiter.
+    pub fn write(&self, val: u64) -> io::Result<()> {
+        (&*self.file).write_all(&val.to_ne_bytes())
+    }
+
+    /// Waits for the counter to be non-zero, then returns it and resets it to zero. The wait
+    /// occupies a thread of `pool` until then, or until the future is dropped.
+    pub async fn read(&self, pool: &BlockingPool) -> io::Result<u64> {
+        let file = self.file.clone();
+        let cancel = CancelOnDrop(EventFd::new()?);
+        let cancelled = cancel.0.file.clone();
+        pool.spawn(move || loop {
+            let mut pollfds = [
+                libc::pollfd {
+                    fd: file.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: cancelled.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            // Safe because `pollfds` is an array of valid pollfds and the return value is checked.
+            let ret =
+                unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+                continue;
+            }
+            if pollfds[1].revents != 0 {
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            let mut buf = [0u8; 8];
+            match (&*file).read_exact(&mut buf) {
+                Ok(()) => return Ok(u64::from_ne_bytes(buf)),
+                // Another reader got there first.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        })
+        .await?
+    }
+}
+
+// Tells the thread waiting in `EventFd::read` to stop once nobody wants the result.
+struct CancelOnDrop(EventFd);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.write(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::future::Future;
+    use std::task::Context;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+
+    #[test]
+    fn write_then_read() {
+        let pool = BlockingPool::new(1);
+        let event = EventFd::new().unwrap();
+        event.write(2).unwrap();
+        event.write(3).unwrap();
+        assert_eq!(block_on(event.read(&pool)).unwrap(), 5);
+    }
+
+    #[test]
+    fn read_waits_for_write() {
+        let pool = BlockingPool::new(1);
+        let event = EventFd::new().unwrap();
+        let writer = event.try_clone().unwrap();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            writer.write(1).unwrap();
+        });
+        assert_eq!(block_on(event.read(&pool)).unwrap(), 1);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn dropped_read_frees_thread() {
+        let pool = BlockingPool::new(1);
+        let event = EventFd::new().unwrap();
+        let other = EventFd::new().unwrap();
+        other.write(4).unwrap();
+        // Polls the first read once so its wait starts, then drops it.
+        let mut read = Box::pin(event.read(&pool));
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+        drop(read);
+        assert_eq!(block_on(other.read(&pool)).unwrap(), 4);
+    }
+}
@@ -1,69 +1,255 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
 use std::future::Future;
+use std::process;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::Poll;
 
-mod deref_disk_file;
+mod android_sparse;
+mod block;
+mod blocking;
+mod composite;
+mod device;
+mod disk;
+// Guest memory and virtqueues are exercised by their tests, not by the demo below.
+#[allow(dead_code)]
+mod event;
+#[allow(dead_code)]
+mod mem;
+mod overlay;
+mod qcow;
+#[allow(dead_code)]
+mod virtqueue;
 
-trait VirtioDevice {
-    fn run(&self);
-}
-
-struct Block<F, Fut>
-where
-    F: Fn(u32) -> Fut,
-    Fut: Future<Output = u32>,
-{
-    process_op: F,
-}
+use android_sparse::AndroidSparse;
+use block::{
+    Block, RawRequest, Request, RequestQueue, Result, SgList, SECTOR_SHIFT, VIRTIO_BLK_S_IOERR,
+    VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH,
+    VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+};
+use blocking::BlockingPool;
+use composite::CompositeDisk;
+use device::{DeviceManager, VirtioDevice};
+use disk::{disk_block, process_request, AsyncDisk, SingleFileDisk};
+use overlay::OverlayDisk;
+use qcow::QcowFile;
 
-impl<F, Fut> Block<F, Fut>
-where
-    F: Fn(u32) -> Fut,
-    Fut: Future<Output = u32>,
-{
-    fn new(f: F) -> Self {
-        Block { process_op: f }
+fn test() {
+    async fn op_proc_file(disk: Rc<SingleFileDisk>, request: Request) -> Result<SgList> {
+        process_request(&*disk, request).await
     }
 
-    async fn do_op(&self) -> u32 {
-        (self.process_op)(3).await
+    // Lets the other side of a `join` run before continuing.
+    async fn yield_now() {
+        let mut yielded = false;
+        futures::future::poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await
     }
-}
 
-impl<F, Fut> VirtioDevice for Block<F, Fut>
-where
-    F: Fn(u32) -> Fut,
-    Fut: Future<Output = u32>,
-{
-    fn run(&self) {
-        let fut = self.do_op();
+    fn run_one<F: Future>(fut: F) -> F::Output {
         futures::pin_mut!(fut);
-        cros_async::run_one(fut).unwrap();
+        cros_async::run_one(fut).unwrap()
     }
-}
 
-fn test() {
-    async fn op_proc_file(b: u32) -> u32 {
-        // This would process read/write etc for files.
-        b + 32
+    // Opens a file in the temp dir that is removed as soon as it's open.
+    fn temp_file(name: &str) -> File {
+        let path = env::temp_dir().join(format!("async_generic_{}_{}", process::id(), name));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        file
     }
 
-    async fn op_proc_qcow(disk_op: u32) -> u32 {
-        async fn get_index(offset: u32) -> u32 {
-            offset + 5
-        }
-        async fn read_from_index(index: u32) -> u32 {
-            index + 2
-        }
-
-        let index = get_index(disk_op).await;
-        read_from_index(index).await
+    fn segment(sector: u64, num_sectors: u32) -> Vec<u8> {
+        let mut seg = sector.to_le_bytes().to_vec();
+        seg.extend_from_slice(&num_sectors.to_le_bytes());
+        seg.extend_from_slice(&0u32.to_le_bytes());
+        seg
     }
 
-    let mut blocks: Vec<Box<dyn VirtioDevice>> = Vec::new();
-    blocks.push(Box::new(Block::new(op_proc_file)));
-    blocks.push(Box::new(Block::new(op_proc_qcow)));
-    for block in &blocks {
-        block.run();
+    fn raw(req_type: u32, sector: u64, out: SgList, in_lens: Vec<usize>) -> RawRequest {
+        RawRequest {
+            req_type,
+            sector,
+            out,
+            in_lens,
+        }
     }
+
+    let pool = Arc::new(BlockingPool::new(2));
+    let disk = Rc::new(SingleFileDisk::new(temp_file("raw.img"), pool.clone()));
+    run_one(disk.set_len(8 << SECTOR_SHIFT)).unwrap();
+    let base = Box::new(SingleFileDisk::new(temp_file("base.img"), pool.clone()));
+    run_one(base.write_at(0, vec![4u8; 8 << SECTOR_SHIFT])).unwrap();
+    let qcow_file = Box::new(SingleFileDisk::new(temp_file("disk.qcow2"), pool.clone()));
+    let mut qcow = run_one(QcowFile::create(qcow_file, 1 << 20, Some("base.img"))).unwrap();
+    assert_eq!(qcow.header().size, 1 << 20);
+    assert_eq!(qcow.backing_file_name(), Some("base.img"));
+    qcow.set_backing_file(Some(base));
+    let qcow = Rc::new(qcow);
+    // Sectors 0-3 from one partition, 6-7 from another, and a gap between them. The second
+    // partition is a sparse image of a single chunk filling two sectors with 2s.
+    let part_a = Box::new(SingleFileDisk::new(temp_file("part_a.img"), pool.clone()));
+    run_one(part_a.write_at(0, vec![1u8; 4 << SECTOR_SHIFT])).unwrap();
+    let sparse_file = Box::new(SingleFileDisk::new(temp_file("part_b.simg"), pool.clone()));
+    let sparse_image: Vec<u32> = vec![
+        0xed26_ff3a,
+        1,                 // Version 1.0.
+        28 | (12 << 16),   // Header and chunk header sizes.
+        1 << SECTOR_SHIFT, // Block size.
+        2,                 // Blocks.
+        1,                 // Chunks.
+        0,                 // Checksum.
+        0xcac2,            // Fill chunk.
+        2,                 // Blocks.
+        16,                // Chunk size.
+        0x0202_0202,       // Pattern.
+    ];
+    let sparse_image: Vec<u8> = sparse_image.iter().flat_map(|w| w.to_le_bytes()).collect();
+    run_one(sparse_file.write_at(0, sparse_image)).unwrap();
+    let part_b = Box::new(run_one(AndroidSparse::open(sparse_file)).unwrap());
+    let composite = run_one(CompositeDisk::new(
+        vec![(0, part_a), (6 << SECTOR_SHIFT, part_b)],
+        None,
+    ))
+    .unwrap();
+    let composite = Rc::new(composite);
+    // A golden image of 4 sectors of 6s that writes never reach.
+    let golden = Box::new(SingleFileDisk::new(temp_file("golden.img"), pool.clone()));
+    run_one(golden.write_at(0, vec![6u8; 4 << SECTOR_SHIFT])).unwrap();
+    let overlay_file = Box::new(SingleFileDisk::new(temp_file("overlay.img"), pool.clone()));
+    let overlay =
+        Rc::new(run_one(OverlayDisk::new(golden, overlay_file, 1 << SECTOR_SHIFT)).unwrap());
+    let file_queue = Rc::new(RequestQueue::default());
+    file_queue.submit(raw(
+        VIRTIO_BLK_T_OUT,
+        1,
+        vec![vec![7u8; 512], vec![8u8; 512]],
+        vec![],
+    ));
+    file_queue.submit(raw(VIRTIO_BLK_T_IN, 1, vec![], vec![256, 768]));
+    file_queue.submit(raw(
+        VIRTIO_BLK_T_WRITE_ZEROES,
+        0,
+        vec![segment(2, 1)],
+        vec![],
+    ));
+    file_queue.submit(raw(VIRTIO_BLK_T_DISCARD, 0, vec![segment(7, 2)], vec![]));
+    file_queue.submit(raw(VIRTIO_BLK_T_IN, 1, vec![], vec![1024]));
+    file_queue.submit(raw(VIRTIO_BLK_T_FLUSH, 0, vec![], vec![]));
+    file_queue.submit(raw(VIRTIO_BLK_T_GET_ID, 0, vec![], vec![20]));
+    file_queue.submit(raw(VIRTIO_BLK_T_IN, 0, vec![], vec![100]));
+    file_queue.submit(raw(99, 0, vec![], vec![]));
+
+    let qcow_queue = Rc::new(RequestQueue::default());
+    qcow_queue.submit(raw(VIRTIO_BLK_T_OUT, 3, vec![vec![10u8; 512]], vec![]));
+    qcow_queue.submit(raw(VIRTIO_BLK_T_IN, 3, vec![], vec![512, 512]));
+    qcow_queue.submit(raw(VIRTIO_BLK_T_FLUSH, 0, vec![], vec![]));
+
+    let composite_queue = Rc::new(RequestQueue::default());
+    composite_queue.submit(raw(VIRTIO_BLK_T_OUT, 3, vec![vec![5u8; 1024]], vec![]));
+    composite_queue.submit(raw(VIRTIO_BLK_T_OUT, 2, vec![vec![5u8; 1024]], vec![]));
+    composite_queue.submit(raw(VIRTIO_BLK_T_IN, 0, vec![], vec![8 << SECTOR_SHIFT]));
+
+    let overlay_queue = Rc::new(RequestQueue::default());
+    overlay_queue.submit(raw(VIRTIO_BLK_T_OUT, 1, vec![vec![7u8; 512]], vec![]));
+    overlay_queue.submit(raw(VIRTIO_BLK_T_IN, 0, vec![], vec![1024]));
+
+    // A block with its own handler next to blocks that all share the `DiskBlock` type.
+    let blocks: Vec<Box<dyn VirtioDevice>> = vec![
+        Box::new(Block::new(
+            move |request| op_proc_file(disk.clone(), request),
+            file_queue.clone(),
+        )),
+        Box::new(disk_block(qcow, qcow_queue.clone())),
+        Box::new(disk_block(composite, composite_queue.clone())),
+    ];
+    let manager = DeviceManager::new(blocks);
+    // The overlay is hotplugged once the other devices are running.
+    let control = async {
+        let id = manager.add(Box::new(disk_block(overlay.clone(), overlay_queue.clone())));
+        assert_eq!(manager.device_ids(), vec![0, 1, 2, id]);
+        qcow_queue.submit(raw(VIRTIO_BLK_T_IN, 4, vec![], vec![512]));
+        assert!(manager.kick(1));
+        // Shut down once the overlay and the kicked qcow device have started.
+        yield_now().await;
+        manager.shutdown();
+    };
+    run_one(futures::future::join(manager.run(), control));
+
+    let used = file_queue.take_used();
+    let statuses: Vec<u8> = used.iter().map(|r| r.status).collect();
+    assert_eq!(
+        statuses,
+        vec![
+            VIRTIO_BLK_S_OK,
+            VIRTIO_BLK_S_OK,
+            VIRTIO_BLK_S_OK,
+            // Sectors 7 and 8 of an 8 sector disk.
+            VIRTIO_BLK_S_IOERR,
+            VIRTIO_BLK_S_OK,
+            VIRTIO_BLK_S_OK,
+            // Disks have no id.
+            VIRTIO_BLK_S_UNSUPP,
+            VIRTIO_BLK_S_IOERR,
+            VIRTIO_BLK_S_UNSUPP,
+        ]
+    );
+    assert_eq!(used[1].data[0], vec![7u8; 256]);
+    assert_eq!(used[1].used_len(), 1025);
+    assert_eq!(used[4].data[0][..512], [7u8; 512][..]);
+    assert_eq!(used[4].data[0][512..], [0u8; 512][..]);
+
+    let used = qcow_queue.take_used();
+    assert!(used.iter().all(|r| r.status == VIRTIO_BLK_S_OK));
+    // The second sector comes from the backing file.
+    assert_eq!(used[1].data, vec![vec![10u8; 512], vec![4u8; 512]]);
+    // Submitted after the device started, along with a kick.
+    assert_eq!(used.len(), 4);
+    assert_eq!(used[3].data, vec![vec![4u8; 512]]);
+
+    let used = composite_queue.take_used();
+    let statuses: Vec<u8> = used.iter().map(|r| r.status).collect();
+    // The first write runs into the gap, the read-only sparse partition is never written.
+    assert_eq!(
+        statuses,
+        vec![VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_OK]
+    );
+    let expected = [
+        &[1u8; 2 << SECTOR_SHIFT][..],
+        &[5; 2 << SECTOR_SHIFT],
+        &[0; 2 << SECTOR_SHIFT],
+        &[2; 2 << SECTOR_SHIFT],
+    ]
+    .concat();
+    assert_eq!(used[2].data, vec![expected]);
+
+    let used = overlay_queue.take_used();
+    assert!(used.iter().all(|r| r.status == VIRTIO_BLK_S_OK));
+    assert_eq!(used[1].data[0][..512], [6u8; 512][..]);
+    assert_eq!(used[1].data[0][512..], [7u8; 512][..]);
+    assert_eq!(run_one(overlay.dirty_clusters()), 1);
+    run_one(overlay.discard()).unwrap();
+    let golden_data = run_one(overlay.read_at(0, vec![0u8; 4 << SECTOR_SHIFT])).unwrap();
+    assert_eq!(golden_data, vec![6u8; 4 << SECTOR_SHIFT]);
+    run_one(overlay.write_at(0, vec![8u8; 16])).unwrap();
+    run_one(overlay.commit()).unwrap();
+    assert_eq!(run_one(overlay.dirty_clusters()), 0);
+    let committed = run_one(overlay.read_at(0, vec![0u8; 32])).unwrap();
+    assert_eq!(committed, [&[8u8; 16][..], &[6; 16]].concat());
 }
 
 fn main() {
@@ -0,0 +1,449 @@
+// Memory shared with the guest, as devices see it.
+
+#[cfg(test)]
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+
+#[derive(Debug)]
+pub enum Error {
+    /// `len` bytes at `addr` aren't all in one region of guest memory.
+    OutOfRange {
+        addr: u64,
+        len: usize,
+    },
+    /// A region that is empty, not page aligned, or overlaps another.
+    InvalidRegion {
+        addr: u64,
+        size: u64,
+    },
+    Memfd(io::Error),
+    Mmap(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            OutOfRange { addr, len } => {
+                write!(f, "{} bytes at {:#x} are outside guest memory", len, addr)
+            }
+            InvalidRegion { addr, size } => {
+                write!(f, "invalid region of {:#x} bytes at {:#x}", size, addr)
+            }
+            Memfd(e) => write!(f, "failed to create the memfd: {}", e),
+            Mmap(e) => write!(f, "failed to map guest memory: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Guest physical memory. Accesses are checked, a range that isn't entirely in guest memory
+/// fails without touching anything. Integers are little endian, as virtio is.
+pub trait Memory {
+    /// Fills `buf` from guest memory at `addr`.
+    fn read_at_addr(&self, addr: u64, buf: &mut [u8]) -> Result<()>;
+    /// Copies `buf` to guest memory at `addr`.
+    fn write_at_addr(&self, addr: u64, buf: &[u8]) -> Result<()>;
+
+    fn read_u16(&self, addr: u64) -> Result<u16> {
+        let mut b = [0u8; 2];
+        self.read_at_addr(addr, &mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn read_u32(&self, addr: u64) -> Result<u32> {
+        let mut b = [0u8; 4];
+        self.read_at_addr(addr, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn read_u64(&self, addr: u64) -> Result<u64> {
+        let mut b = [0u8; 8];
+        self.read_at_addr(addr, &mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn write_u16(&self, addr: u64, val: u16) -> Result<()> {
+        self.write_at_addr(addr, &val.to_le_bytes())
+    }
+
+    fn write_u32(&self, addr: u64, val: u32) -> Result<()> {
+        self.write_at_addr(addr, &val.to_le_bytes())
+    }
+
+    fn write_u64(&self, addr: u64, val: u64) -> Result<()> {
+        self.write_at_addr(addr, &val.to_le_bytes())
+    }
+}
+
+/// Guest memory starting at address 0 that is just a `Vec`, for running devices without a VM.
+#[cfg(test)]
+pub struct VecMemory {
+    bytes: RefCell<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl VecMemory {
+    pub fn new(size: usize) -> VecMemory {
+        VecMemory {
+            bytes: RefCell::new(vec![0u8; size]),
+        }
+    }
+
+    fn range(&self, addr: u64, len: usize) -> Result<std::ops::Range<usize>> {
+        let size = self.bytes.borrow().len() as u64;
+        match addr.checked_add(len as u64) {
+            Some(end) if end <= size => Ok(addr as usize..end as usize),
+            _ => Err(Error::OutOfRange { addr, len }),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Memory for VecMemory {
+    fn read_at_addr(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(addr, buf.len())?;
+        buf.copy_from_slice(&self.bytes.borrow()[range]);
+        Ok(())
+    }
+
+    fn write_at_addr(&self, addr: u64, buf: &[u8]) -> Result<()> {
+        let range = self.range(addr, buf.len())?;
+        self.bytes.borrow_mut()[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Types that can be copied to and from guest memory as plain bytes.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type and it must have no padding bytes.
+pub unsafe trait DataInit: Copy {}
+
+// Safe because integers are valid for any bits and have no padding.
+unsafe impl DataInit for u8 {}
+unsafe impl DataInit for u16 {}
+unsafe impl DataInit for u32 {}
+unsafe impl DataInit for u64 {}
+
+/// Bytes of guest memory that the guest may change at any time, so they are only accessed with
+/// volatile copies and never through a reference.
+pub struct VolatileSlice<'a> {
+    addr: *mut u8,
+    size: usize,
+    phantom: PhantomData<&'a u8>,
+}
+
+impl<'a> VolatileSlice<'a> {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The `count` bytes at `offset` in this slice.
+    pub fn sub_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice<'a>> {
+        match offset.checked_add(count) {
+            Some(end) if end <= self.size => Ok(VolatileSlice {
+                // Safe because `offset` is inside the slice.
+                addr: unsafe { self.addr.add(offset) },
+                size: count,
+                phantom: PhantomData,
+            }),
+            _ => Err(Error::OutOfRange {
+                addr: offset as u64,
+                len: count,
+            }),
+        }
+    }
+
+    /// Copies the start of the slice to `buf`, as many bytes as fit in both.
+    pub fn copy_to(&self, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().take(self.size).enumerate() {
+            // Safe because `i` is inside the slice.
+            *b = unsafe { ptr::read_volatile(self.addr.add(i)) };
+        }
+    }
+
+    /// Copies `buf` to the start of the slice, as many bytes as fit in both.
+    pub fn copy_from(&self, buf: &[u8]) {
+        for (i, b) in buf.iter().take(self.size).enumerate() {
+            // Safe because `i` is inside the slice.
+            unsafe { ptr::write_volatile(self.addr.add(i), *b) };
+        }
+    }
+}
+
+// A mapping of memory this process owns, unmapped on drop.
+struct MemoryMapping {
+    addr: *mut u8,
+    size: usize,
+}
+
+impl MemoryMapping {
+    // Maps `size` bytes of `fd` at `offset`, or anonymous memory if `fd` is `None`.
+    fn new(fd: Option<&File>, offset: u64, size: usize) -> Result<MemoryMapping> {
+        let (flags, fd) = match fd {
+            Some(file) => (libc::MAP_SHARED, file.as_raw_fd()),
+            None => (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1),
+        };
+        // Safe because a new mapping is requested, so no existing memory is affected, and the
+        // return value is checked.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags | libc::MAP_NORESERVE,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::Mmap(io::Error::last_os_error()));
+        }
+        Ok(MemoryMapping {
+            addr: addr as *mut u8,
+            size,
+        })
+    }
+}
+
+impl Drop for MemoryMapping {
+    fn drop(&mut self) {
+        // Safe because the mapping was created by `new` and nothing refers to it any more.
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+struct MemoryRegion {
+    guest_addr: u64,
+    mapping: MemoryMapping,
+}
+
+/// How guest memory is allocated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backing {
+    /// Private anonymous memory, only this process can see it.
+    Anonymous,
+    /// One memfd holding every region, which can be shared with other processes.
+    Memfd,
+}
+
+/// Guest physical memory made of regions mapped into this process. Addresses between regions
+/// aren't backed by anything.
+pub struct GuestMemory {
+    regions: Vec<MemoryRegion>,
+    memfd: Option<File>,
+}
+
+impl GuestMemory {
+    /// Maps a region of `size` bytes at each `(guest_addr, size)` in `ranges`. Both must be page
+    /// aligned.
+    pub fn new(ranges: &[(u64, u64)], backing: Backing) -> Result<GuestMemory> {
+        let mut ranges = ranges.to_vec();
+        ranges.sort_unstable();
+        // Safe because sysconf doesn't touch memory.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let mut end = 0;
+        for (i, &(addr, size)) in ranges.iter().enumerate() {
+            let overlaps = i > 0 && addr < end;
+            if size == 0
+                || !addr.is_multiple_of(page_size)
+                || !size.is_multiple_of(page_size)
+                || overlaps
+            {
+                return Err(Error::InvalidRegion { addr, size });
+            }
+            end = addr
+                .checked_add(size)
+                .ok_or(Error::InvalidRegion { addr, size })?;
+        }
+
+        let memfd = match backing {
+            Backing::Anonymous => None,
+            Backing::Memfd => {
+                let total: u64 = ranges.iter().map(|(_, size)| size).sum();
+                Some(create_memfd(total)?)
+            }
+        };
+        let mut regions = Vec::with_capacity(ranges.len());
+        let mut offset = 0;
+        for (guest_addr, size) in ranges {
+            let mapping = MemoryMapping::new(memfd.as_ref(), offset, size as usize)?;
+            regions.push(MemoryRegion {
+                guest_addr,
+                mapping,
+            });
+            offset += size;
+        }
+        Ok(GuestMemory { regions, memfd })
+    }
+
+    /// The memfd behind guest memory, if it has one. Regions are stored one after the other in
+    /// order of address.
+    pub fn memfd(&self) -> Option<&File> {
+        self.memfd.as_ref()
+    }
+
+    /// The `len` bytes at `addr`, which must all be in one region.
+    pub fn get_slice_at_addr(&self, addr: u64, len: usize) -> Result<VolatileSlice<'_>> {
+        let region = self
+            .regions
+            .iter()
+            .find(|r| addr >= r.guest_addr && addr - r.guest_addr < r.mapping.size as u64)
+            .ok_or(Error::OutOfRange { addr, len })?;
+        let offset = (addr - region.guest_addr) as usize;
+        if len > region.mapping.size - offset {
+            return Err(Error::OutOfRange { addr, len });
+        }
+        Ok(VolatileSlice {
+            // Safe because `offset` is inside the mapping.
+            addr: unsafe { region.mapping.addr.add(offset) },
+            size: len,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Reads a `T` from `addr`, which needn't be aligned.
+    pub fn read_obj_from_addr<T: DataInit>(&self, addr: u64) -> Result<T> {
+        let mut buf = vec![0u8; size_of::<T>()];
+        self.get_slice_at_addr(addr, buf.len())?.copy_to(&mut buf);
+        // Safe because `buf` holds exactly a `T` and any bytes are a valid `T`.
+        Ok(unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) })
+    }
+
+    /// Writes `val` at `addr`, which needn't be aligned.
+    pub fn write_obj_at_addr<T: DataInit>(&self, val: T, addr: u64) -> Result<()> {
+        // Safe because `val` lives for the whole borrow and has no padding to read.
+        let bytes =
+            unsafe { std::slice::from_raw_parts(&val as *const T as *const u8, size_of::<T>()) };
+        self.get_slice_at_addr(addr, bytes.len())?.copy_from(bytes);
+        Ok(())
+    }
+}
+
+impl Memory for GuestMemory {
+    fn read_at_addr(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        self.get_slice_at_addr(addr, buf.len())?.copy_to(buf);
+        Ok(())
+    }
+
+    fn write_at_addr(&self, addr: u64, buf: &[u8]) -> Result<()> {
+        self.get_slice_at_addr(addr, buf.len())?.copy_from(buf);
+        Ok(())
+    }
+}
+
+fn create_memfd(size: u64) -> Result<File> {
+    // Safe because the name is a valid C string and the return value is checked.
+    let fd = unsafe {
+        libc::memfd_create(
+            b"guest_memory\0".as_ptr() as *const libc::c_char,
+            libc::MFD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::Memfd(io::Error::last_os_error()));
+    }
+    // Safe because `fd` is a new memfd nothing else owns.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size).map_err(Error::Memfd)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let mem = VecMemory::new(16);
+        mem.write_u64(8, 0x0102_0304_0506_0708).unwrap();
+        assert_eq!(mem.read_u32(8).unwrap(), 0x0506_0708);
+        assert_eq!(mem.read_u16(14).unwrap(), 0x0102);
+        assert!(matches!(
+            mem.write_u32(14, 0),
+            Err(Error::OutOfRange { addr: 14, len: 4 })
+        ));
+        // The failed write didn't touch the bytes that are in range.
+        assert_eq!(mem.read_u16(14).unwrap(), 0x0102);
+        assert!(mem.read_u64(u64::MAX - 2).is_err());
+    }
+
+    const PAGE: u64 = 4096;
+
+    #[test]
+    fn guest_memory_regions() {
+        for backing in [Backing::Anonymous, Backing::Memfd] {
+            let mem = GuestMemory::new(&[(4 * PAGE, PAGE), (0, 2 * PAGE)], backing).unwrap();
+            assert_eq!(mem.memfd().is_some(), backing == Backing::Memfd);
+            mem.write_obj_at_addr(0x1122_3344_5566_7788u64, 2 * PAGE - 8)
+                .unwrap();
+            assert_eq!(
+                mem.read_obj_from_addr::<u32>(2 * PAGE - 4).unwrap(),
+                0x1122_3344
+            );
+            // Unaligned and in the second region.
+            mem.write_obj_at_addr(0xabcdu16, 4 * PAGE + 3).unwrap();
+            assert_eq!(mem.read_u16(4 * PAGE + 3).unwrap(), 0xabcd);
+            // Past the end of the first region, between regions and across the end of memory.
+            assert!(mem.read_obj_from_addr::<u64>(2 * PAGE - 4).is_err());
+            assert!(mem.read_obj_from_addr::<u8>(3 * PAGE).is_err());
+            assert!(mem.write_obj_at_addr(0u16, 5 * PAGE - 1).is_err());
+            assert!(mem.get_slice_at_addr(u64::MAX, 2).is_err());
+        }
+    }
+
+    #[test]
+    fn memfd_shares_contents() {
+        let mem = GuestMemory::new(&[(0, PAGE), (0x10000, PAGE)], Backing::Memfd).unwrap();
+        mem.write_obj_at_addr(7u8, 0x10000 + 5).unwrap();
+        let mut byte = [0u8];
+        // The second region comes right after the first in the memfd.
+        std::os::unix::fs::FileExt::read_exact_at(mem.memfd().unwrap(), &mut byte, PAGE + 5)
+            .unwrap();
+        assert_eq!(byte, [7]);
+    }
+
+    #[test]
+    fn volatile_slices() {
+        let mem = GuestMemory::new(&[(0, PAGE)], Backing::Anonymous).unwrap();
+        let slice = mem.get_slice_at_addr(16, 8).unwrap();
+        assert_eq!(slice.size(), 8);
+        slice.copy_from(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let sub = slice.sub_slice(2, 4).unwrap();
+        let mut buf = [0u8; 6];
+        sub.copy_to(&mut buf);
+        assert_eq!(buf, [3, 4, 5, 6, 0, 0]);
+        assert!(slice.sub_slice(6, 3).is_err());
+        // Only 8 of the 9 bytes fit.
+        assert_eq!(mem.read_obj_from_addr::<u8>(24).unwrap(), 0);
+    }
+
+    #[test]
+    fn bad_regions() {
+        for ranges in [
+            vec![(0, 0)],
+            vec![(1, PAGE)],
+            vec![(0, PAGE + 1)],
+            vec![(0, 2 * PAGE), (PAGE, PAGE)],
+        ] {
+            assert!(matches!(
+                GuestMemory::new(&ranges, Backing::Anonymous),
+                Err(Error::InvalidRegion { .. })
+            ));
+        }
+    }
+}
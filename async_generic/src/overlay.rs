@@ -0,0 +1,449 @@
+// A copy-on-write overlay that keeps a base disk unmodified.
+//
+// Written clusters are stored in a separate disk at the same offsets they have in the virtual
+// disk, so the overlay file only takes space where it was written if it is a sparse file. Which
+// clusters live in the overlay is tracked by an in-memory bitmap; the overlay isn't meant to
+// outlive the process, it's either committed to the base or discarded.
+
+use std::fmt;
+use std::io;
+
+use futures::lock::Mutex;
+
+use crate::disk::{self, AsyncDisk, DiskFuture};
+
+const MIN_CLUSTER_SIZE: u64 = 512;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cluster sizes are powers of two of at least 512 bytes.
+    BadClusterSize(u64),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BadClusterSize(size) => write!(f, "bad cluster size {}", size),
+            Io(e) => write!(f, "overlay I/O failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+// What to put in a range being modified.
+#[derive(Clone, Copy)]
+enum Fill<'a> {
+    Data(&'a [u8]),
+    Zeroes { punch: bool },
+}
+
+struct OverlayState {
+    // One bit per cluster, set if the cluster's contents are in the overlay.
+    dirty: Vec<u64>,
+    // The part of the base that shows through. Shrinking hides the rest so growing again
+    // doesn't bring it back.
+    base_len: u64,
+    size: u64,
+}
+
+impl OverlayState {
+    fn is_dirty(&self, cluster: u64) -> bool {
+        self.dirty
+            .get((cluster / 64) as usize)
+            .is_some_and(|bits| bits & (1 << (cluster % 64)) != 0)
+    }
+
+    fn set_dirty(&mut self, cluster: u64) {
+        let index = (cluster / 64) as usize;
+        if index >= self.dirty.len() {
+            self.dirty.resize(index + 1, 0);
+        }
+        self.dirty[index] |= 1 << (cluster % 64);
+    }
+
+    // Forgets the dirty clusters at and after `cluster`.
+    fn clear_from(&mut self, cluster: u64) {
+        let index = (cluster / 64) as usize;
+        if index < self.dirty.len() {
+            self.dirty[index] &= (1 << (cluster % 64)) - 1;
+            self.dirty.truncate(index + 1);
+        }
+    }
+
+    fn dirty_clusters(&self) -> impl Iterator<Item = u64> + '_ {
+        self.dirty.iter().enumerate().flat_map(|(index, &bits)| {
+            (0..64)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| index as u64 * 64 + bit)
+        })
+    }
+}
+
+/// Presents `base` with writes redirected to `overlay`, one cluster at a time. The base is only
+/// ever read until the overlay is committed.
+pub struct OverlayDisk {
+    base: Box<dyn AsyncDisk>,
+    overlay: Box<dyn AsyncDisk>,
+    cluster_size: u64,
+    state: Mutex<OverlayState>,
+}
+
+impl OverlayDisk {
+    /// Stacks `overlay` on `base`. Whatever `overlay` contained is thrown away.
+    pub async fn new(
+        base: Box<dyn AsyncDisk>,
+        overlay: Box<dyn AsyncDisk>,
+        cluster_size: u64,
+    ) -> Result<OverlayDisk> {
+        if cluster_size < MIN_CLUSTER_SIZE || !cluster_size.is_power_of_two() {
+            return Err(Error::BadClusterSize(cluster_size));
+        }
+        let size = base.get_len().await.map_err(Error::Io)?;
+        let disk = OverlayDisk {
+            base,
+            overlay,
+            cluster_size,
+            state: Mutex::new(OverlayState {
+                dirty: Vec::new(),
+                base_len: size,
+                size,
+            }),
+        };
+        disk.reset_overlay(size).await?;
+        Ok(disk)
+    }
+
+    /// The number of clusters written since the overlay was created, committed or discarded.
+    pub async fn dirty_clusters(&self) -> usize {
+        self.state.lock().await.dirty_clusters().count()
+    }
+
+    /// Writes the dirty clusters back to the base and empties the overlay.
+    pub async fn commit(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let size = state.size;
+        let base_len = self.base.get_len().await.map_err(Error::Io)?;
+        if base_len != size {
+            self.base.set_len(size).await.map_err(Error::Io)?;
+        }
+        // Base data hidden by shrinking and growing again has to be zeroed.
+        if state.base_len < size.min(base_len) {
+            self.base
+                .write_zeroes_at(state.base_len, size.min(base_len) - state.base_len)
+                .await
+                .map_err(Error::Io)?;
+        }
+        let clusters: Vec<u64> = state.dirty_clusters().collect();
+        for cluster in clusters {
+            let start = cluster * self.cluster_size;
+            let len = self.cluster_size.min(size - start) as usize;
+            let data = self
+                .overlay
+                .read_at(start, vec![0u8; len])
+                .await
+                .map_err(Error::Io)?;
+            self.base.write_at(start, data).await.map_err(Error::Io)?;
+        }
+        self.base.flush().await.map_err(Error::Io)?;
+        state.dirty.clear();
+        state.base_len = size;
+        self.reset_overlay(size).await
+    }
+
+    /// Throws away everything written to the overlay, going back to the contents of the base.
+    pub async fn discard(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let size = self.base.get_len().await.map_err(Error::Io)?;
+        state.dirty.clear();
+        state.base_len = size;
+        state.size = size;
+        self.reset_overlay(size).await
+    }
+
+    // Truncating deallocates the overlay's contents before it's extended to `size` again.
+    async fn reset_overlay(&self, size: u64) -> Result<()> {
+        self.overlay.set_len(0).await.map_err(Error::Io)?;
+        self.overlay.set_len(size).await.map_err(Error::Io)
+    }
+
+    fn check_range(&self, state: &OverlayState, offset: u64, len: u64) -> Result<()> {
+        disk::check_range(offset, len, state.size).map_err(Error::Io)
+    }
+
+    // Splits [offset, offset + len) at cluster boundaries.
+    fn chunks(&self, offset: u64, len: u64) -> Vec<(u64, u64)> {
+        disk::cluster_chunks(offset, len, self.cluster_size)
+    }
+
+    // Reads `len` bytes of the base at `offset`, zeroes past the part that shows through.
+    async fn read_base(&self, state: &OverlayState, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        if offset < state.base_len {
+            let count = (state.base_len - offset).min(len as u64) as usize;
+            let data = self
+                .base
+                .read_at(offset, vec![0u8; count])
+                .await
+                .map_err(Error::Io)?;
+            buf[..count].copy_from_slice(&data);
+        }
+        Ok(buf)
+    }
+
+    async fn read(&self, offset: u64, mut buf: Vec<u8>) -> Result<Vec<u8>> {
+        let state = self.state.lock().await;
+        self.check_range(&state, offset, buf.len() as u64)?;
+        let mut pos = 0;
+        for (chunk_offset, count) in self.chunks(offset, buf.len() as u64) {
+            let count = count as usize;
+            let data = if state.is_dirty(chunk_offset / self.cluster_size) {
+                self.overlay
+                    .read_at(chunk_offset, vec![0u8; count])
+                    .await
+                    .map_err(Error::Io)?
+            } else {
+                self.read_base(&state, chunk_offset, count).await?
+            };
+            buf[pos..pos + count].copy_from_slice(&data);
+            pos += count;
+        }
+        Ok(buf)
+    }
+
+    // Puts `fill` in `len` bytes at `offset`, copying the rest of each newly dirtied cluster up
+    // from the base.
+    async fn modify(&self, offset: u64, len: u64, fill: Fill<'_>) -> Result<()> {
+        let mut state = self.state.lock().await;
+        self.check_range(&state, offset, len)?;
+        for (chunk_offset, count) in self.chunks(offset, len) {
+            let cluster = chunk_offset / self.cluster_size;
+            let cluster_start = cluster * self.cluster_size;
+            let cluster_len = self.cluster_size.min(state.size - cluster_start);
+            let pos = (chunk_offset - offset) as usize;
+            let in_cluster = (chunk_offset - cluster_start) as usize;
+            if !state.is_dirty(cluster) && count < cluster_len {
+                let mut data = self
+                    .read_base(&state, cluster_start, cluster_len as usize)
+                    .await?;
+                let dst = &mut data[in_cluster..in_cluster + count as usize];
+                match fill {
+                    Fill::Data(buf) => dst.copy_from_slice(&buf[pos..pos + count as usize]),
+                    Fill::Zeroes { .. } => dst.iter_mut().for_each(|b| *b = 0),
+                }
+                self.overlay
+                    .write_at(cluster_start, data)
+                    .await
+                    .map_err(Error::Io)?;
+            } else {
+                let written = match fill {
+                    Fill::Data(buf) => {
+                        let data = buf[pos..pos + count as usize].to_vec();
+                        self.overlay.write_at(chunk_offset, data).await
+                    }
+                    Fill::Zeroes { punch: true } => {
+                        self.overlay.punch_hole(chunk_offset, count).await
+                    }
+                    Fill::Zeroes { punch: false } => {
+                        self.overlay.write_zeroes_at(chunk_offset, count).await
+                    }
+                };
+                written.map_err(Error::Io)?;
+            }
+            state.set_dirty(cluster);
+        }
+        Ok(())
+    }
+
+    async fn resize(&self, len: u64) -> Result<()> {
+        let mut state = self.state.lock().await;
+        self.overlay.set_len(len).await.map_err(Error::Io)?;
+        if len < state.size {
+            // A cluster cut in half keeps its dirty bit, the overlay has zeroes past the cut.
+            state.clear_from(len.div_ceil(self.cluster_size));
+            state.base_len = state.base_len.min(len);
+        }
+        state.size = len;
+        Ok(())
+    }
+}
+
+impl AsyncDisk for OverlayDisk {
+    fn read_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, Vec<u8>> {
+        Box::pin(async move { Ok(self.read(offset, buf).await?) })
+    }
+
+    fn write_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            let len = buf.len() as u64;
+            Ok(self.modify(offset, len, Fill::Data(&buf)).await?)
+        })
+    }
+
+    fn flush(&self) -> DiskFuture<'_, ()> {
+        self.overlay.flush()
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            Ok(self
+                .modify(offset, len, Fill::Zeroes { punch: true })
+                .await?)
+        })
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move {
+            Ok(self
+                .modify(offset, len, Fill::Zeroes { punch: false })
+                .await?)
+        })
+    }
+
+    fn get_len(&self) -> DiskFuture<'_, u64> {
+        Box::pin(async move { Ok(self.state.lock().await.size) })
+    }
+
+    fn set_len(&self, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.resize(len).await?) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use tempfile::tempfile;
+
+    use crate::blocking::BlockingPool;
+    use crate::disk::SingleFileDisk;
+
+    const CLUSTER: u64 = 512;
+
+    // Returns the overlay and a handle to the base file, which starts as four clusters of 1s.
+    fn overlay_disk() -> (OverlayDisk, File) {
+        let pool = Arc::new(BlockingPool::new(2));
+        let mut base_file = tempfile().unwrap();
+        base_file.write_all(&[1u8; 4 * CLUSTER as usize]).unwrap();
+        let base = SingleFileDisk::new(base_file.try_clone().unwrap(), pool.clone());
+        let overlay = SingleFileDisk::new(tempfile().unwrap(), pool);
+        let disk = block_on(OverlayDisk::new(Box::new(base), Box::new(overlay), CLUSTER)).unwrap();
+        (disk, base_file)
+    }
+
+    fn base_contents(file: &File) -> Vec<u8> {
+        let mut buf = vec![0u8; file.metadata().unwrap().len() as usize];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn writes_stay_in_overlay() {
+        let (disk, base_file) = overlay_disk();
+        block_on(async {
+            disk.write_at(500, vec![2u8; 20]).await.unwrap();
+            assert_eq!(disk.dirty_clusters().await, 2);
+            let buf = disk
+                .read_at(0, vec![0u8; 3 * CLUSTER as usize])
+                .await
+                .unwrap();
+            assert!(buf[..500].iter().all(|&b| b == 1));
+            assert!(buf[500..520].iter().all(|&b| b == 2));
+            assert!(buf[520..].iter().all(|&b| b == 1));
+            disk.write_zeroes_at(CLUSTER * 2, CLUSTER).await.unwrap();
+            disk.punch_hole(CLUSTER * 3 + 10, 10).await.unwrap();
+            let buf = disk.read_at(CLUSTER * 2, vec![9u8; 1024]).await.unwrap();
+            assert!(buf[..512].iter().all(|&b| b == 0));
+            assert!(buf[512..522].iter().all(|&b| b == 1));
+            assert!(buf[522..532].iter().all(|&b| b == 0));
+            assert!(buf[532..].iter().all(|&b| b == 1));
+            disk.flush().await.unwrap();
+        });
+        assert_eq!(base_contents(&base_file), vec![1u8; 4 * CLUSTER as usize]);
+    }
+
+    #[test]
+    fn commit() {
+        let (disk, base_file) = overlay_disk();
+        block_on(async {
+            disk.write_at(CLUSTER + 1, vec![3u8; 2]).await.unwrap();
+            disk.commit().await.unwrap();
+            assert_eq!(disk.dirty_clusters().await, 0);
+            let buf = disk.read_at(CLUSTER, vec![0u8; 4]).await.unwrap();
+            assert_eq!(buf, [1, 3, 3, 1]);
+        });
+        let base = base_contents(&base_file);
+        assert_eq!(base[CLUSTER as usize..CLUSTER as usize + 4], [1, 3, 3, 1]);
+        assert_eq!(base.iter().filter(|&&b| b == 3).count(), 2);
+    }
+
+    #[test]
+    fn discard() {
+        let (disk, base_file) = overlay_disk();
+        block_on(async {
+            disk.write_at(0, vec![4u8; 2 * CLUSTER as usize])
+                .await
+                .unwrap();
+            disk.set_len(CLUSTER * 8).await.unwrap();
+            disk.discard().await.unwrap();
+            assert_eq!(disk.get_len().await.unwrap(), 4 * CLUSTER);
+            let buf = disk
+                .read_at(0, vec![0u8; 4 * CLUSTER as usize])
+                .await
+                .unwrap();
+            assert_eq!(buf, vec![1u8; 4 * CLUSTER as usize]);
+        });
+        assert_eq!(base_contents(&base_file), vec![1u8; 4 * CLUSTER as usize]);
+    }
+
+    #[test]
+    fn resize() {
+        let (disk, base_file) = overlay_disk();
+        block_on(async {
+            disk.write_at(CLUSTER, vec![5u8; 4]).await.unwrap();
+            // Cuts the base and the dirty cluster in half.
+            disk.set_len(CLUSTER + 2).await.unwrap();
+            disk.set_len(CLUSTER * 3).await.unwrap();
+            let buf = disk.read_at(CLUSTER, vec![9u8; 8]).await.unwrap();
+            assert_eq!(buf, [5, 5, 0, 0, 0, 0, 0, 0]);
+            assert!(disk.read_at(CLUSTER * 3, vec![0u8; 1]).await.is_err());
+            disk.commit().await.unwrap();
+        });
+        let base = base_contents(&base_file);
+        assert_eq!(base.len() as u64, CLUSTER * 3);
+        assert!(base[..CLUSTER as usize].iter().all(|&b| b == 1));
+        assert_eq!(base[CLUSTER as usize..CLUSTER as usize + 2], [5, 5]);
+        assert!(base[CLUSTER as usize + 2..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn bad_cluster_size() {
+        let pool = Arc::new(BlockingPool::new(1));
+        let base = SingleFileDisk::new(tempfile().unwrap(), pool.clone());
+        let overlay = SingleFileDisk::new(tempfile().unwrap(), pool);
+        match block_on(OverlayDisk::new(Box::new(base), Box::new(overlay), 1000)) {
+            Err(Error::BadClusterSize(1000)) => (),
+            r => panic!("unexpected result {:?}", r.err()),
+        }
+    }
+}
@@ -0,0 +1,1044 @@
+// qcow2 images on top of another AsyncDisk.
+//
+// Supports versions 2 and 3 with 16 bit refcounts. Compressed clusters, encryption and internal
+// snapshots aren't supported. Clusters are always allocated at the end of the file and freed
+// clusters aren't reused.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+
+use futures::lock::Mutex;
+
+use crate::disk::{self, AsyncDisk, DiskFuture};
+
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_LEN: u32 = 72;
+const V3_HEADER_LEN: u32 = 104;
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// 16 bit refcounts, the only width supported.
+const REFCOUNT_ORDER: u32 = 4;
+// Longest backing file name accepted, the same limit as qemu.
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+// Only bit 0, the dirty bit, is understood. It only matters to lazy refcounts, which aren't used.
+const SUPPORTED_INCOMPATIBLE_FEATURES: u64 = 1;
+
+// Bits of L1 and L2 entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+const ZERO_FLAG: u64 = 1;
+
+// L2 tables kept in memory. Entries are written through, so evicting is free.
+const L2_CACHE_TABLES: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    UnsupportedClusterBits(u32),
+    UnsupportedRefcountOrder(u32),
+    UnsupportedFeatures(u64),
+    Encrypted,
+    Snapshots,
+    /// The L1 table is too small for the size of the disk.
+    L1TooSmall(u32),
+    /// The L1 table has more entries than the size of the disk needs.
+    L1TooLarge(u32),
+    /// The L1 table doesn't fit in the image file.
+    L1OutOfFile(u64),
+    /// The refcount table doesn't fit in the image file.
+    RefcountTableOutOfFile(u64),
+    BackingFileNameTooLong(usize),
+    /// Compressed clusters can't be read.
+    CompressedCluster(u64),
+    /// An allocated cluster needs a refcount block the refcount table has no room for.
+    RefcountTableFull,
+    /// The image can't be resized to this many bytes.
+    InvalidSize(u64),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            BadMagic(magic) => write!(f, "bad magic {:#x}", magic),
+            UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            UnsupportedClusterBits(bits) => write!(f, "unsupported cluster bits {}", bits),
+            UnsupportedRefcountOrder(order) => write!(f, "unsupported refcount order {}", order),
+            UnsupportedFeatures(features) => {
+                write!(f, "unsupported incompatible features {:#x}", features)
+            }
+            Encrypted => write!(f, "encrypted images aren't supported"),
+            Snapshots => write!(f, "images with snapshots aren't supported"),
+            L1TooSmall(size) => write!(f, "L1 table of {} entries is too small", size),
+            L1TooLarge(size) => write!(f, "L1 table of {} entries is too large", size),
+            L1OutOfFile(offset) => {
+                write!(f, "L1 table at {:#x} is past the end of the file", offset)
+            }
+            RefcountTableOutOfFile(offset) => write!(
+                f,
+                "refcount table at {:#x} is past the end of the file",
+                offset
+            ),
+            BackingFileNameTooLong(len) => write!(f, "backing file name of {} bytes", len),
+            CompressedCluster(offset) => write!(f, "compressed cluster at {:#x}", offset),
+            RefcountTableFull => write!(f, "refcount table is full"),
+            InvalidSize(size) => write!(f, "can't resize to {} bytes", size),
+            Io(e) => write!(f, "image I/O failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_be_bytes(b)
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_be_bytes(b)
+}
+
+fn div_round_up(n: u64, d: u64) -> u64 {
+    n.div_ceil(d)
+}
+
+/// The fields of a qcow2 header that this implementation uses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QcowHeader {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub header_length: u32,
+}
+
+impl QcowHeader {
+    /// Parses and validates the header at the start of `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<QcowHeader> {
+        if bytes.len() < V2_HEADER_LEN as usize {
+            return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        let magic = be32(bytes, 0);
+        if magic != QCOW_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        let version = be32(bytes, 4);
+        if version != 2 && version != 3 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let cluster_bits = be32(bytes, 20);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(Error::UnsupportedClusterBits(cluster_bits));
+        }
+        if be32(bytes, 32) != 0 {
+            return Err(Error::Encrypted);
+        }
+        if be32(bytes, 60) != 0 {
+            return Err(Error::Snapshots);
+        }
+        let mut header_length = V2_HEADER_LEN;
+        if version == 3 {
+            if bytes.len() < V3_HEADER_LEN as usize {
+                return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            let incompatible = be64(bytes, 72);
+            if incompatible & !SUPPORTED_INCOMPATIBLE_FEATURES != 0 {
+                return Err(Error::UnsupportedFeatures(incompatible));
+            }
+            let refcount_order = be32(bytes, 96);
+            if refcount_order != REFCOUNT_ORDER {
+                return Err(Error::UnsupportedRefcountOrder(refcount_order));
+            }
+            header_length = be32(bytes, 100);
+        }
+        let header = QcowHeader {
+            version,
+            backing_file_offset: be64(bytes, 8),
+            backing_file_size: be32(bytes, 16),
+            cluster_bits,
+            size: be64(bytes, 24),
+            l1_size: be32(bytes, 36),
+            l1_table_offset: be64(bytes, 40),
+            refcount_table_offset: be64(bytes, 48),
+            refcount_table_clusters: be32(bytes, 56),
+            header_length,
+        };
+        if header.backing_file_size > MAX_BACKING_FILE_SIZE {
+            return Err(Error::BackingFileNameTooLong(
+                header.backing_file_size as usize,
+            ));
+        }
+        let l2_coverage = header.cluster_size() * header.l2_entries();
+        let l1_entries = div_round_up(header.size, l2_coverage);
+        if u64::from(header.l1_size) < l1_entries {
+            return Err(Error::L1TooSmall(header.l1_size));
+        }
+        if u64::from(header.l1_size) > l1_entries {
+            return Err(Error::L1TooLarge(header.l1_size));
+        }
+        Ok(header)
+    }
+
+    // Serializes as a version 3 header without extensions.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(V3_HEADER_LEN as usize);
+        bytes.extend_from_slice(&QCOW_MAGIC.to_be_bytes());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.backing_file_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.backing_file_size.to_be_bytes());
+        bytes.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&0u32.to_be_bytes()); // crypt_method
+        bytes.extend_from_slice(&self.l1_size.to_be_bytes());
+        bytes.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        bytes.extend_from_slice(&0u32.to_be_bytes()); // nb_snapshots
+        bytes.extend_from_slice(&0u64.to_be_bytes()); // snapshots_offset
+        bytes.extend_from_slice(&0u64.to_be_bytes()); // incompatible_features
+        bytes.extend_from_slice(&0u64.to_be_bytes()); // compatible_features
+        bytes.extend_from_slice(&0u64.to_be_bytes()); // autoclear_features
+        bytes.extend_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+        bytes.extend_from_slice(&self.header_length.to_be_bytes());
+        bytes
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Entries in each L2 table, one cluster of 64 bit entries.
+    pub fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Refcounts in each refcount block, one cluster of 16 bit refcounts.
+    pub fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size() / 2
+    }
+}
+
+// L2 tables by file offset, evicted oldest first.
+struct L2Cache {
+    tables: HashMap<u64, Vec<u64>>,
+    order: VecDeque<u64>,
+}
+
+impl L2Cache {
+    fn new() -> L2Cache {
+        L2Cache {
+            tables: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get_mut(&mut self, offset: u64) -> Option<&mut Vec<u64>> {
+        self.tables.get_mut(&offset)
+    }
+
+    fn insert(&mut self, offset: u64, table: Vec<u64>) {
+        if self.tables.len() >= L2_CACHE_TABLES {
+            if let Some(old) = self.order.pop_front() {
+                self.tables.remove(&old);
+            }
+        }
+        self.order.push_back(offset);
+        self.tables.insert(offset, table);
+    }
+}
+
+// Metadata that changes as clusters are allocated. Held across the awaits of a whole operation
+// so concurrent writes can't allocate the same cluster twice.
+struct QcowState {
+    l1_table: Vec<u64>,
+    l2_cache: L2Cache,
+    refcount_table: Vec<u64>,
+    // Where the next allocated cluster goes, the end of the file rounded up to a cluster.
+    next_cluster: u64,
+    size: u64,
+}
+
+/// A qcow2 image stored in `file`, reading unallocated clusters from an optional backing disk.
+pub struct QcowFile {
+    file: Box<dyn AsyncDisk>,
+    header: QcowHeader,
+    backing_file_name: Option<String>,
+    backing: Option<Box<dyn AsyncDisk>>,
+    state: Mutex<QcowState>,
+}
+
+impl QcowFile {
+    /// Opens the image in `file`. If it names a backing file, attach it with `set_backing_file`,
+    /// until then unallocated clusters read as zeroes.
+    pub async fn open(file: Box<dyn AsyncDisk>) -> Result<QcowFile> {
+        let header_bytes = file
+            .read_at(0, vec![0u8; V3_HEADER_LEN as usize])
+            .await
+            .map_err(Error::Io)?;
+        let header = QcowHeader::parse(&header_bytes)?;
+        // The tables are sized by the header, don't allocate more than the file could hold.
+        let file_len = file.get_len().await.map_err(Error::Io)?;
+        let l1_len = u64::from(header.l1_size) * 8;
+        if !in_file(header.l1_table_offset, l1_len, file_len) {
+            return Err(Error::L1OutOfFile(header.l1_table_offset));
+        }
+        let refcount_table_len = u64::from(header.refcount_table_clusters) * header.cluster_size();
+        if !in_file(header.refcount_table_offset, refcount_table_len, file_len) {
+            return Err(Error::RefcountTableOutOfFile(header.refcount_table_offset));
+        }
+        let backing_file_name = if header.backing_file_offset != 0 {
+            let name = file
+                .read_at(
+                    header.backing_file_offset,
+                    vec![0u8; header.backing_file_size as usize],
+                )
+                .await
+                .map_err(Error::Io)?;
+            Some(String::from_utf8_lossy(&name).into_owned())
+        } else {
+            None
+        };
+        let l1_table =
+            read_table(&*file, header.l1_table_offset, u64::from(header.l1_size)).await?;
+        let refcount_table =
+            read_table(&*file, header.refcount_table_offset, refcount_table_len / 8).await?;
+        let next_cluster = div_round_up(file_len, header.cluster_size()) * header.cluster_size();
+        let size = header.size;
+        Ok(QcowFile {
+            file,
+            header,
+            backing_file_name,
+            backing: None,
+            state: Mutex::new(QcowState {
+                l1_table,
+                l2_cache: L2Cache::new(),
+                refcount_table,
+                next_cluster,
+                size,
+            }),
+        })
+    }
+
+    /// Writes an empty version 3 image of `size` bytes to `file` and opens it. `backing_file_name`
+    /// is only recorded, attach the disk itself with `set_backing_file`.
+    pub async fn create(
+        file: Box<dyn AsyncDisk>,
+        size: u64,
+        backing_file_name: Option<&str>,
+    ) -> Result<QcowFile> {
+        QcowFile::create_with_cluster_bits(file, size, backing_file_name, DEFAULT_CLUSTER_BITS)
+            .await
+    }
+
+    /// Like `create`, with clusters of `1 << cluster_bits` bytes.
+    pub async fn create_with_cluster_bits(
+        file: Box<dyn AsyncDisk>,
+        size: u64,
+        backing_file_name: Option<&str>,
+        cluster_bits: u32,
+    ) -> Result<QcowFile> {
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(Error::UnsupportedClusterBits(cluster_bits));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_coverage = cluster_size * (cluster_size / 8);
+        let l1_size = div_round_up(size, l2_coverage);
+        let l1_clusters = div_round_up(l1_size * 8, cluster_size);
+        // Header, refcount table, first refcount block, then the L1 table.
+        let refcount_table_offset = cluster_size;
+        let refcount_block_offset = 2 * cluster_size;
+        let l1_table_offset = 3 * cluster_size;
+        let clusters = 3 + l1_clusters;
+        if clusters > cluster_size / 2 {
+            return Err(Error::InvalidSize(size));
+        }
+
+        let (backing_file_offset, backing_file_size) = match backing_file_name {
+            Some(name) => {
+                // After the header and the end of header extensions marker.
+                let offset = u64::from(V3_HEADER_LEN) + 8;
+                if name.len() > MAX_BACKING_FILE_SIZE as usize
+                    || offset + name.len() as u64 > cluster_size
+                {
+                    return Err(Error::BackingFileNameTooLong(name.len()));
+                }
+                (offset, name.len() as u32)
+            }
+            None => (0, 0),
+        };
+        let header = QcowHeader {
+            version: 3,
+            backing_file_offset,
+            backing_file_size,
+            cluster_bits,
+            size,
+            l1_size: l1_size as u32,
+            l1_table_offset,
+            refcount_table_offset,
+            refcount_table_clusters: 1,
+            header_length: V3_HEADER_LEN,
+        };
+
+        let mut first_cluster = header.to_bytes();
+        first_cluster.resize(cluster_size as usize, 0);
+        if let Some(name) = backing_file_name {
+            let start = backing_file_offset as usize;
+            first_cluster[start..start + name.len()].copy_from_slice(name.as_bytes());
+        }
+        let mut refcount_table = vec![0u8; cluster_size as usize];
+        refcount_table[..8].copy_from_slice(&refcount_block_offset.to_be_bytes());
+        let mut refcount_block = vec![0u8; cluster_size as usize];
+        for cluster in 0..clusters as usize {
+            refcount_block[cluster * 2..cluster * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+
+        file.set_len(0).await.map_err(Error::Io)?;
+        file.set_len(clusters * cluster_size)
+            .await
+            .map_err(Error::Io)?;
+        file.write_at(0, first_cluster).await.map_err(Error::Io)?;
+        file.write_at(refcount_table_offset, refcount_table)
+            .await
+            .map_err(Error::Io)?;
+        file.write_at(refcount_block_offset, refcount_block)
+            .await
+            .map_err(Error::Io)?;
+        // The L1 table is already zeroes.
+        QcowFile::open(file).await
+    }
+
+    pub fn header(&self) -> &QcowHeader {
+        &self.header
+    }
+
+    /// The name of the backing file recorded in the image, if any.
+    pub fn backing_file_name(&self) -> Option<&str> {
+        self.backing_file_name.as_deref()
+    }
+
+    /// Reads clusters the image hasn't allocated from `backing`.
+    pub fn set_backing_file(&mut self, backing: Option<Box<dyn AsyncDisk>>) {
+        self.backing = backing;
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.header.cluster_size()
+    }
+
+    // Splits `offset` into the L1 index, the L2 index and the offset inside the cluster.
+    fn indices(&self, offset: u64) -> (usize, usize, u64) {
+        let cluster = offset >> self.header.cluster_bits;
+        let l2_entries = self.header.l2_entries();
+        (
+            (cluster / l2_entries) as usize,
+            (cluster % l2_entries) as usize,
+            offset & (self.cluster_size() - 1),
+        )
+    }
+
+    // Returns the L2 table at `offset`, reading it into the cache if needed.
+    async fn l2_table<'a>(
+        &self,
+        state: &'a mut QcowState,
+        offset: u64,
+    ) -> Result<&'a mut Vec<u64>> {
+        if state.l2_cache.get_mut(offset).is_none() {
+            let table = read_table(&*self.file, offset, self.header.l2_entries()).await?;
+            state.l2_cache.insert(offset, table);
+        }
+        Ok(state.l2_cache.get_mut(offset).unwrap())
+    }
+
+    // Returns the L2 entry for the cluster containing `offset`, zero if unallocated.
+    async fn l2_entry(&self, state: &mut QcowState, offset: u64) -> Result<u64> {
+        let (l1_index, l2_index, _) = self.indices(offset);
+        let l2_offset = state.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.l2_table(state, l2_offset).await?[l2_index])
+    }
+
+    // Reads `len` bytes of the backing disk at `offset`, zeroes where there is none.
+    async fn read_backing(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        if let Some(backing) = &self.backing {
+            let backing_len = backing.get_len().await.map_err(Error::Io)?;
+            if offset < backing_len {
+                let count = (backing_len - offset).min(len as u64) as usize;
+                let data = backing
+                    .read_at(offset, vec![0u8; count])
+                    .await
+                    .map_err(Error::Io)?;
+                buf[..count].copy_from_slice(&data);
+            }
+        }
+        Ok(buf)
+    }
+
+    fn check_range(&self, state: &QcowState, offset: u64, len: u64) -> Result<()> {
+        disk::check_range(offset, len, state.size).map_err(Error::Io)
+    }
+
+    // Splits [offset, offset + len) at cluster boundaries.
+    fn chunks(&self, offset: u64, len: u64) -> Vec<(u64, u64)> {
+        disk::cluster_chunks(offset, len, self.cluster_size())
+    }
+
+    async fn read(&self, offset: u64, mut buf: Vec<u8>) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().await;
+        self.check_range(&state, offset, buf.len() as u64)?;
+        let mut pos = 0;
+        for (chunk_offset, count) in self.chunks(offset, buf.len() as u64) {
+            let count = count as usize;
+            let entry = self.l2_entry(&mut state, chunk_offset).await?;
+            if entry & COMPRESSED_FLAG != 0 {
+                return Err(Error::CompressedCluster(chunk_offset));
+            }
+            let data = if entry & ZERO_FLAG != 0 {
+                vec![0u8; count]
+            } else if entry & OFFSET_MASK == 0 {
+                self.read_backing(chunk_offset, count).await?
+            } else {
+                let (_, _, in_cluster) = self.indices(chunk_offset);
+                self.file
+                    .read_at((entry & OFFSET_MASK) + in_cluster, vec![0u8; count])
+                    .await
+                    .map_err(Error::Io)?
+            };
+            buf[pos..pos + count].copy_from_slice(&data);
+            pos += count;
+        }
+        Ok(buf)
+    }
+
+    // Sets the refcount of the cluster at `offset`, adding a refcount block if there is none
+    // for it yet.
+    fn set_refcount<'a>(
+        &'a self,
+        state: &'a mut QcowState,
+        offset: u64,
+        refcount: u16,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>> {
+        Box::pin(async move {
+            let cluster = offset >> self.header.cluster_bits;
+            let entries = self.header.refcount_block_entries();
+            let table_index = (cluster / entries) as usize;
+            if table_index >= state.refcount_table.len() {
+                return Err(Error::RefcountTableFull);
+            }
+            let mut block = state.refcount_table[table_index];
+            if block == 0 {
+                block = state.next_cluster;
+                state.next_cluster += self.cluster_size();
+                self.file
+                    .write_at(block, vec![0u8; self.cluster_size() as usize])
+                    .await
+                    .map_err(Error::Io)?;
+                self.file
+                    .write_at(
+                        self.header.refcount_table_offset + table_index as u64 * 8,
+                        block.to_be_bytes().to_vec(),
+                    )
+                    .await
+                    .map_err(Error::Io)?;
+                state.refcount_table[table_index] = block;
+                // The new block is counted by itself or the block after it.
+                self.set_refcount(state, block, 1).await?;
+            }
+            self.file
+                .write_at(
+                    block + (cluster % entries) * 2,
+                    refcount.to_be_bytes().to_vec(),
+                )
+                .await
+                .map_err(Error::Io)
+        })
+    }
+
+    async fn allocate_cluster(&self, state: &mut QcowState) -> Result<u64> {
+        let offset = state.next_cluster;
+        state.next_cluster += self.cluster_size();
+        self.set_refcount(state, offset, 1).await?;
+        Ok(offset)
+    }
+
+    // Points the L2 entry for `offset` at `entry`, allocating the L2 table if needed.
+    async fn set_l2_entry(&self, state: &mut QcowState, offset: u64, entry: u64) -> Result<()> {
+        let (l1_index, l2_index, _) = self.indices(offset);
+        let mut l2_offset = state.l1_table[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster(state).await?;
+            self.file
+                .write_at(l2_offset, vec![0u8; self.cluster_size() as usize])
+                .await
+                .map_err(Error::Io)?;
+            state
+                .l2_cache
+                .insert(l2_offset, vec![0; self.header.l2_entries() as usize]);
+            let l1_entry = l2_offset | COPIED_FLAG;
+            self.file
+                .write_at(
+                    self.header.l1_table_offset + l1_index as u64 * 8,
+                    l1_entry.to_be_bytes().to_vec(),
+                )
+                .await
+                .map_err(Error::Io)?;
+            state.l1_table[l1_index] = l1_entry;
+        }
+        self.l2_table(state, l2_offset).await?[l2_index] = entry;
+        self.file
+            .write_at(
+                l2_offset + l2_index as u64 * 8,
+                entry.to_be_bytes().to_vec(),
+            )
+            .await
+            .map_err(Error::Io)
+    }
+
+    async fn write(&self, offset: u64, buf: Vec<u8>) -> Result<()> {
+        let mut state = self.state.lock().await;
+        self.check_range(&state, offset, buf.len() as u64)?;
+        let mut pos = 0;
+        for (chunk_offset, count) in self.chunks(offset, buf.len() as u64) {
+            let count = count as usize;
+            let data = &buf[pos..pos + count];
+            pos += count;
+            let (_, _, in_cluster) = self.indices(chunk_offset);
+            let entry = self.l2_entry(&mut state, chunk_offset).await?;
+            if entry & COMPRESSED_FLAG != 0 {
+                return Err(Error::CompressedCluster(chunk_offset));
+            }
+            let cluster_offset = entry & OFFSET_MASK;
+            if cluster_offset != 0 && entry & ZERO_FLAG == 0 {
+                self.file
+                    .write_at(cluster_offset + in_cluster, data.to_vec())
+                    .await
+                    .map_err(Error::Io)?;
+                continue;
+            }
+
+            // Fill a new cluster with what reads returned before the write, the backing file or
+            // zeroes, then the new data.
+            let cluster_start = chunk_offset - in_cluster;
+            let mut cluster = if count as u64 == self.cluster_size() {
+                Vec::new()
+            } else if entry & ZERO_FLAG != 0 {
+                vec![0u8; self.cluster_size() as usize]
+            } else {
+                self.read_backing(cluster_start, self.cluster_size() as usize)
+                    .await?
+            };
+            if cluster.is_empty() {
+                cluster = data.to_vec();
+            } else {
+                cluster[in_cluster as usize..in_cluster as usize + count].copy_from_slice(data);
+            }
+            // A zero cluster may still own its old allocation.
+            let new_offset = if cluster_offset != 0 {
+                cluster_offset
+            } else {
+                self.allocate_cluster(&mut state).await?
+            };
+            self.file
+                .write_at(new_offset, cluster)
+                .await
+                .map_err(Error::Io)?;
+            self.set_l2_entry(&mut state, chunk_offset, new_offset | COPIED_FLAG)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Makes [offset, offset + len) read as zeroes. Whole clusters are released when nothing shows
+    // through from a backing file, or marked as zero clusters in version 3 images. Partial
+    // clusters are written with zeroes.
+    async fn zero_range(&self, offset: u64, len: u64) -> Result<()> {
+        let mut partial = Vec::new();
+        {
+            let mut state = self.state.lock().await;
+            self.check_range(&state, offset, len)?;
+            for (chunk_offset, count) in self.chunks(offset, len) {
+                if count != self.cluster_size() {
+                    partial.push((chunk_offset, count));
+                    continue;
+                }
+                // An image that names a backing file must hide it even before it's attached.
+                let new_entry = if self.backing_file_name.is_none() && self.backing.is_none() {
+                    0
+                } else if self.header.version >= 3 {
+                    ZERO_FLAG
+                } else {
+                    partial.push((chunk_offset, count));
+                    continue;
+                };
+                let entry = self.l2_entry(&mut state, chunk_offset).await?;
+                if entry == new_entry {
+                    continue;
+                }
+                let cluster_offset = entry & OFFSET_MASK;
+                self.set_l2_entry(&mut state, chunk_offset, new_entry)
+                    .await?;
+                if cluster_offset != 0 {
+                    self.set_refcount(&mut state, cluster_offset, 0).await?;
+                    // Give the space back to the host, nothing references it anymore.
+                    self.file
+                        .punch_hole(cluster_offset, self.cluster_size())
+                        .await
+                        .map_err(Error::Io)?;
+                }
+            }
+        }
+        for (chunk_offset, count) in partial {
+            self.write(chunk_offset, vec![0u8; count as usize]).await?;
+        }
+        Ok(())
+    }
+
+    async fn resize(&self, len: u64) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let l2_coverage = self.cluster_size() * self.header.l2_entries();
+        // Shrinking would have to free clusters, growing past the L1 table would have to move it.
+        if len < state.size || div_round_up(len, l2_coverage) > state.l1_table.len() as u64 {
+            return Err(Error::InvalidSize(len));
+        }
+        self.file
+            .write_at(24, len.to_be_bytes().to_vec())
+            .await
+            .map_err(Error::Io)?;
+        state.size = len;
+        Ok(())
+    }
+}
+
+// Whether `len` bytes at `offset` are inside a file of `file_len` bytes.
+fn in_file(offset: u64, len: u64, file_len: u64) -> bool {
+    offset.checked_add(len).is_some_and(|end| end <= file_len)
+}
+
+// Reads a table of `entries` big endian u64s at `offset`.
+async fn read_table(file: &dyn AsyncDisk, offset: u64, entries: u64) -> Result<Vec<u64>> {
+    let bytes = file
+        .read_at(offset, vec![0u8; entries as usize * 8])
+        .await
+        .map_err(Error::Io)?;
+    Ok(bytes.chunks(8).map(|b| be64(b, 0)).collect())
+}
+
+impl AsyncDisk for QcowFile {
+    fn read_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, Vec<u8>> {
+        Box::pin(async move { Ok(self.read(offset, buf).await?) })
+    }
+
+    fn write_at(&self, offset: u64, buf: Vec<u8>) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.write(offset, buf).await?) })
+    }
+
+    fn flush(&self) -> DiskFuture<'_, ()> {
+        self.file.flush()
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.zero_range(offset, len).await?) })
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.zero_range(offset, len).await?) })
+    }
+
+    fn get_len(&self) -> DiskFuture<'_, u64> {
+        Box::pin(async move { Ok(self.state.lock().await.size) })
+    }
+
+    fn set_len(&self, len: u64) -> DiskFuture<'_, ()> {
+        Box::pin(async move { Ok(self.resize(len).await?) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use tempfile::tempfile;
+
+    use crate::blocking::BlockingPool;
+    use crate::disk::SingleFileDisk;
+
+    const MB: u64 = 1 << 20;
+
+    fn raw(file: &File, pool: &Arc<BlockingPool>) -> Box<dyn AsyncDisk> {
+        Box::new(SingleFileDisk::new(file.try_clone().unwrap(), pool.clone()))
+    }
+
+    // Counts every reference to each cluster by walking the metadata and compares the counts
+    // with the refcounts stored in the image.
+    async fn check_refcounts(qcow: &QcowFile, file: &dyn AsyncDisk) {
+        let h = qcow.header();
+        let cluster_size = h.cluster_size();
+        let len = file.get_len().await.unwrap();
+        let clusters = div_round_up(len, cluster_size) as usize;
+        let mut refs = vec![0u16; clusters];
+        let mut add = |offset: u64| refs[(offset / cluster_size) as usize] += 1;
+
+        add(0);
+        for i in 0..u64::from(h.refcount_table_clusters) {
+            add(h.refcount_table_offset + i * cluster_size);
+        }
+        for i in 0..div_round_up(u64::from(h.l1_size) * 8, cluster_size) {
+            add(h.l1_table_offset + i * cluster_size);
+        }
+        let refcount_table = read_table(
+            file,
+            h.refcount_table_offset,
+            u64::from(h.refcount_table_clusters) * cluster_size / 8,
+        )
+        .await
+        .unwrap();
+        for &block in refcount_table.iter().filter(|&&b| b != 0) {
+            add(block);
+        }
+        let l1 = read_table(file, h.l1_table_offset, u64::from(h.l1_size))
+            .await
+            .unwrap();
+        for l1_entry in l1.into_iter().filter(|&e| e != 0) {
+            let l2_offset = l1_entry & OFFSET_MASK;
+            add(l2_offset);
+            let l2 = read_table(file, l2_offset, h.l2_entries()).await.unwrap();
+            for l2_entry in l2.into_iter().filter(|&e| e & OFFSET_MASK != 0) {
+                add(l2_entry & OFFSET_MASK);
+            }
+        }
+
+        let entries = h.refcount_block_entries();
+        for (cluster, &expected) in refs.iter().enumerate() {
+            let block = refcount_table[cluster / entries as usize];
+            let stored = if block == 0 {
+                0
+            } else {
+                let b = file
+                    .read_at(block + (cluster as u64 % entries) * 2, vec![0u8; 2])
+                    .await
+                    .unwrap();
+                u16::from_be_bytes([b[0], b[1]])
+            };
+            assert_eq!(stored, expected, "refcount of cluster {}", cluster);
+        }
+    }
+
+    #[test]
+    fn create_and_parse_header() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let file = tempfile().unwrap();
+        block_on(async {
+            let qcow = QcowFile::create(raw(&file, &pool), 100 * MB, None)
+                .await
+                .unwrap();
+            let header = qcow.header().clone();
+            assert_eq!(header.version, 3);
+            assert_eq!(header.size, 100 * MB);
+            assert_eq!(header.cluster_size(), 65536);
+            assert_eq!(header.l1_size, 1);
+            assert_eq!(qcow.backing_file_name(), None);
+            check_refcounts(&qcow, &*raw(&file, &pool)).await;
+
+            let mut bytes = raw(&file, &pool)
+                .read_at(0, vec![0u8; V3_HEADER_LEN as usize])
+                .await
+                .unwrap();
+            assert_eq!(QcowHeader::parse(&bytes).unwrap(), header);
+            bytes[0] = 0;
+            assert!(matches!(QcowHeader::parse(&bytes), Err(Error::BadMagic(_))));
+        });
+    }
+
+    // Creates an image, overwrites the header field at `offset` with `bytes` and opens it again.
+    fn open_patched(pool: &Arc<BlockingPool>, offset: u64, bytes: Vec<u8>) -> Result<QcowFile> {
+        let file = tempfile().unwrap();
+        block_on(async {
+            QcowFile::create(raw(&file, pool), 4 * MB, None)
+                .await
+                .unwrap();
+            raw(&file, pool).write_at(offset, bytes).await.unwrap();
+            QcowFile::open(raw(&file, pool)).await
+        })
+    }
+
+    #[test]
+    fn malicious_headers() {
+        let pool = Arc::new(BlockingPool::new(2));
+        // Each header claims a name or table far bigger than the file, which must fail before
+        // anything that size is allocated.
+        let too_big = u32::MAX.to_be_bytes().to_vec();
+        assert!(matches!(
+            open_patched(&pool, 16, too_big.clone()),
+            Err(Error::BackingFileNameTooLong(_))
+        ));
+        assert!(matches!(
+            open_patched(&pool, 36, too_big.clone()),
+            Err(Error::L1TooLarge(_))
+        ));
+        assert!(matches!(
+            open_patched(&pool, 40, (u64::MAX - 7).to_be_bytes().to_vec()),
+            Err(Error::L1OutOfFile(_))
+        ));
+        assert!(matches!(
+            open_patched(&pool, 56, too_big),
+            Err(Error::RefcountTableOutOfFile(_))
+        ));
+        assert!(matches!(
+            open_patched(&pool, 48, (1u64 << 40).to_be_bytes().to_vec()),
+            Err(Error::RefcountTableOutOfFile(_))
+        ));
+    }
+
+    #[test]
+    fn write_read_reopen() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let file = tempfile().unwrap();
+        block_on(async {
+            let qcow = QcowFile::create(raw(&file, &pool), 4096 * MB, None)
+                .await
+                .unwrap();
+            assert_eq!(
+                qcow.read_at(0, vec![1u8; 100]).await.unwrap(),
+                vec![0u8; 100]
+            );
+            // Crosses a cluster boundary, and lands in a second L2 table.
+            qcow.write_at(65536 - 10, vec![5u8; 20]).await.unwrap();
+            qcow.write_at(3000 * MB, vec![6u8; 65536]).await.unwrap();
+            qcow.write_at(65536 - 5, vec![7u8; 2]).await.unwrap();
+            let buf = qcow.read_at(65536 - 12, vec![0u8; 24]).await.unwrap();
+            assert_eq!(
+                buf,
+                [&[0u8; 2][..], &[5; 5], &[7; 2], &[5; 13], &[0; 2]].concat()
+            );
+            assert!(qcow.read_at(4096 * MB - 1, vec![0u8; 2]).await.is_err());
+            check_refcounts(&qcow, &*raw(&file, &pool)).await;
+        });
+
+        block_on(async {
+            let qcow = QcowFile::open(raw(&file, &pool)).await.unwrap();
+            let buf = qcow.read_at(3000 * MB - 1, vec![0u8; 65538]).await.unwrap();
+            assert_eq!(buf[0], 0);
+            assert!(buf[1..65537].iter().all(|&b| b == 6));
+            assert_eq!(buf[65537], 0);
+            assert_eq!(qcow.get_len().await.unwrap(), 4096 * MB);
+        });
+    }
+
+    #[test]
+    fn refcount_blocks_added() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let file = tempfile().unwrap();
+        block_on(async {
+            // With 512 byte clusters a refcount block covers 256 clusters. Fill past it.
+            let qcow = QcowFile::create_with_cluster_bits(raw(&file, &pool), MB, None, 9)
+                .await
+                .unwrap();
+            for i in 0..300u64 {
+                qcow.write_at(i * 512, vec![1u8; 1]).await.unwrap();
+            }
+            assert_ne!(qcow.state.lock().await.refcount_table[1], 0);
+            check_refcounts(&qcow, &*raw(&file, &pool)).await;
+        });
+    }
+
+    #[test]
+    fn backing_file() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let mut base = tempfile().unwrap();
+        base.write_all(&vec![9u8; 100_000]).unwrap();
+        let file = tempfile().unwrap();
+        block_on(async {
+            let mut qcow = QcowFile::create(raw(&file, &pool), 4 * MB, Some("base.img"))
+                .await
+                .unwrap();
+            assert_eq!(qcow.backing_file_name(), Some("base.img"));
+            qcow.set_backing_file(Some(raw(&base, &pool)));
+
+            let buf = qcow.read_at(99_990, vec![0u8; 20]).await.unwrap();
+            assert_eq!(buf, [&[9u8; 10][..], &[0; 10]].concat());
+            // Copies the rest of the cluster from the backing file.
+            qcow.write_at(10, vec![1u8; 10]).await.unwrap();
+            let buf = qcow.read_at(0, vec![0u8; 30]).await.unwrap();
+            assert_eq!(buf, [&[9u8; 10][..], &[1; 10], &[9; 10]].concat());
+            // Zeroed clusters hide the backing file.
+            qcow.write_zeroes_at(0, 65536).await.unwrap();
+            qcow.punch_hole(65536, 65536).await.unwrap();
+            let buf = qcow.read_at(0, vec![1u8; 100_000]).await.unwrap();
+            assert!(buf.iter().all(|&b| b == 0));
+            check_refcounts(&qcow, &*raw(&file, &pool)).await;
+        });
+        block_on(async {
+            let qcow = QcowFile::open(raw(&file, &pool)).await.unwrap();
+            assert_eq!(qcow.backing_file_name(), Some("base.img"));
+            // Discarding without the backing file attached still hides it.
+            qcow.write_at(65536, vec![2u8; 65536]).await.unwrap();
+            qcow.punch_hole(65536, 65536).await.unwrap();
+        });
+        block_on(async {
+            let mut qcow = QcowFile::open(raw(&file, &pool)).await.unwrap();
+            qcow.set_backing_file(Some(raw(&base, &pool)));
+            let buf = qcow.read_at(65536, vec![1u8; 65536]).await.unwrap();
+            assert!(buf.iter().all(|&b| b == 0));
+        });
+    }
+
+    #[test]
+    fn discard_frees_clusters() {
+        let pool = Arc::new(BlockingPool::new(2));
+        let file = tempfile().unwrap();
+        block_on(async {
+            let qcow = QcowFile::create(raw(&file, &pool), 4 * MB, None)
+                .await
+                .unwrap();
+            qcow.write_at(0, vec![3u8; 3 * 65536]).await.unwrap();
+            qcow.punch_hole(100, 2 * 65536).await.unwrap();
+            let buf = qcow.read_at(0, vec![1u8; 3 * 65536]).await.unwrap();
+            assert!(buf[..100].iter().all(|&b| b == 3));
+            assert!(buf[100..100 + 2 * 65536].iter().all(|&b| b == 0));
+            assert!(buf[100 + 2 * 65536..].iter().all(|&b| b == 3));
+            // The one whole cluster in the range is released.
+            check_refcounts(&qcow, &*raw(&file, &pool)).await;
+            assert_eq!(
+                qcow.l2_entry(&mut *qcow.state.lock().await, 65536)
+                    .await
+                    .unwrap(),
+                0
+            );
+        });
+    }
+}
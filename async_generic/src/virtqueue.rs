@@ -0,0 +1,699 @@
+// Split virtqueues, the rings a driver hands buffers to a device through.
+//
+// The driver puts chains of descriptors in the available ring and kicks the device, the device
+// returns them through the used ring and interrupts the driver. With VIRTIO_F_EVENT_IDX each side
+// tells the other which ring index it next wants to hear about, suppressing the rest of the kicks
+// and interrupts.
+
+use std::fmt;
+use std::io;
+use std::num::Wrapping;
+use std::rc::Rc;
+use std::sync::atomic::{fence, Ordering};
+
+use crate::blocking::BlockingPool;
+use crate::event::EventFd;
+use crate::mem::{self, Memory};
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+// Set by the driver in the available ring when it doesn't want interrupts.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const MAX_QUEUE_SIZE: u16 = 32768;
+const DESC_SIZE: u64 = 16;
+const USED_ELEM_SIZE: u64 = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Queue sizes are powers of two up to 32768.
+    InvalidSize(u16),
+    /// The ring at this address isn't aligned as the spec requires.
+    Misaligned(u64),
+    /// A descriptor index past the end of its table.
+    InvalidIndex(u16),
+    /// A chain that loops or is longer than its table.
+    ChainTooLong,
+    /// An indirect table whose length isn't a multiple of the descriptor size.
+    InvalidIndirectTable(u32),
+    /// A descriptor inside an indirect table is itself indirect, or is both indirect and chained.
+    InvalidIndirectFlags,
+    /// A device-readable descriptor after a device-writable one.
+    ReadableAfterWritable,
+    /// The chain at this head is off the ring but couldn't be parsed. Return the head to the
+    /// driver with `Queue::add_used` and a length of 0.
+    BadChain(u16, Box<Error>),
+    Memory(mem::Error),
+    Kick(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidSize(size) => write!(f, "invalid queue size {}", size),
+            Misaligned(addr) => write!(f, "ring at {:#x} is misaligned", addr),
+            InvalidIndex(index) => write!(f, "descriptor index {} is out of range", index),
+            ChainTooLong => write!(f, "descriptor chain is too long"),
+            InvalidIndirectTable(len) => write!(f, "indirect table of {} bytes", len),
+            InvalidIndirectFlags => write!(f, "invalid flags on an indirect descriptor"),
+            ReadableAfterWritable => write!(f, "readable descriptor after a writable one"),
+            BadChain(head, e) => write!(f, "bad descriptor chain at {}: {}", head, e),
+            Memory(e) => write!(f, "failed to access the queue: {}", e),
+            Kick(e) => write!(f, "failed to wait for a kick: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mem::Error> for Error {
+    fn from(e: mem::Error) -> Error {
+        Error::Memory(e)
+    }
+}
+
+/// A buffer the driver handed to the device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+}
+
+impl Descriptor {
+    /// True if the device writes the buffer, false if it reads it.
+    pub fn is_write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+}
+
+/// The buffers of one request, with indirect tables already followed. Device-readable buffers
+/// come first.
+#[derive(Debug)]
+pub struct DescriptorChain {
+    head: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// The index to hand back to `Queue::add_used`.
+    pub fn head_index(&self) -> u16 {
+        self.head
+    }
+
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// The buffers the device reads.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|d| !d.is_write_only())
+    }
+
+    /// The buffers the device writes.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|d| d.is_write_only())
+    }
+}
+
+/// Where the driver put the parts of a queue in guest memory.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueLayout {
+    pub size: u16,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+}
+
+/// The device side of a split virtqueue.
+pub struct Queue {
+    mem: Rc<dyn Memory>,
+    layout: QueueLayout,
+    event_idx: bool,
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+    // The used index the driver was last interrupted for.
+    signalled_used: Wrapping<u16>,
+    kick: EventFd,
+    // Waits for kicks, so a queue never holds up disk I/O on a shared pool.
+    kick_thread: BlockingPool,
+}
+
+impl Queue {
+    /// Checks `layout` and creates a queue that waits for kicks on `kick` using a thread of its
+    /// own.
+    pub fn new(mem: Rc<dyn Memory>, layout: QueueLayout, kick: EventFd) -> Result<Queue> {
+        let size = layout.size;
+        if size == 0 || size > MAX_QUEUE_SIZE || !size.is_power_of_two() {
+            return Err(Error::InvalidSize(size));
+        }
+        for (addr, align) in [
+            (layout.desc_table, 16),
+            (layout.avail_ring, 2),
+            (layout.used_ring, 4),
+        ] {
+            if !addr.is_multiple_of(align) {
+                return Err(Error::Misaligned(addr));
+            }
+        }
+        let queue = Queue {
+            mem,
+            layout,
+            event_idx: false,
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            signalled_used: Wrapping(0),
+            kick,
+            kick_thread: BlockingPool::new(1),
+        };
+        // Touch the last byte of each part so a queue that doesn't fit in memory fails now.
+        let size = size as u64;
+        let mut last = [0u8];
+        for (addr, len) in [
+            (layout.desc_table, size * DESC_SIZE),
+            (layout.avail_ring, 6 + 2 * size),
+            (layout.used_ring, 6 + USED_ELEM_SIZE * size),
+        ] {
+            let last_addr = addr.checked_add(len - 1).ok_or(mem::Error::OutOfRange {
+                addr,
+                len: len as usize,
+            })?;
+            queue.mem.read_at_addr(last_addr, &mut last)?;
+        }
+        Ok(queue)
+    }
+
+    /// Turns notification suppression with event indexes on or off, as negotiated with
+    /// VIRTIO_F_EVENT_IDX.
+    pub fn set_event_idx(&mut self, enabled: bool) {
+        self.event_idx = enabled;
+    }
+
+    fn avail_idx(&self) -> Result<Wrapping<u16>> {
+        let idx = self.mem.read_u16(self.layout.avail_ring + 2)?;
+        // The ring entries before the index are only valid after reading it.
+        fence(Ordering::Acquire);
+        Ok(Wrapping(idx))
+    }
+
+    // Where the device tells the driver which available index it next wants a kick for.
+    fn avail_event_addr(&self) -> u64 {
+        self.layout.used_ring + 4 + USED_ELEM_SIZE * self.layout.size as u64
+    }
+
+    // Where the driver tells the device which used index it next wants an interrupt for.
+    fn used_event_addr(&self) -> u64 {
+        self.layout.avail_ring + 4 + 2 * self.layout.size as u64
+    }
+
+    /// Takes the next chain from the available ring, or returns `None` if the driver hasn't
+    /// made one available. A chain that fails to parse is still taken off the ring, and its head
+    /// comes back in `Error::BadChain`.
+    pub fn pop(&mut self) -> Result<Option<DescriptorChain>> {
+        let mut avail_idx = self.avail_idx()?;
+        if avail_idx == self.next_avail && self.event_idx {
+            // Ask for a kick when the next chain arrives, then check again in case it arrived
+            // before the driver could see the request.
+            self.mem
+                .write_u16(self.avail_event_addr(), self.next_avail.0)?;
+            fence(Ordering::SeqCst);
+            avail_idx = self.avail_idx()?;
+        }
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        let slot = (self.next_avail.0 % self.layout.size) as u64;
+        let head = self.mem.read_u16(self.layout.avail_ring + 4 + 2 * slot)?;
+        self.next_avail += Wrapping(1);
+        self.read_chain(head)
+            .map(Some)
+            .map_err(|e| Error::BadChain(head, Box::new(e)))
+    }
+
+    /// Waits for the driver to make a chain available and takes it.
+    pub async fn next_descriptor_chain(&mut self) -> Result<DescriptorChain> {
+        loop {
+            if let Some(chain) = self.pop()? {
+                return Ok(chain);
+            }
+            self.kick
+                .read(&self.kick_thread)
+                .await
+                .map_err(Error::Kick)?;
+        }
+    }
+
+    fn read_descriptor(&self, table: u64, index: u16) -> Result<(Descriptor, u16)> {
+        let addr = table + index as u64 * DESC_SIZE;
+        let descriptor = Descriptor {
+            addr: self.mem.read_u64(addr)?,
+            len: self.mem.read_u32(addr + 8)?,
+            flags: self.mem.read_u16(addr + 12)?,
+        };
+        let next = self.mem.read_u16(addr + 14)?;
+        Ok((descriptor, next))
+    }
+
+    fn read_chain(&self, head: u16) -> Result<DescriptorChain> {
+        let mut descriptors = Vec::new();
+        let mut table = self.layout.desc_table;
+        let mut table_len = self.layout.size;
+        let mut index = head;
+        let mut indirect = false;
+        loop {
+            if index >= table_len {
+                return Err(Error::InvalidIndex(index));
+            }
+            // Every descriptor of a valid chain is visited once.
+            if descriptors.len() >= table_len as usize {
+                return Err(Error::ChainTooLong);
+            }
+            let (descriptor, next) = self.read_descriptor(table, index)?;
+            if descriptor.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if indirect || descriptor.flags & VIRTQ_DESC_F_NEXT != 0 || !descriptors.is_empty()
+                {
+                    return Err(Error::InvalidIndirectFlags);
+                }
+                if descriptor.len == 0 || !(descriptor.len as u64).is_multiple_of(DESC_SIZE) {
+                    return Err(Error::InvalidIndirectTable(descriptor.len));
+                }
+                let entries = descriptor.len as u64 / DESC_SIZE;
+                if entries > u16::MAX as u64 {
+                    return Err(Error::InvalidIndirectTable(descriptor.len));
+                }
+                table = descriptor.addr;
+                table_len = entries as u16;
+                index = 0;
+                indirect = true;
+                continue;
+            }
+            if !descriptor.is_write_only() && descriptors.iter().any(Descriptor::is_write_only) {
+                return Err(Error::ReadableAfterWritable);
+            }
+            descriptors.push(descriptor);
+            if descriptor.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Ok(DescriptorChain { head, descriptors })
+    }
+
+    /// Returns the chain starting at `head` to the driver, with `len` bytes written to it.
+    pub fn add_used(&mut self, head: u16, len: u32) -> Result<()> {
+        let slot = (self.next_used.0 % self.layout.size) as u64;
+        let elem = self.layout.used_ring + 4 + USED_ELEM_SIZE * slot;
+        self.mem.write_u32(elem, head as u32)?;
+        self.mem.write_u32(elem + 4, len)?;
+        self.next_used += Wrapping(1);
+        // The driver must see the element before the index that covers it.
+        fence(Ordering::Release);
+        self.mem
+            .write_u16(self.layout.used_ring + 2, self.next_used.0)?;
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for the chains used since the last time this
+    /// returned true.
+    pub fn needs_interrupt(&mut self) -> Result<bool> {
+        // The used index has to be visible before the driver's request is read.
+        fence(Ordering::SeqCst);
+        let needed = if self.event_idx {
+            let used_event = Wrapping(self.mem.read_u16(self.used_event_addr())?);
+            // True if `used_event` is among the indexes used since the last interrupt.
+            self.next_used - used_event - Wrapping(1) < self.next_used - self.signalled_used
+        } else {
+            let flags = self.mem.read_u16(self.layout.avail_ring)?;
+            flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0
+        };
+        if needed {
+            self.signalled_used = self.next_used;
+        }
+        Ok(needed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use futures::future::join;
+    use tempfile::tempfile;
+
+    use crate::block::{RawRequest, RequestQueue, SgList, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_IN};
+    use crate::blocking::BlockingPool;
+    use crate::disk::{disk_block, SingleFileDisk};
+    use crate::mem::{Backing, GuestMemory, VecMemory};
+
+    const SIZE: u16 = 4;
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const INDIRECT_TABLE: u64 = 0x4000;
+
+    // The driver's side of the queue.
+    struct Driver {
+        mem: Rc<VecMemory>,
+        avail_idx: u16,
+    }
+
+    impl Driver {
+        fn set_desc(&self, table: u64, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+            let desc = table + index as u64 * DESC_SIZE;
+            self.mem.write_u64(desc, addr).unwrap();
+            self.mem.write_u32(desc + 8, len).unwrap();
+            self.mem.write_u16(desc + 12, flags).unwrap();
+            self.mem.write_u16(desc + 14, next).unwrap();
+        }
+
+        fn make_available(&mut self, head: u16) {
+            let slot = (self.avail_idx % SIZE) as u64;
+            self.mem.write_u16(AVAIL_RING + 4 + 2 * slot, head).unwrap();
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.mem.write_u16(AVAIL_RING + 2, self.avail_idx).unwrap();
+        }
+
+        fn used(&self, index: u16) -> (u32, u32) {
+            let elem = USED_RING + 4 + USED_ELEM_SIZE * (index % SIZE) as u64;
+            (
+                self.mem.read_u32(elem).unwrap(),
+                self.mem.read_u32(elem + 4).unwrap(),
+            )
+        }
+
+        fn used_idx(&self) -> u16 {
+            self.mem.read_u16(USED_RING + 2).unwrap()
+        }
+    }
+
+    fn setup() -> (Queue, Driver, EventFd) {
+        let mem = Rc::new(VecMemory::new(0x8000));
+        let kick = EventFd::new().unwrap();
+        let driver_kick = kick.try_clone().unwrap();
+        let layout = QueueLayout {
+            size: SIZE,
+            desc_table: DESC_TABLE,
+            avail_ring: AVAIL_RING,
+            used_ring: USED_RING,
+        };
+        let queue = Queue::new(mem.clone(), layout, kick).unwrap();
+        (queue, Driver { mem, avail_idx: 0 }, driver_kick)
+    }
+
+    #[test]
+    fn pop_and_use() {
+        let (mut queue, mut driver, _) = setup();
+        assert!(queue.pop().unwrap().is_none());
+        driver.set_desc(DESC_TABLE, 2, 0x5000, 16, VIRTQ_DESC_F_NEXT, 0);
+        driver.set_desc(DESC_TABLE, 0, 0x6000, 512, VIRTQ_DESC_F_WRITE, 0);
+        driver.make_available(2);
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!(chain.head_index(), 2);
+        assert_eq!(chain.descriptors().len(), 2);
+        assert_eq!(chain.readable().next().unwrap().addr, 0x5000);
+        let writable: Vec<_> = chain.writable().collect();
+        assert_eq!(writable.len(), 1);
+        assert_eq!(writable[0].len, 512);
+        assert!(queue.pop().unwrap().is_none());
+
+        queue.add_used(2, 512).unwrap();
+        assert_eq!(driver.used_idx(), 1);
+        assert_eq!(driver.used(0), (2, 512));
+        assert!(queue.needs_interrupt().unwrap());
+        driver
+            .mem
+            .write_u16(AVAIL_RING, VIRTQ_AVAIL_F_NO_INTERRUPT)
+            .unwrap();
+        queue.add_used(2, 0).unwrap();
+        assert!(!queue.needs_interrupt().unwrap());
+    }
+
+    #[test]
+    fn ring_wraps() {
+        let (mut queue, mut driver, _) = setup();
+        driver.set_desc(DESC_TABLE, 1, 0x5000, 8, 0, 0);
+        for i in 0..3 * SIZE {
+            driver.make_available(1);
+            let chain = queue.pop().unwrap().unwrap();
+            assert_eq!(chain.head_index(), 1);
+            queue.add_used(1, i as u32).unwrap();
+            assert_eq!(driver.used(i), (1, i as u32));
+        }
+        assert_eq!(driver.used_idx(), 3 * SIZE);
+    }
+
+    #[test]
+    fn indirect() {
+        let (mut queue, mut driver, _) = setup();
+        driver.set_desc(INDIRECT_TABLE, 0, 0x5000, 16, VIRTQ_DESC_F_NEXT, 2);
+        driver.set_desc(INDIRECT_TABLE, 2, 0x6000, 4, VIRTQ_DESC_F_NEXT, 1);
+        driver.set_desc(INDIRECT_TABLE, 1, 0x7000, 1, VIRTQ_DESC_F_WRITE, 0);
+        driver.set_desc(DESC_TABLE, 3, INDIRECT_TABLE, 48, VIRTQ_DESC_F_INDIRECT, 0);
+        driver.make_available(3);
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!(chain.head_index(), 3);
+        let addrs: Vec<u64> = chain.descriptors().iter().map(|d| d.addr).collect();
+        assert_eq!(addrs, vec![0x5000, 0x6000, 0x7000]);
+    }
+
+    // Pops a chain that fails to parse, returns its head to the driver and gives the reason.
+    fn pop_bad(queue: &mut Queue) -> Error {
+        match queue.pop() {
+            Err(Error::BadChain(head, e)) => {
+                queue.add_used(head, 0).unwrap();
+                *e
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn bad_chains() {
+        let (mut queue, mut driver, _) = setup();
+        // Loops back on itself.
+        driver.set_desc(DESC_TABLE, 0, 0x5000, 8, VIRTQ_DESC_F_NEXT, 1);
+        driver.set_desc(DESC_TABLE, 1, 0x5000, 8, VIRTQ_DESC_F_NEXT, 0);
+        driver.make_available(0);
+        assert!(matches!(pop_bad(&mut queue), Error::ChainTooLong));
+
+        driver.set_desc(DESC_TABLE, 0, 0x5000, 8, VIRTQ_DESC_F_NEXT, 9);
+        driver.make_available(0);
+        assert!(matches!(pop_bad(&mut queue), Error::InvalidIndex(9)));
+
+        driver.set_desc(
+            DESC_TABLE,
+            0,
+            0x5000,
+            8,
+            VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        driver.set_desc(DESC_TABLE, 1, 0x5000, 8, 0, 0);
+        driver.make_available(0);
+        assert!(matches!(pop_bad(&mut queue), Error::ReadableAfterWritable));
+
+        driver.set_desc(DESC_TABLE, 0, INDIRECT_TABLE, 20, VIRTQ_DESC_F_INDIRECT, 0);
+        driver.make_available(0);
+        assert!(matches!(
+            pop_bad(&mut queue),
+            Error::InvalidIndirectTable(20)
+        ));
+
+        driver.set_desc(
+            INDIRECT_TABLE,
+            0,
+            INDIRECT_TABLE,
+            16,
+            VIRTQ_DESC_F_INDIRECT,
+            0,
+        );
+        driver.set_desc(DESC_TABLE, 0, INDIRECT_TABLE, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        driver.make_available(0);
+        assert!(matches!(pop_bad(&mut queue), Error::InvalidIndirectFlags));
+
+        // Each bad chain was handed back without data.
+        assert_eq!(driver.used_idx(), 5);
+        assert!((0..5).all(|i| driver.used(i) == (0, 0)));
+    }
+
+    #[test]
+    fn bad_layouts() {
+        let mem: Rc<dyn Memory> = Rc::new(VecMemory::new(0x4000));
+        let layout = QueueLayout {
+            size: SIZE,
+            desc_table: DESC_TABLE,
+            avail_ring: AVAIL_RING,
+            used_ring: USED_RING,
+        };
+        let new = |layout| Queue::new(mem.clone(), layout, EventFd::new().unwrap());
+        assert!(new(layout).is_ok());
+        assert!(matches!(
+            new(QueueLayout { size: 3, ..layout }),
+            Err(Error::InvalidSize(3))
+        ));
+        assert!(matches!(
+            new(QueueLayout {
+                used_ring: USED_RING + 2,
+                ..layout
+            }),
+            Err(Error::Misaligned(_))
+        ));
+        assert!(matches!(
+            new(QueueLayout {
+                used_ring: 0x3ff0,
+                ..layout
+            }),
+            Err(Error::Memory(_))
+        ));
+        // Past the end of the address space.
+        assert!(matches!(
+            new(QueueLayout {
+                desc_table: u64::MAX - 15,
+                ..layout
+            }),
+            Err(Error::Memory(mem::Error::OutOfRange { .. }))
+        ));
+    }
+
+    #[test]
+    fn event_idx() {
+        let (mut queue, mut driver, _) = setup();
+        queue.set_event_idx(true);
+        // An empty queue asks to be kicked for the next chain.
+        assert!(queue.pop().unwrap().is_none());
+        let avail_event = USED_RING + 4 + USED_ELEM_SIZE * SIZE as u64;
+        assert_eq!(driver.mem.read_u16(avail_event).unwrap(), 0);
+
+        driver.set_desc(DESC_TABLE, 0, 0x5000, 8, 0, 0);
+        let used_event = AVAIL_RING + 4 + 2 * SIZE as u64;
+        // Only interrupt once the second chain is used.
+        driver.mem.write_u16(used_event, 1).unwrap();
+        for _ in 0..2 {
+            driver.make_available(0);
+            queue.pop().unwrap().unwrap();
+        }
+        assert!(queue.pop().unwrap().is_none());
+        assert_eq!(driver.mem.read_u16(avail_event).unwrap(), 2);
+        queue.add_used(0, 0).unwrap();
+        assert!(!queue.needs_interrupt().unwrap());
+        queue.add_used(0, 0).unwrap();
+        assert!(queue.needs_interrupt().unwrap());
+        // Nothing new was used.
+        assert!(!queue.needs_interrupt().unwrap());
+    }
+
+    #[test]
+    fn waits_for_kick() {
+        let (mut queue, mut driver, kick) = setup();
+        driver.set_desc(DESC_TABLE, 0, 0x5000, 8, 0, 0);
+        let device = async {
+            let chain = queue.next_descriptor_chain().await.unwrap();
+            assert_eq!(chain.head_index(), 0);
+        };
+        let driver_side = async {
+            driver.make_available(0);
+            kick.write(1).unwrap();
+        };
+        block_on(join(device, driver_side));
+    }
+
+    #[test]
+    fn block_request() {
+        // The driver reads sector 0 of a disk: a header, a data buffer and the status byte. Guest
+        // memory is in a memfd, as it would be if shared with another process.
+        let sector: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        let mut file = tempfile().unwrap();
+        file.write_all(&sector).unwrap();
+        let disk = Rc::new(SingleFileDisk::new(file, Arc::new(BlockingPool::new(1))));
+        let mem = Rc::new(GuestMemory::new(&[(0, 0x8000)], Backing::Memfd).unwrap());
+        let kick = EventFd::new().unwrap();
+        let driver_kick = kick.try_clone().unwrap();
+        let layout = QueueLayout {
+            size: SIZE,
+            desc_table: DESC_TABLE,
+            avail_ring: AVAIL_RING,
+            used_ring: USED_RING,
+        };
+        let mut queue = Queue::new(mem.clone(), layout, kick).unwrap();
+        queue.set_event_idx(true);
+        mem.write_obj_at_addr(VIRTIO_BLK_T_IN, 0x4000).unwrap();
+        mem.write_obj_at_addr(0u64, 0x4008).unwrap();
+        for (index, (addr, len, flags)) in [
+            (0x4000, 16, VIRTQ_DESC_F_NEXT),
+            (0x5000, 512, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT),
+            (0x6000, 1, VIRTQ_DESC_F_WRITE),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let desc = DESC_TABLE + DESC_SIZE * index as u64;
+            mem.write_u64(desc, *addr).unwrap();
+            mem.write_u32(desc + 8, *len).unwrap();
+            mem.write_u16(desc + 12, *flags).unwrap();
+            mem.write_u16(desc + 14, index as u16 + 1).unwrap();
+        }
+        mem.write_u16(AVAIL_RING + 4, 0).unwrap();
+        mem.write_u16(AVAIL_RING + 2, 1).unwrap();
+        driver_kick.write(1).unwrap();
+
+        let chain = block_on(queue.next_descriptor_chain()).unwrap();
+        assert_eq!(chain.descriptors().len(), 3);
+        let mut readable = chain.readable();
+        let header = readable.next().unwrap();
+        let read_buf = |addr: u64, len: u32| {
+            let mut buf = vec![0u8; len as usize];
+            mem.read_at_addr(addr, &mut buf).unwrap();
+            buf
+        };
+        let out: SgList = readable.map(|d| read_buf(d.addr, d.len)).collect();
+        let writable: Vec<_> = chain.writable().collect();
+        let (status_desc, data_descs) = writable.split_last().unwrap();
+        let requests = Rc::new(RequestQueue::default());
+        requests.submit(RawRequest {
+            req_type: mem.read_obj_from_addr(header.addr).unwrap(),
+            sector: mem.read_obj_from_addr(header.addr + 8).unwrap(),
+            out,
+            in_lens: data_descs.iter().map(|d| d.len as usize).collect(),
+        });
+        block_on(disk_block(disk, requests.clone()).process_queue());
+        let response = requests.take_used().pop().unwrap();
+        for (desc, data) in data_descs.iter().zip(&response.data) {
+            mem.write_at_addr(desc.addr, data).unwrap();
+        }
+        // The status goes in the last byte of the last buffer.
+        let status = mem
+            .get_slice_at_addr(status_desc.addr, status_desc.len as usize)
+            .unwrap();
+        status
+            .sub_slice(status.size() - 1, 1)
+            .unwrap()
+            .copy_from(&[response.status]);
+        queue
+            .add_used(chain.head_index(), response.used_len() as u32)
+            .unwrap();
+        assert!(queue.needs_interrupt().unwrap());
+        assert!(queue.pop().unwrap().is_none());
+
+        assert_eq!(read_buf(0x5000, 512), sector);
+        let mut status = [0u8];
+        mem.memfd()
+            .unwrap()
+            .read_exact_at(&mut status, 0x6000)
+            .unwrap();
+        assert_eq!(status, [VIRTIO_BLK_S_OK]);
+        // The used ring holds the head and the 513 bytes written.
+        assert_eq!(mem.read_u16(USED_RING + 2).unwrap(), 1);
+        assert_eq!(mem.read_u32(USED_RING + 8).unwrap(), 513);
+    }
+}
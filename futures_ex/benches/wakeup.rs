@@ -0,0 +1,155 @@
+// Measures the cost of waking one task while thousands of other tasks sit idle. Each wakeup goes
+// through a pipe and `wait_wake_readable`, so the time per wakeup should stay flat as the number of
+// idle tasks grows.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures_ex::{FdExecutor, FdExecutorInterface, InterfaceState};
+
+const WAKEUPS: usize = 10000;
+const IDLE_COUNTS: &[usize] = &[0, 10, 100, 1000, 10000];
+
+struct PipeFd(RawFd);
+
+impl AsRawFd for PipeFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for PipeFd {
+    fn drop(&mut self) {
+        // Safe because this struct owns the fd.
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+fn pipe() -> (PipeFd, PipeFd) {
+    let mut fds = [0; 2];
+    // Safe because `fds` has room for the two fds pipe fills in.
+    let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    assert_eq!(ret, 0);
+    (PipeFd(fds[0]), PipeFd(fds[1]))
+}
+
+// Parks until `done` is set, then completes.
+struct IdleTask {
+    done: Rc<Cell<bool>>,
+    wakers: Rc<RefCell<Vec<Waker>>>,
+}
+
+impl Future for IdleTask {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.done.get() {
+            return Poll::Ready(());
+        }
+        self.wakers.borrow_mut().push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// Writes a byte to a pipe and waits for it to become readable `WAKEUPS` times, then releases the
+// idle tasks.
+struct PingTask {
+    read_fd: PipeFd,
+    write_fd: PipeFd,
+    remaining: usize,
+    waiting: bool,
+    start: Option<Instant>,
+    elapsed: Rc<Cell<Duration>>,
+    state: Arc<RefCell<InterfaceState>>,
+    done: Rc<Cell<bool>>,
+    idle_wakers: Rc<RefCell<Vec<Waker>>>,
+}
+
+impl Future for PingTask {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut b = [0u8; 1];
+        if self.waiting {
+            // Safe because `b` is a valid one byte buffer.
+            let ret = unsafe { libc::read(self.read_fd.0, b.as_mut_ptr() as *mut _, 1) };
+            assert_eq!(ret, 1);
+            self.waiting = false;
+            self.remaining -= 1;
+            // Start timing after the first wakeup so the initial poll of every idle task isn't
+            // counted.
+            if self.start.is_none() {
+                self.start = Some(Instant::now());
+            }
+        }
+
+        if self.remaining == 0 {
+            let elapsed = self.start.unwrap().elapsed();
+            self.elapsed.set(elapsed);
+            self.done.set(true);
+            for waker in self.idle_wakers.borrow_mut().drain(..) {
+                waker.wake();
+            }
+            return Poll::Ready(());
+        }
+
+        // Safe because `b` is a valid one byte buffer.
+        let ret = unsafe { libc::write(self.write_fd.0, b.as_ptr() as *const _, 1) };
+        assert_eq!(ret, 1);
+        self.waiting = true;
+        self.state
+            .borrow_mut()
+            .add_waker(&self.read_fd, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+fn run_bench(idle_count: usize) -> Duration {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let done = Rc::new(Cell::new(false));
+    let idle_wakers = Rc::new(RefCell::new(Vec::new()));
+    let elapsed = Rc::new(Cell::new(Duration::default()));
+    let (read_fd, write_fd) = pipe();
+
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    futures.push(Box::pin(PingTask {
+        read_fd,
+        write_fd,
+        // One extra round for the untimed first wakeup.
+        remaining: WAKEUPS + 1,
+        waiting: false,
+        start: None,
+        elapsed: elapsed.clone(),
+        state: state.clone(),
+        done: done.clone(),
+        idle_wakers: idle_wakers.clone(),
+    }));
+    for _ in 0..idle_count {
+        futures.push(Box::pin(IdleTask {
+            done: done.clone(),
+            wakers: idle_wakers.clone(),
+        }));
+    }
+
+    FdExecutor::new(futures, state).run();
+    elapsed.get()
+}
+
+fn main() {
+    for &idle_count in IDLE_COUNTS {
+        let elapsed = run_bench(idle_count);
+        println!(
+            "{:>6} idle tasks: {:>8} ns per wakeup",
+            idle_count,
+            elapsed.as_nanos() / WAKEUPS as u128
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Reads and writes on a non-blocking fd that wait for readiness through the reactor.
+
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::executor::SavedFd;
+use crate::reactor::{self, Registration};
+
+pub(crate) fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // Safe because F_GETFL and F_SETFL don't touch memory and errors are checked.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// An fd owned and set to non-blocking mode. Reads and writes wait for the fd to become ready
+/// instead of blocking the executor, so they must be polled from a task on an `FdExecutor`.
+pub struct AsyncFd {
+    fd: RawFd,
+}
+
+impl AsyncFd {
+    /// Takes ownership of `fd` and makes it non-blocking.
+    pub fn new<F: IntoRawFd>(fd: F) -> io::Result<AsyncFd> {
+        let fd = fd.into_raw_fd();
+        let async_fd = AsyncFd { fd };
+        set_nonblocking(fd)?;
+        Ok(async_fd)
+    }
+
+    /// Reads into `buf`, resolving to the number of bytes read. Zero means end of file.
+    pub fn read<'a>(&'a self, buf: &'a mut [u8]) -> ReadFd<'a> {
+        ReadFd {
+            fd: self.fd,
+            buf,
+            registration: Registration::default(),
+        }
+    }
+
+    /// Writes from `buf`, resolving to the number of bytes written.
+    pub fn write<'a>(&'a self, buf: &'a [u8]) -> WriteFd<'a> {
+        WriteFd {
+            fd: self.fd,
+            buf,
+            registration: Registration::default(),
+        }
+    }
+
+    /// Reads until end of file, appending to `buf`. Returns the number of bytes read.
+    pub async fn read_to_end(&self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let mut total = 0;
+        loop {
+            let count = self.read(&mut chunk).await?;
+            if count == 0 {
+                return Ok(total);
+            }
+            buf.extend_from_slice(&chunk[..count]);
+            total += count;
+        }
+    }
+
+    /// Writes all of `buf`.
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let count = self.write(buf).await?;
+            if count == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            buf = &buf[count..];
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for AsyncFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for AsyncFd {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for AsyncFd {
+    fn drop(&mut self) {
+        // Safe because this struct owns the fd.
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+// Runs `op` until it stops failing with EINTR, registering for readiness when it would block.
+fn poll_io(
+    fd: RawFd,
+    write: bool,
+    registration: &mut Registration,
+    cx: &mut Context,
+    mut op: impl FnMut() -> isize,
+) -> Poll<io::Result<usize>> {
+    loop {
+        let ret = op();
+        if ret >= 0 {
+            registration.clear();
+            return Poll::Ready(Ok(ret as usize));
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => continue,
+            io::ErrorKind::WouldBlock => {
+                return match registration.register(&SavedFd(fd), write, cx.waker()) {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(e.into())),
+                };
+            }
+            _ => {
+                registration.clear();
+                return Poll::Ready(Err(err));
+            }
+        }
+    }
+}
+
+/// Future returned by `AsyncFd::read`.
+pub struct ReadFd<'a> {
+    fd: RawFd,
+    buf: &'a mut [u8],
+    registration: Registration,
+}
+
+impl<'a> Future for ReadFd<'a> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let fd = this.fd;
+        let buf = &mut *this.buf;
+        poll_io(fd, false, &mut this.registration, cx, || {
+            // Safe because the kernel writes at most `buf.len()` bytes to `buf`.
+            unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) }
+        })
+    }
+}
+
+/// Future returned by `AsyncFd::write`.
+pub struct WriteFd<'a> {
+    fd: RawFd,
+    buf: &'a [u8],
+    registration: Registration,
+}
+
+impl<'a> Future for WriteFd<'a> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let fd = this.fd;
+        let buf = this.buf;
+        poll_io(fd, true, &mut this.registration, cx, || {
+            // Safe because the kernel reads at most `buf.len()` bytes from `buf`.
+            unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, buf.len()) }
+        })
+    }
+}
+
+/// Runs `op` until it stops failing with `WouldBlock` or `Interrupted`, waiting for `fd` to become
+/// readable, or writable if `write` is set, whenever it would block.
+pub(crate) async fn retry_io<T>(
+    fd: &dyn AsRawFd,
+    write: bool,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if write {
+                    reactor::wait_writable(fd).await?;
+                } else {
+                    reactor::wait_readable(fd).await?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
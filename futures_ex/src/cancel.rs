@@ -0,0 +1,87 @@
+//! Cooperative cancellation for tasks running on the `FdExecutor`.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use crate::sync::WaitQueue;
+
+struct CancelState {
+    cancelled: bool,
+    waiters: WaitQueue,
+}
+
+/// Lets one task tell others to stop. Clones share the same state, cancelling any of them cancels
+/// them all.
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Rc<RefCell<CancelState>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            state: Rc::new(RefCell::new(CancelState {
+                cancelled: false,
+                waiters: WaitQueue::new(),
+            })),
+        }
+    }
+
+    /// Marks the token cancelled and wakes every task waiting in `cancelled`.
+    pub fn cancel(&self) {
+        let mut state = self.state.borrow_mut();
+        state.cancelled = true;
+        state.waiters.wake_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.borrow().cancelled
+    }
+
+    /// Returns a future that completes once `cancel` has been called.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled {
+            state: self.state.clone(),
+            id: None,
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `CancellationToken::cancelled`.
+pub struct Cancelled {
+    state: Rc<RefCell<CancelState>>,
+    // Our place in the token's waiters, taken out again if the future is dropped first.
+    id: Option<u64>,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let state = self.state.clone();
+        let mut state = state.borrow_mut();
+        if state.cancelled {
+            self.id = None;
+            return Poll::Ready(());
+        }
+        self.id = Some(state.waiters.register(self.id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.state.borrow_mut().waiters.remove(id);
+        }
+    }
+}
@@ -0,0 +1,768 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::mem::{self, ManuallyDrop};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::task::{RawWaker, RawWakerVTable, Waker};
+use std::time::Instant;
+
+use sys_util::{EventFd, PollContext, WatchingEvents};
+
+use crate::reactor;
+use crate::stats::{Dump, ExecutorStats, TaskDump, TaskStats, TaskWait};
+use crate::task::{self, TaskContext, TaskPanic};
+use crate::uring::UringContext;
+
+// Tokens for the eventfds owned by the executor. Registered fds use the fd number as their token.
+const URING_TOKEN: u64 = u64::MAX;
+const WAKE_TOKEN: u64 = u64::MAX - 1;
+
+/// Indices of the tasks that have been woken and need to be polled. Wakers push to it, `run`
+/// drains it, so the cost of a wakeup doesn't depend on how many tasks are idle.
+pub(crate) struct ReadyQueue {
+    queue: Mutex<VecDeque<usize>>,
+}
+
+impl ReadyQueue {
+    pub(crate) fn new() -> ReadyQueue {
+        ReadyQueue {
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn push(&self, index: usize) {
+        self.queue.lock().unwrap().push_back(index);
+    }
+
+    pub(crate) fn take(&self) -> VecDeque<usize> {
+        mem::take(&mut *self.queue.lock().unwrap())
+    }
+}
+
+/// The data behind each task's `Waker`. Clones of a waker share one `TaskWaker`.
+pub(crate) struct TaskWaker {
+    pub(crate) index: usize,
+    // Set while the task sits in the ready queue so repeated wakes only queue it once.
+    pub(crate) queued: AtomicBool,
+    // Set when the task completes. Wakers can outlive their task and the slot might be reused.
+    pub(crate) done: AtomicBool,
+    pub(crate) ready: Arc<ReadyQueue>,
+}
+
+impl TaskWaker {
+    pub(crate) fn wake(&self) {
+        if self.done.load(Ordering::Acquire) {
+            return;
+        }
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.ready.push(self.index);
+        }
+    }
+}
+
+unsafe fn waker_drop(data_ptr: *const ()) {
+    drop(Arc::from_raw(data_ptr as *const TaskWaker));
+}
+unsafe fn waker_wake(data_ptr: *const ()) {
+    let task_waker = Arc::from_raw(data_ptr as *const TaskWaker);
+    task_waker.wake();
+}
+unsafe fn waker_wake_by_ref(data_ptr: *const ()) {
+    let task_waker = &*(data_ptr as *const TaskWaker);
+    task_waker.wake();
+}
+unsafe fn waker_clone(data_ptr: *const ()) -> RawWaker {
+    let task_waker = ManuallyDrop::new(Arc::from_raw(data_ptr as *const TaskWaker));
+    create_waker(Arc::into_raw(Arc::clone(&task_waker)) as *const ())
+}
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
+
+unsafe fn create_waker(data_ptr: *const ()) -> RawWaker {
+    RawWaker::new(data_ptr, &WAKER_VTABLE)
+}
+
+pub(crate) fn new_waker(task_waker: &Arc<TaskWaker>) -> Waker {
+    let data_ptr = Arc::into_raw(Arc::clone(task_waker)) as *const ();
+    // Safe because the pointer came from `Arc::into_raw` and the vtable functions treat it as
+    // an `Arc<TaskWaker>`.
+    unsafe { Waker::from_raw(create_waker(data_ptr)) }
+}
+
+// Saved FD exists becaus RawFd doesn't impl AsRawFd.
+pub(crate) struct SavedFd(pub(crate) RawFd);
+
+impl AsRawFd for SavedFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Identifies a waker added with `add_waker` or `add_write_waker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WakerToken(pub(crate) u64);
+
+pub trait FdExecutorInterface {
+    /// Tells the waking system to wake `waker` when `fd` becomes readable.
+    fn add_waker(&mut self, fd: &dyn AsRawFd, waker: Waker) -> WakerToken;
+    /// Tells the waking system to wake `waker` when `fd` becomes writable.
+    fn add_write_waker(&mut self, fd: &dyn AsRawFd, waker: Waker) -> WakerToken;
+    /// Forgets a waker that hasn't fired yet. Every call to `add_waker` or `add_write_waker`
+    /// returns a new token, so futures call this with their old token when they register again,
+    /// and when they are dropped while waiting so the fd isn't left registered.
+    fn remove_waker(&mut self, token: WakerToken);
+    /// Adds a new top level future to the Executor.
+    fn add_future(&mut self, future: Pin<Box<dyn Future<Output = ()>>>);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Interest {
+    Read,
+    Write,
+}
+
+struct FdWaker {
+    token: WakerToken,
+    interest: Interest,
+    waker: Waker,
+}
+
+// All the wakers waiting on one fd. The fd is added to the poll context once, watching for
+// everything its wakers are interested in.
+struct FdRegistration {
+    fd: SavedFd,
+    wakers: Vec<FdWaker>,
+    // What the poll context is currently watching for, empty if the fd hasn't been added.
+    events: WatchingEvents,
+}
+
+impl FdRegistration {
+    fn wanted_events(&self) -> WatchingEvents {
+        self.wakers
+            .iter()
+            .fold(WatchingEvents::empty(), |events, w| match w.interest {
+                Interest::Read => events.set_read(),
+                Interest::Write => events.set_write(),
+            })
+    }
+}
+
+/// Handles tracking the state of any futures blocked on FDs and allows adding a wake up request
+/// from the poll funciton of a future.
+pub struct InterfaceState {
+    poll_ctx: PollContext<u64>,
+    registrations: HashMap<RawFd, FdRegistration>,
+    waker_fds: HashMap<WakerToken, RawFd>,
+    next_token: u64,
+    new_futures: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+    uring: Option<UringContext>,
+    // Written to interrupt `wait_wake_readable`, for example from another thread.
+    wake_event: EventFd,
+}
+
+/// Used by futures who want to block until an FD becomes readable or writable.
+/// Keeps a list of FDs and associated wakers that will be woekn with `wake_by_ref` when the FD
+/// becomes ready. Any number of wakers can wait on the same FD.
+impl InterfaceState {
+    /// Create an empty InterfaceState.
+    pub fn new() -> InterfaceState {
+        let poll_ctx = PollContext::new().unwrap();
+        let wake_event = EventFd::new().unwrap();
+        poll_ctx.add(&wake_event, WAKE_TOKEN).unwrap();
+        InterfaceState {
+            poll_ctx,
+            registrations: HashMap::new(),
+            waker_fds: HashMap::new(),
+            next_token: 0,
+            new_futures: Vec::new(),
+            uring: None,
+            wake_event,
+        }
+    }
+
+    /// Create an empty InterfaceState that submits `read_at`, `write_at` and `fsync` through
+    /// io_uring. Falls back to the same behavior as `new` if io_uring isn't available.
+    pub fn with_io_uring() -> InterfaceState {
+        let mut state = InterfaceState::new();
+        if let Ok(uring) = UringContext::new() {
+            state.poll_ctx.add(uring.eventfd(), URING_TOKEN).unwrap();
+            state.uring = Some(uring);
+        }
+        state
+    }
+
+    /// Returns true if file operations are submitted through io_uring.
+    pub fn uses_io_uring(&self) -> bool {
+        self.uring.is_some()
+    }
+
+    /// Returns the number of wakers waiting for an fd to become ready.
+    pub fn waker_count(&self) -> usize {
+        self.waker_fds.len()
+    }
+
+    /// Returns true if the waker for `token` is still waiting, false once its fd has fired or it
+    /// was removed.
+    pub fn is_waker_pending(&self, token: WakerToken) -> bool {
+        self.waker_fds.contains_key(&token)
+    }
+
+    // Lists the fds `waker` is registered for, with true for the ones it waits to write.
+    fn fds_waited_by(&self, waker: &Waker) -> Vec<(RawFd, bool)> {
+        let mut fds: Vec<_> = self
+            .registrations
+            .iter()
+            .flat_map(|(fd, r)| {
+                r.wakers
+                    .iter()
+                    .filter(|w| w.waker.will_wake(waker))
+                    .map(move |w| (*fd, w.interest == Interest::Write))
+            })
+            .collect();
+        fds.sort();
+        fds.dedup();
+        fds
+    }
+
+    pub(crate) fn uring_mut(&mut self) -> Option<&mut UringContext> {
+        self.uring.as_mut()
+    }
+
+    // Returns an eventfd that interrupts `wait_wake_readable` when written.
+    pub(crate) fn wake_event(&self) -> EventFd {
+        self.wake_event.try_clone().unwrap()
+    }
+
+    /// Waits until one of the FDs is ready and wakes the wakers waiting for it.
+    pub fn wait_wake_readable(&mut self) {
+        if let Some(uring) = self.uring.as_mut() {
+            uring.submit().unwrap();
+        }
+        let events = self.poll_ctx.wait().unwrap();
+        let mut fired_fds = Vec::new();
+        for e in events.iter() {
+            if e.token() == URING_TOKEN {
+                if let Some(uring) = self.uring.as_mut() {
+                    uring.reap();
+                }
+                continue;
+            }
+            if e.token() == WAKE_TOKEN {
+                // Only needs clearing, whoever wrote it left something for `run` to look at.
+                let _ = self.wake_event.read();
+                continue;
+            }
+            let raw_fd = e.token() as RawFd;
+            let registration = match self.registrations.get_mut(&raw_fd) {
+                Some(r) => r,
+                None => continue,
+            };
+            // Hangups and errors are reported regardless of interest, wake everyone so they can
+            // see the failure.
+            let wake_all = e.hungup() || !(e.readable() || e.writable());
+            let waker_fds = &mut self.waker_fds;
+            registration.wakers.retain(|w| {
+                let fire = wake_all
+                    || match w.interest {
+                        Interest::Read => e.readable(),
+                        Interest::Write => e.writable(),
+                    };
+                if fire {
+                    waker_fds.remove(&w.token);
+                    w.waker.wake_by_ref();
+                }
+                !fire
+            });
+            fired_fds.push(raw_fd);
+        }
+        for raw_fd in fired_fds {
+            self.update_registration(raw_fd);
+        }
+    }
+
+    fn add_fd_waker(&mut self, fd: &dyn AsRawFd, interest: Interest, waker: Waker) -> WakerToken {
+        let raw_fd = fd.as_raw_fd();
+        let registration = self
+            .registrations
+            .entry(raw_fd)
+            .or_insert_with(|| FdRegistration {
+                fd: SavedFd(raw_fd),
+                wakers: Vec::new(),
+                events: WatchingEvents::empty(),
+            });
+        // Futures in the same task share a waker, so each registration gets its own token even
+        // if it looks like another one.
+        let token = WakerToken(self.next_token);
+        self.next_token += 1;
+        registration.wakers.push(FdWaker {
+            token,
+            interest,
+            waker,
+        });
+        self.waker_fds.insert(token, raw_fd);
+        self.update_registration(raw_fd);
+        token
+    }
+
+    // Registers `waker` for `fd` in place of the waker registered as `old`. Every registration
+    // gets a new token, so a future polled again must give back the one it had. The new waker is
+    // added first so the fd isn't taken out of the poll context in between.
+    pub(crate) fn replace_waker(
+        &mut self,
+        old: Option<WakerToken>,
+        fd: &dyn AsRawFd,
+        write: bool,
+        waker: Waker,
+    ) -> WakerToken {
+        let interest = if write {
+            Interest::Write
+        } else {
+            Interest::Read
+        };
+        let token = self.add_fd_waker(fd, interest, waker);
+        if let Some(old) = old {
+            self.remove_waker(old);
+        }
+        token
+    }
+
+    // Makes the poll context watch for what the wakers of `raw_fd` still want, removing the fd if
+    // nothing is waiting on it.
+    fn update_registration(&mut self, raw_fd: RawFd) {
+        let registration = match self.registrations.get_mut(&raw_fd) {
+            Some(r) => r,
+            None => return,
+        };
+
+        if registration.wakers.is_empty() {
+            // Closing an fd removes it from the epoll set, so this can fail if the fd was closed
+            // while futures were waiting on it.
+            let _ = self.poll_ctx.delete(&registration.fd);
+            self.registrations.remove(&raw_fd);
+            return;
+        }
+
+        let events = registration.wanted_events();
+        if events.get_raw() == registration.events.get_raw() {
+            return;
+        }
+        let token = raw_fd as u64;
+        let poll_ctx = &self.poll_ctx;
+        let result = if registration.events.get_raw() == 0 {
+            poll_ctx
+                .add_fd_with_events(&registration.fd, events, token)
+                .or_else(|e| match e.errno() {
+                    libc::EEXIST => poll_ctx.modify(&registration.fd, events, token),
+                    _ => Err(e),
+                })
+        } else {
+            // The fd number might have been closed and reused since it was added.
+            poll_ctx
+                .modify(&registration.fd, events, token)
+                .or_else(|e| match e.errno() {
+                    libc::ENOENT => poll_ctx.add_fd_with_events(&registration.fd, events, token),
+                    _ => Err(e),
+                })
+        };
+        result.unwrap();
+        registration.events = events;
+    }
+}
+
+impl Default for InterfaceState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FdExecutorInterface for InterfaceState {
+    /// Tells the waking system to wake `waker` when `fd` becomes readable.
+    fn add_waker(&mut self, fd: &dyn AsRawFd, waker: Waker) -> WakerToken {
+        self.add_fd_waker(fd, Interest::Read, waker)
+    }
+
+    /// Tells the waking system to wake `waker` when `fd` becomes writable.
+    fn add_write_waker(&mut self, fd: &dyn AsRawFd, waker: Waker) -> WakerToken {
+        self.add_fd_waker(fd, Interest::Write, waker)
+    }
+
+    fn remove_waker(&mut self, token: WakerToken) {
+        let raw_fd = match self.waker_fds.remove(&token) {
+            Some(fd) => fd,
+            None => return,
+        };
+        if let Some(registration) = self.registrations.get_mut(&raw_fd) {
+            registration.wakers.retain(|w| w.token != token);
+        }
+        self.update_registration(raw_fd);
+    }
+
+    fn add_future(&mut self, future: Pin<Box<dyn Future<Output = ()>>>) {
+        self.new_futures.push(future);
+    }
+}
+
+pub(crate) struct Task {
+    pub(crate) future: Pin<Box<dyn Future<Output = ()>>>,
+    pub(crate) waker: Arc<TaskWaker>,
+    pub(crate) stats: TaskStats,
+    pub(crate) context: TaskContext,
+}
+
+impl Task {
+    // The stats are kept apart from the name, which the task can change while it runs.
+    pub(crate) fn named_stats(&self) -> TaskStats {
+        TaskStats {
+            name: self.context.name.clone(),
+            ..self.stats.clone()
+        }
+    }
+}
+
+/// Task storage indexed by the slot number handed to each task's waker. Freed slots are reused.
+pub(crate) struct TaskSlab {
+    slots: Vec<Option<Task>>,
+    free_slots: Vec<usize>,
+    len: usize,
+}
+
+impl TaskSlab {
+    pub(crate) fn new() -> TaskSlab {
+        TaskSlab {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn next_index(&self) -> usize {
+        self.free_slots.last().cloned().unwrap_or(self.slots.len())
+    }
+
+    pub(crate) fn insert(&mut self, task: Task) -> usize {
+        self.len += 1;
+        match self.free_slots.pop() {
+            Some(index) => {
+                self.slots[index] = Some(task);
+                index
+            }
+            None => {
+                self.slots.push(Some(task));
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, index: usize) -> Option<&mut Task> {
+        self.slots.get_mut(index).and_then(|slot| slot.as_mut())
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) -> Option<Task> {
+        let task = self.slots.get_mut(index).and_then(|slot| slot.take());
+        if task.is_some() {
+            self.len -= 1;
+            self.free_slots.push(index);
+        }
+        task
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Task> {
+        self.slots.iter().flatten()
+    }
+
+    pub(crate) fn take_all(&mut self) -> Vec<Task> {
+        self.free_slots.clear();
+        self.len = 0;
+        self.slots.drain(..).flatten().collect()
+    }
+}
+
+// Sets its flag when dropped, marking the end of a `run_until` future however it ended.
+struct DoneGuard(Rc<Cell<bool>>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        self.0.set(true);
+    }
+}
+
+struct ShutdownState {
+    requested: AtomicBool,
+    wake_event: EventFd,
+}
+
+/// Stops an `FdExecutor`. Can be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct ExecutorHandle {
+    shutdown: Arc<ShutdownState>,
+}
+
+impl ExecutorHandle {
+    /// Makes the executor drop every task it hasn't finished and return from `run`. Tasks are
+    /// dropped from the executor's thread the next time it checks for work.
+    pub fn shutdown(&self) {
+        self.shutdown.requested.store(true, Ordering::Release);
+        // The executor might be waiting for an fd, make sure it wakes up to see the request.
+        self.shutdown.wake_event.write(1).unwrap();
+    }
+
+    /// Returns true if `shutdown` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.requested.load(Ordering::Acquire)
+    }
+}
+
+pub struct FdExecutor {
+    tasks: TaskSlab,
+    ready: Arc<ReadyQueue>,
+    state: Arc<RefCell<InterfaceState>>,
+    shutdown: Arc<ShutdownState>,
+    next_task_id: u64,
+    // Collect poll and wait times. Off by default as it reads the clock around every poll.
+    tracing: bool,
+    // Totals for `stats`, the per-task counters live in each task.
+    totals: ExecutorStats,
+    panic_hook: Option<Box<dyn FnMut(TaskPanic)>>,
+}
+
+impl FdExecutor {
+    pub fn new(
+        futures: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+        state: Arc<RefCell<InterfaceState>>,
+    ) -> FdExecutor {
+        let shutdown = Arc::new(ShutdownState {
+            requested: AtomicBool::new(false),
+            wake_event: state.borrow().wake_event(),
+        });
+        let mut ex = FdExecutor {
+            tasks: TaskSlab::new(),
+            ready: Arc::new(ReadyQueue::new()),
+            state,
+            shutdown,
+            next_task_id: 0,
+            tracing: false,
+            totals: ExecutorStats::default(),
+            panic_hook: None,
+        };
+        for future in futures {
+            ex.spawn(future);
+        }
+        ex
+    }
+
+    /// Returns a handle that can shut the executor down.
+    pub fn handle(&self) -> ExecutorHandle {
+        ExecutorHandle {
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    // Stores `future` and queues it so it gets its first poll.
+    fn spawn(&mut self, future: Pin<Box<dyn Future<Output = ()>>>) {
+        let waker = Arc::new(TaskWaker {
+            index: self.tasks.next_index(),
+            queued: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+        waker.wake();
+        let stats = TaskStats::new(self.next_task_id);
+        let context = TaskContext::new(self.next_task_id);
+        self.next_task_id += 1;
+        self.tasks.insert(Task {
+            future,
+            waker,
+            stats,
+            context,
+        });
+    }
+
+    /// Turns on collecting the per-task poll counts and times and the time spent waiting for fds
+    /// reported by `stats` and `dump`.
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.tracing = enabled;
+    }
+
+    /// Calls `hook` with the panic of every task that panics and isn't joined through a
+    /// `task::JoinHandle`. Without a hook such panics are only reported by the thread's panic hook.
+    /// Either way the task is dropped and the executor keeps running the others.
+    pub fn set_panic_hook<H: FnMut(TaskPanic) + 'static>(&mut self, hook: H) {
+        self.panic_hook = Some(Box::new(hook));
+    }
+
+    /// Returns the executor's counters. Polls and times are only counted while tracing is enabled.
+    pub fn stats(&self) -> ExecutorStats {
+        let mut stats = self.totals.clone();
+        stats.tasks = self.tasks.iter().map(Task::named_stats).collect();
+        stats.tasks.sort_by_key(|t| t.id);
+        stats
+    }
+
+    /// Describes every task that hasn't completed: its counters and whether it is queued,
+    /// waiting on fds, or waiting on something the executor can't see. A task that stays in the
+    /// last group while the executor is idle has probably lost its waker.
+    pub fn dump(&self) -> String {
+        let state = self.state.borrow();
+        let mut tasks: Vec<TaskDump> = self
+            .tasks
+            .iter()
+            .map(|task| {
+                let wait = if task.waker.queued.load(Ordering::Acquire) {
+                    TaskWait::Queued
+                } else {
+                    let fds = state.fds_waited_by(&new_waker(&task.waker));
+                    if fds.is_empty() {
+                        TaskWait::Other
+                    } else {
+                        TaskWait::Fds(fds)
+                    }
+                };
+                TaskDump {
+                    stats: task.named_stats(),
+                    wait,
+                }
+            })
+            .collect();
+        tasks.sort_by_key(|t| t.stats.id);
+        Dump {
+            stats: self.stats(),
+            tasks,
+            now: Instant::now(),
+        }
+        .to_string()
+    }
+
+    // Polls the task at `index`, dropping it if it completes or panics.
+    fn poll_task(&mut self, index: usize) {
+        let task = match self.tasks.get_mut(index) {
+            Some(task) => task,
+            None => return,
+        };
+        // Clear before polling so a wake from inside `poll` queues the task again.
+        task.waker.queued.store(false, Ordering::Release);
+        let waker = new_waker(&task.waker);
+        let mut ctx = Context::from_waker(&waker);
+        let start = if self.tracing {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        let poll = {
+            let _task = task::enter(&mut task.context);
+            let future = task.future.as_mut();
+            panic::catch_unwind(AssertUnwindSafe(|| future.poll(&mut ctx)))
+        };
+        if let Some(start) = start {
+            task.stats.record_poll(start, start.elapsed());
+            self.totals.polls += 1;
+        }
+        match poll {
+            Ok(Poll::Pending) => return,
+            Ok(Poll::Ready(())) => self.totals.completed_tasks += 1,
+            Err(payload) => {
+                let panic = TaskPanic {
+                    id: task.context.id,
+                    name: task.context.name.clone(),
+                    payload,
+                };
+                self.totals.panicked_tasks += 1;
+                if let Some(hook) = self.panic_hook.as_mut() {
+                    hook(panic);
+                }
+            }
+        }
+        if let Some(task) = self.tasks.remove(index) {
+            task.waker.done.store(true, Ordering::Release);
+        }
+    }
+
+    /// Runs until every future has completed or the executor is shut down.
+    pub fn run(&mut self) {
+        self.run_while(|| true);
+    }
+
+    /// Runs until `future` completes, returning its output. Other futures that haven't finished
+    /// stay in the executor for a later call to `run` or `run_until`. Returns `None` if the
+    /// executor was shut down first or `future` panicked.
+    pub fn run_until<F: Future + 'static>(&mut self, future: F) -> Option<F::Output> {
+        let output = Rc::new(RefCell::new(None));
+        let done = Rc::new(Cell::new(false));
+        let output_clone = output.clone();
+        let guard = DoneGuard(done.clone());
+        self.spawn(Box::pin(async move {
+            // Set when the wrapper is dropped, whether `future` completed or unwound.
+            let _guard = guard;
+            let value = future.await;
+            *output_clone.borrow_mut() = Some(value);
+        }));
+        self.run_while(|| !done.get());
+        let value = output.borrow_mut().take();
+        value
+    }
+
+    // Polls woken tasks and waits for fds until there are no tasks left, `keep_running` returns
+    // false, or the executor is shut down.
+    fn run_while<C: Fn() -> bool>(&mut self, keep_running: C) {
+        let _reactor = reactor::enter(self.state.clone());
+        loop {
+            if self.shutdown.requested.load(Ordering::Acquire) {
+                self.drop_tasks();
+                return;
+            }
+
+            // Add any new futures to the list.
+            let new_futures = mem::take(&mut self.state.borrow_mut().new_futures);
+            for future in new_futures {
+                self.spawn(future);
+            }
+
+            if !keep_running() {
+                return;
+            }
+
+            let ready = self.ready.take();
+            if !ready.is_empty() {
+                for index in ready {
+                    self.poll_task(index);
+                }
+                continue;
+            }
+
+            if self.tasks.is_empty() {
+                return;
+            }
+
+            if self.tracing {
+                let start = Instant::now();
+                self.state.borrow_mut().wait_wake_readable();
+                self.totals.blocked_time += start.elapsed();
+                self.totals.waits += 1;
+            } else {
+                self.state.borrow_mut().wait_wake_readable();
+            }
+        }
+    }
+
+    // Drops every task, including futures added but not yet spawned. The futures' destructors
+    // may use the state, so it must not be borrowed while they run.
+    fn drop_tasks(&mut self) {
+        let new_futures = mem::take(&mut self.state.borrow_mut().new_futures);
+        drop(new_futures);
+        for task in self.tasks.take_all() {
+            task.waker.done.store(true, Ordering::Release);
+            drop(task);
+        }
+        self.ready.take();
+    }
+}
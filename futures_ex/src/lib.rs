@@ -0,0 +1,21 @@
+mod async_fd;
+mod cancel;
+mod executor;
+mod stats;
+mod uring;
+
+pub mod net;
+pub mod pool;
+pub mod process;
+pub mod reactor;
+pub mod signal;
+pub mod sim;
+pub mod sync;
+pub mod task;
+
+pub use async_fd::{AsyncFd, ReadFd, WriteFd};
+pub use cancel::{CancellationToken, Cancelled};
+pub use executor::{ExecutorHandle, FdExecutor, FdExecutorInterface, InterfaceState, WakerToken};
+pub use pool::{PoolExecutor, PoolHandle};
+pub use stats::{ExecutorStats, TaskStats};
+pub use uring::{fsync, read_at, write_at, IoOp};
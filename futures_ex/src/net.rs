@@ -0,0 +1,402 @@
+//! Unix domain and TCP sockets for tasks on an `FdExecutor`.
+//!
+//! The types wrap their `std` counterparts in non-blocking mode. Operations that would block wait
+//! for the socket through the reactor instead, so they must be awaited from a task on an
+//! `FdExecutor`.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{self, Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net as unix;
+use std::path::Path;
+use std::ptr;
+
+use crate::async_fd::retry_io;
+use crate::reactor;
+
+/// Listens for connections on a Unix domain socket.
+pub struct UnixListener {
+    inner: unix::UnixListener,
+}
+
+impl UnixListener {
+    /// Creates a socket bound to `path`.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        UnixListener::from_std(unix::UnixListener::bind(path)?)
+    }
+
+    /// Wraps a listener created elsewhere, switching it to non-blocking mode.
+    pub fn from_std(inner: unix::UnixListener) -> io::Result<UnixListener> {
+        inner.set_nonblocking(true)?;
+        Ok(UnixListener { inner })
+    }
+
+    /// Waits for the next connection.
+    pub async fn accept(&self) -> io::Result<(UnixStream, unix::SocketAddr)> {
+        let (stream, addr) = retry_io(&self.inner, false, || self.inner.accept()).await?;
+        Ok((UnixStream::from_std(stream)?, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// A connected Unix domain stream socket. Besides bytes it can pass fds to its peer with
+/// SCM_RIGHTS.
+pub struct UnixStream {
+    inner: unix::UnixStream,
+}
+
+impl UnixStream {
+    /// Connects to the socket at `path`. Connecting to a Unix socket doesn't wait for the peer to
+    /// accept, so this doesn't block.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+        UnixStream::from_std(unix::UnixStream::connect(path)?)
+    }
+
+    /// Creates a pair of connected sockets.
+    pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+        let (a, b) = unix::UnixStream::pair()?;
+        Ok((UnixStream::from_std(a)?, UnixStream::from_std(b)?))
+    }
+
+    /// Wraps a stream created elsewhere, switching it to non-blocking mode.
+    pub fn from_std(inner: unix::UnixStream) -> io::Result<UnixStream> {
+        inner.set_nonblocking(true)?;
+        Ok(UnixStream { inner })
+    }
+
+    /// Reads into `buf`, returning the number of bytes read. Zero means the peer shut down.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        retry_io(&self.inner, false, || (&self.inner).read(buf)).await
+    }
+
+    /// Writes from `buf`, returning the number of bytes written.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        retry_io(&self.inner, true, || (&self.inner).write(buf)).await
+    }
+
+    /// Fills `buf`, failing with `UnexpectedEof` if the peer shuts down first.
+    pub async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        read_exact(&self.inner, &self.inner, buf).await
+    }
+
+    /// Writes all of `buf`.
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        write_all(&self.inner, &self.inner, buf).await
+    }
+
+    /// Sends `buf` along with `fds`. The fds are duplicated into the peer, the caller still owns
+    /// its copies. Returns the number of bytes sent, the fds go with the first byte.
+    pub async fn send_with_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let fd = self.inner.as_raw_fd();
+        retry_io(&self.inner, true, || send_with_fds(fd, buf, fds)).await
+    }
+
+    /// Receives into `buf` and `fds`. Returns the number of bytes and fds received, the caller
+    /// owns the received fds. Fds that don't fit in `fds` are closed by the kernel.
+    pub async fn recv_with_fds(
+        &self,
+        buf: &mut [u8],
+        fds: &mut [RawFd],
+    ) -> io::Result<(usize, usize)> {
+        let fd = self.inner.as_raw_fd();
+        retry_io(&self.inner, false, || recv_with_fds(fd, buf, fds)).await
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn local_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<unix::SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Listens for TCP connections.
+pub struct TcpListener {
+    inner: net::TcpListener,
+}
+
+impl TcpListener {
+    /// Creates a socket bound to `addr`. Binding doesn't block.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        TcpListener::from_std(net::TcpListener::bind(addr)?)
+    }
+
+    /// Wraps a listener created elsewhere, switching it to non-blocking mode.
+    pub fn from_std(inner: net::TcpListener) -> io::Result<TcpListener> {
+        inner.set_nonblocking(true)?;
+        Ok(TcpListener { inner })
+    }
+
+    /// Waits for the next connection.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = retry_io(&self.inner, false, || self.inner.accept()).await?;
+        Ok((TcpStream::from_std(stream)?, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// A connected TCP socket.
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    /// Connects to `addr`, waiting for the handshake through the reactor.
+    pub async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+        let family = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        // Safe because socket doesn't touch memory and the result is checked before it's owned.
+        let fd = unsafe {
+            libc::socket(
+                family,
+                libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because `fd` was just created and nothing else owns it.
+        let inner = unsafe { net::TcpStream::from_raw_fd(fd) };
+
+        let (storage, len) = sockaddr(&addr);
+        // Safe because `storage` holds a valid address of `len` bytes.
+        let ret = unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINPROGRESS) {
+                return Err(err);
+            }
+            reactor::wait_writable(&inner).await?;
+            if let Some(err) = inner.take_error()? {
+                return Err(err);
+            }
+        }
+        Ok(TcpStream { inner })
+    }
+
+    /// Wraps a stream created elsewhere, switching it to non-blocking mode.
+    pub fn from_std(inner: net::TcpStream) -> io::Result<TcpStream> {
+        inner.set_nonblocking(true)?;
+        Ok(TcpStream { inner })
+    }
+
+    /// Reads into `buf`, returning the number of bytes read. Zero means the peer shut down.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        retry_io(&self.inner, false, || (&self.inner).read(buf)).await
+    }
+
+    /// Writes from `buf`, returning the number of bytes written.
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        retry_io(&self.inner, true, || (&self.inner).write(buf)).await
+    }
+
+    /// Fills `buf`, failing with `UnexpectedEof` if the peer shuts down first.
+    pub async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        read_exact(&self.inner, &self.inner, buf).await
+    }
+
+    /// Writes all of `buf`.
+    pub async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        write_all(&self.inner, &self.inner, buf).await
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+async fn read_exact<R: Read>(
+    fd: &dyn AsRawFd,
+    mut reader: R,
+    mut buf: &mut [u8],
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        let count = retry_io(fd, false, || reader.read(buf)).await?;
+        if count == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buf = &mut buf[count..];
+    }
+    Ok(())
+}
+
+async fn write_all<W: Write>(fd: &dyn AsRawFd, mut writer: W, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let count = retry_io(fd, true, || writer.write(buf)).await?;
+        if count == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        buf = &buf[count..];
+    }
+    Ok(())
+}
+
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // Safe because sockaddr_storage is plain data, all zeros is a valid value.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: a.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(a.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // Safe because sockaddr_storage is big enough and aligned for any address.
+            unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: a.port().to_be(),
+                sin6_flowinfo: a.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: a.ip().octets(),
+                },
+                sin6_scope_id: a.scope_id(),
+            };
+            // Safe because sockaddr_storage is big enough and aligned for any address.
+            unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+// Control message buffer with room for `count` fds, aligned for cmsghdr.
+fn cmsg_buffer(count: usize) -> Vec<u64> {
+    // Safe because CMSG_SPACE only does arithmetic.
+    let space = unsafe { libc::CMSG_SPACE((count * mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0u64; space.div_ceil(mem::size_of::<u64>())]
+}
+
+fn send_with_fds(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = cmsg_buffer(fds.len());
+    // Safe because msghdr is plain data, all zeros is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(control.as_slice()) as _;
+        // Safe because `control` has room for a header and `fds.len()` fds, as computed by
+        // CMSG_SPACE, so the header and data pointers stay in bounds.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of_val(fds) as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+    }
+    // Safe because `msg` points at `iov` and `control`, which outlive the call.
+    let ret = unsafe { libc::sendmsg(fd, &msg, libc::MSG_NOSIGNAL) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+fn recv_with_fds(fd: RawFd, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = cmsg_buffer(fds.len());
+    // Safe because msghdr is plain data, all zeros is a valid value.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(control.as_slice()) as _;
+    }
+    // Safe because `msg` points at `iov` and `control`, the kernel writes within their lengths.
+    let ret = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut count = 0;
+    // Safe because the kernel filled in the control messages and CMSG_NXTHDR stops at the end
+    // of `msg_controllen`.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let header = libc::CMSG_LEN(0) as usize;
+                let n = ((*cmsg).cmsg_len as usize - header) / mem::size_of::<RawFd>();
+                for i in 0..n {
+                    let received = ptr::read_unaligned(data.add(i));
+                    if count < fds.len() {
+                        fds[count] = received;
+                        count += 1;
+                    } else {
+                        libc::close(received);
+                    }
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((ret as usize, count))
+}
@@ -0,0 +1,490 @@
+//! A multi-threaded executor for `Send` futures.
+//!
+//! `PoolExecutor` runs its tasks on a fixed number of worker threads. Each worker has a local
+//! queue for the tasks it wakes itself, tasks woken from anywhere else go to a shared injection
+//! queue, and a worker that runs out of work steals half of another worker's queue. One idle
+//! worker at a time waits on the pool's `InterfaceState` for fds, the rest sleep until there is
+//! something to run. Futures that aren't `Send`, like the ones using `reactor`, stay on
+//! `FdExecutor`.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::thread;
+
+use sys_util::EventFd;
+
+use crate::executor::{FdExecutorInterface, InterfaceState, SavedFd, WakerToken};
+use crate::reactor;
+
+type PoolFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+thread_local! {
+    // The pool and worker index of the worker running on this thread.
+    static WORKER: RefCell<Option<(Arc<PoolShared>, usize)>> = const { RefCell::new(None) };
+}
+
+// Lets the reactor state be shared between workers.
+struct SendState(InterfaceState);
+
+// Safe because the pool never adds futures to the state, its list of new futures is the only part
+// of `InterfaceState` that isn't `Send`.
+unsafe impl Send for SendState {}
+
+/// The fd reactor shared by the workers. The worker waiting in `wait_wake_readable` holds the
+/// lock, others that want to add or remove a waker kick it out of the wait first.
+struct PoolReactor {
+    state: Mutex<SendState>,
+    // Threads waiting to lock `state`. The waiting worker doesn't start another wait while this
+    // is non-zero.
+    lockers: AtomicUsize,
+    // Set while a worker might be blocked in `wait_wake_readable`.
+    driving: AtomicBool,
+    wake_event: EventFd,
+}
+
+impl PoolReactor {
+    fn new() -> PoolReactor {
+        let state = InterfaceState::new();
+        let wake_event = state.wake_event();
+        PoolReactor {
+            state: Mutex::new(SendState(state)),
+            lockers: AtomicUsize::new(0),
+            driving: AtomicBool::new(false),
+            wake_event,
+        }
+    }
+
+    // Interrupts the worker waiting for fds, if there is one.
+    fn kick(&self) {
+        self.wake_event.write(1).unwrap();
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut InterfaceState) -> R) -> R {
+        self.lockers.fetch_add(1, Ordering::SeqCst);
+        self.kick();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.lockers.fetch_sub(1, Ordering::SeqCst);
+        f(&mut state.0)
+    }
+}
+
+struct PoolTask {
+    id: u64,
+    // `None` once the future has completed or been dropped by shutdown.
+    future: Mutex<Option<PoolFuture>>,
+    // Set while the task sits in a queue so repeated wakes only queue it once.
+    queued: AtomicBool,
+    // Weak because the reactor keeps wakers, which keep the task, alive.
+    shared: Weak<PoolShared>,
+}
+
+impl PoolTask {
+    fn wake(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if let Some(shared) = self.shared.upgrade() {
+            shared.schedule(self.clone());
+        }
+    }
+}
+
+unsafe fn pool_waker_drop(data_ptr: *const ()) {
+    drop(Arc::from_raw(data_ptr as *const PoolTask));
+}
+unsafe fn pool_waker_wake(data_ptr: *const ()) {
+    let task = Arc::from_raw(data_ptr as *const PoolTask);
+    task.wake();
+}
+unsafe fn pool_waker_wake_by_ref(data_ptr: *const ()) {
+    let task = ManuallyDrop::new(Arc::from_raw(data_ptr as *const PoolTask));
+    task.wake();
+}
+unsafe fn pool_waker_clone(data_ptr: *const ()) -> RawWaker {
+    let task = ManuallyDrop::new(Arc::from_raw(data_ptr as *const PoolTask));
+    RawWaker::new(
+        Arc::into_raw(Arc::clone(&task)) as *const (),
+        &POOL_WAKER_VTABLE,
+    )
+}
+
+static POOL_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    pool_waker_clone,
+    pool_waker_wake,
+    pool_waker_wake_by_ref,
+    pool_waker_drop,
+);
+
+fn new_pool_waker(task: &Arc<PoolTask>) -> Waker {
+    let data_ptr = Arc::into_raw(Arc::clone(task)) as *const ();
+    // Safe because the pointer came from `Arc::into_raw` and the vtable functions treat it as
+    // an `Arc<PoolTask>`.
+    unsafe { Waker::from_raw(RawWaker::new(data_ptr, &POOL_WAKER_VTABLE)) }
+}
+
+struct PoolShared {
+    injector: Mutex<VecDeque<Arc<PoolTask>>>,
+    locals: Vec<Mutex<VecDeque<Arc<PoolTask>>>>,
+    // Every task that hasn't completed, so shutdown can drop them.
+    tasks: Mutex<HashMap<u64, Arc<PoolTask>>>,
+    next_id: AtomicU64,
+    remaining: AtomicUsize,
+    // Number of workers sleeping on `idle_cond`.
+    idle: Mutex<usize>,
+    idle_cond: Condvar,
+    shutdown: AtomicBool,
+    reactor: PoolReactor,
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A task that panicked while polled has already stopped the pool, the queues are still
+    // consistent.
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl PoolShared {
+    fn spawn(self: &Arc<Self>, future: PoolFuture) {
+        let task = Arc::new(PoolTask {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            future: Mutex::new(Some(future)),
+            queued: AtomicBool::new(false),
+            shared: Arc::downgrade(self),
+        });
+        self.remaining.fetch_add(1, Ordering::SeqCst);
+        lock(&self.tasks).insert(task.id, task.clone());
+        task.wake();
+    }
+
+    // Queues a woken task, on this thread's worker if it belongs to the pool.
+    fn schedule(self: &Arc<Self>, task: Arc<PoolTask>) {
+        let worker = WORKER.with(|w| match &*w.borrow() {
+            Some((shared, index)) if Arc::ptr_eq(shared, self) => Some(*index),
+            _ => None,
+        });
+        match worker {
+            Some(index) => {
+                lock(&self.locals[index]).push_back(task);
+                // Give a sleeping worker the chance to steal it.
+                self.notify_one();
+            }
+            None => {
+                lock(&self.injector).push_back(task);
+                self.notify_one();
+                if self.reactor.driving.load(Ordering::SeqCst) {
+                    self.reactor.kick();
+                }
+            }
+        }
+    }
+
+    fn notify_one(&self) {
+        if *lock(&self.idle) > 0 {
+            self.idle_cond.notify_one();
+        }
+    }
+
+    // Wakes every worker so they notice the pool is done or shut down.
+    fn notify_all(&self) {
+        let _idle = lock(&self.idle);
+        self.idle_cond.notify_all();
+        self.reactor.kick();
+    }
+
+    fn finished(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire) || self.remaining.load(Ordering::SeqCst) == 0
+    }
+
+    fn find_task(&self, index: usize) -> Option<Arc<PoolTask>> {
+        if let Some(task) = lock(&self.locals[index]).pop_front() {
+            return Some(task);
+        }
+        if let Some(task) = lock(&self.injector).pop_front() {
+            return Some(task);
+        }
+        let count = self.locals.len();
+        for victim in (1..count).map(|i| (index + i) % count) {
+            let mut stolen: VecDeque<_> = {
+                let mut queue = lock(&self.locals[victim]);
+                let half = queue.len().div_ceil(2);
+                queue.drain(..half).collect()
+            };
+            if let Some(task) = stolen.pop_front() {
+                lock(&self.locals[index]).extend(stolen);
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    fn run_task(&self, task: Arc<PoolTask>) {
+        // Clear before polling so a wake from inside `poll` queues the task again.
+        task.queued.store(false, Ordering::Release);
+        let mut future = lock(&task.future);
+        let done = match future.as_mut() {
+            Some(f) => {
+                let waker = new_pool_waker(&task);
+                let mut ctx = Context::from_waker(&waker);
+                f.as_mut().poll(&mut ctx).is_ready()
+            }
+            None => return,
+        };
+        if done {
+            *future = None;
+            drop(future);
+            lock(&self.tasks).remove(&task.id);
+            if self.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                self.notify_all();
+            }
+        }
+    }
+
+    // Waits for fds if no other worker is. Returns false if another worker has the reactor.
+    fn drive(&self) -> bool {
+        if self.reactor.lockers.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        let mut state = match self.reactor.state.try_lock() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+        self.reactor.driving.store(true, Ordering::SeqCst);
+        // Tasks queued before `driving` was set didn't kick the reactor, look for them before
+        // blocking.
+        if lock(&self.injector).is_empty() && !self.finished() {
+            state.0.wait_wake_readable();
+        }
+        self.reactor.driving.store(false, Ordering::SeqCst);
+        drop(state);
+        // Let another idle worker take over waiting for fds while this one runs what was woken.
+        self.notify_one();
+        true
+    }
+
+    fn sleep(&self) {
+        let mut idle = lock(&self.idle);
+        if !lock(&self.injector).is_empty() || self.finished() {
+            return;
+        }
+        *idle += 1;
+        idle = self
+            .idle_cond
+            .wait(idle)
+            .unwrap_or_else(PoisonError::into_inner);
+        *idle -= 1;
+    }
+
+    fn work(self: &Arc<Self>, index: usize) {
+        WORKER.with(|w| *w.borrow_mut() = Some((self.clone(), index)));
+        let _stop = StopOnPanic(self);
+        while !self.finished() {
+            if let Some(task) = self.find_task(index) {
+                self.run_task(task);
+                continue;
+            }
+            if !self.drive() {
+                self.sleep();
+            }
+        }
+        WORKER.with(|w| *w.borrow_mut() = None);
+    }
+
+    fn shut_down(&self) {
+        self.shutdown.store(true, Ordering::Release);
+        self.notify_all();
+    }
+
+    // Drops every task that hasn't completed. Called with no workers running.
+    fn drop_tasks(&self) {
+        let tasks: Vec<_> = lock(&self.tasks).drain().map(|(_, task)| task).collect();
+        for task in tasks {
+            // The future's destructor might remove wakers, so drop it without holding its lock.
+            let future = lock(&task.future).take();
+            drop(future);
+        }
+        lock(&self.injector).clear();
+        for local in &self.locals {
+            lock(local).clear();
+        }
+        self.remaining.store(0, Ordering::SeqCst);
+    }
+}
+
+// Shuts the pool down if a task panics, so the other workers return instead of waiting for it.
+struct StopOnPanic<'a>(&'a PoolShared);
+
+impl<'a> Drop for StopOnPanic<'a> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.shut_down();
+        }
+    }
+}
+
+/// Spawns tasks on and stops a `PoolExecutor`. Can be cloned and sent to other threads, including
+/// into the pool's own tasks.
+#[derive(Clone)]
+pub struct PoolHandle {
+    shared: Arc<PoolShared>,
+}
+
+impl PoolHandle {
+    /// Adds `future` to the pool. It starts running on the next `run`, or right away if the pool
+    /// is running.
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+        self.shared.spawn(Box::pin(future));
+    }
+
+    /// Makes `run` drop every task it hasn't finished and return. Tasks being polled when this is
+    /// called finish their current poll first.
+    pub fn shutdown(&self) {
+        self.shared.shut_down();
+    }
+
+    /// Returns true if `shutdown` has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.shutdown.load(Ordering::Acquire)
+    }
+}
+
+/// Runs `Send` futures on a pool of worker threads.
+pub struct PoolExecutor {
+    shared: Arc<PoolShared>,
+}
+
+impl PoolExecutor {
+    /// Creates a pool that runs its tasks on `threads` worker threads.
+    pub fn new(threads: usize) -> PoolExecutor {
+        assert!(threads > 0, "a pool needs at least one worker");
+        PoolExecutor {
+            shared: Arc::new(PoolShared {
+                injector: Mutex::new(VecDeque::new()),
+                locals: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+                tasks: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                remaining: AtomicUsize::new(0),
+                idle: Mutex::new(0),
+                idle_cond: Condvar::new(),
+                shutdown: AtomicBool::new(false),
+                reactor: PoolReactor::new(),
+            }),
+        }
+    }
+
+    /// Returns a handle that can add tasks to the pool or shut it down.
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Adds `future` to the pool.
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+        self.shared.spawn(Box::pin(future));
+    }
+
+    /// Starts the workers and waits until every task has completed or the pool is shut down. If a
+    /// task panics the pool is shut down and the panic continues from here.
+    pub fn run(&mut self) {
+        let workers: Vec<_> = (0..self.shared.locals.len())
+            .map(|index| {
+                let shared = self.shared.clone();
+                thread::Builder::new()
+                    .name(format!("pool-worker-{}", index))
+                    .spawn(move || shared.work(index))
+                    .unwrap()
+            })
+            .collect();
+        let mut panic = None;
+        for worker in workers {
+            if let Err(e) = worker.join() {
+                panic.get_or_insert(e);
+            }
+        }
+        if self.shared.shutdown.load(Ordering::Acquire) {
+            self.shared.drop_tasks();
+        }
+        if let Some(e) = panic {
+            panic::resume_unwind(e);
+        }
+    }
+}
+
+impl Drop for PoolExecutor {
+    fn drop(&mut self) {
+        // Break the cycles between tasks that hold handles to the pool.
+        self.shared.drop_tasks();
+    }
+}
+
+/// Waits for `fd` to become readable from a task on a `PoolExecutor`. The future is `Send` so it
+/// can be held across an await in a pool task. `fd` must stay open until the future is dropped.
+pub fn wait_readable(fd: &dyn AsRawFd) -> PoolFdReady {
+    PoolFdReady {
+        fd: fd.as_raw_fd(),
+        write: false,
+        registration: None,
+    }
+}
+
+/// Waits for `fd` to become writable from a task on a `PoolExecutor`. `fd` must stay open until
+/// the future is dropped.
+pub fn wait_writable(fd: &dyn AsRawFd) -> PoolFdReady {
+    PoolFdReady {
+        fd: fd.as_raw_fd(),
+        write: true,
+        registration: None,
+    }
+}
+
+/// Future returned by `pool::wait_readable` and `pool::wait_writable`. Resolves to
+/// `Error::NoReactor` if polled anywhere but a pool worker.
+pub struct PoolFdReady {
+    fd: RawFd,
+    write: bool,
+    registration: Option<(Arc<PoolShared>, WakerToken)>,
+}
+
+impl Future for PoolFdReady {
+    type Output = reactor::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some((shared, token)) = &self.registration {
+            let token = *token;
+            if !shared.reactor.with_state(|s| s.is_waker_pending(token)) {
+                self.registration = None;
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        let shared = match WORKER.with(|w| w.borrow().as_ref().map(|(s, _)| s.clone())) {
+            Some(shared) => shared,
+            None => return Poll::Ready(Err(reactor::Error::NoReactor)),
+        };
+        let fd = SavedFd(self.fd);
+        let write = self.write;
+        let waker = cx.waker().clone();
+        // A pool task only runs on its own pool, so the old waker is in the same reactor.
+        let old = self.registration.take().map(|(_, token)| token);
+        let token = shared
+            .reactor
+            .with_state(|s| s.replace_waker(old, &fd, write, waker));
+        self.registration = Some((shared, token));
+        Poll::Pending
+    }
+}
+
+impl Drop for PoolFdReady {
+    fn drop(&mut self) {
+        if let Some((shared, token)) = self.registration.take() {
+            shared.reactor.with_state(|s| s.remove_waker(token));
+        }
+    }
+}
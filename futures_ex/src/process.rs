@@ -0,0 +1,185 @@
+//! Child processes whose exit and output are awaited on the executor.
+//!
+//! `spawn` starts a `std::process::Command` and hands back its piped stdio as `AsyncFd`s. The exit
+//! is watched through a pidfd, which becomes readable when the child exits. Kernels without
+//! pidfd_open fall back to reading SIGCHLD from a signalfd, with the same caveat as `signals`
+//! about the threads that have it blocked.
+
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::pin::Pin;
+use std::process::{self, Command, ExitStatus};
+use std::task::{Context, Poll};
+
+use crate::async_fd::AsyncFd;
+use crate::reactor;
+use crate::signal::{signals, Signals};
+
+// How the child's exit is noticed.
+enum ExitWatch {
+    PidFd(AsyncFd),
+    SigChld(Signals),
+}
+
+/// A running child process.
+///
+/// Dropping a `Child` neither kills nor reaps the process, `wait` for it first.
+pub struct Child {
+    child: process::Child,
+    exit: ExitWatch,
+    /// The child's stdin if it was piped.
+    pub stdin: Option<AsyncFd>,
+    /// The child's stdout if it was piped.
+    pub stdout: Option<AsyncFd>,
+    /// The child's stderr if it was piped.
+    pub stderr: Option<AsyncFd>,
+}
+
+fn pidfd_open(pid: u32) -> io::Result<AsyncFd> {
+    // Safe because pidfd_open doesn't touch memory and the returned fd is checked and owned.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because `fd` was just opened and nothing else owns it.
+    AsyncFd::new(unsafe { std::fs::File::from_raw_fd(fd as i32) })
+}
+
+fn into_async<F: IntoRawFd>(fd: Option<F>) -> io::Result<Option<AsyncFd>> {
+    fd.map(AsyncFd::new).transpose()
+}
+
+/// Starts `command`. Any stdio set to `Stdio::piped()` is available from the returned `Child`.
+pub fn spawn(command: &mut Command) -> io::Result<Child> {
+    let mut child = command.spawn()?;
+    // A child that exits before SIGCHLD is blocked is caught by `wait` checking for an exit
+    // before it waits for the signal.
+    let exit = match pidfd_open(child.id()) {
+        Ok(pidfd) => Ok(ExitWatch::PidFd(pidfd)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+            signals(&[libc::SIGCHLD]).map(ExitWatch::SigChld)
+        }
+        Err(e) => Err(e),
+    };
+    let exit = match exit {
+        Ok(exit) => exit,
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+    };
+    Ok(Child {
+        stdin: into_async(child.stdin.take())?,
+        stdout: into_async(child.stdout.take())?,
+        stderr: into_async(child.stderr.take())?,
+        child,
+        exit,
+    })
+}
+
+impl Child {
+    /// The child's process id.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Sends SIGKILL to the child.
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.kill()
+    }
+
+    /// Returns the exit status if the child has exited, without waiting.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    /// Waits for the child to exit and reaps it. Stdin is closed first so a child reading it
+    /// doesn't wait forever. Must be polled from a task on an `FdExecutor`.
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.stdin = None;
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(status);
+            }
+            match &mut self.exit {
+                ExitWatch::PidFd(pidfd) => {
+                    reactor::wait_readable(pidfd).await?;
+                }
+                ExitWatch::SigChld(sigchld) => {
+                    // Any child exiting sends SIGCHLD, check again whichever it was.
+                    sigchld.recv().await?;
+                }
+            }
+        }
+    }
+
+    /// Reads stdout and stderr to the end while waiting for the child to exit. Stdin is closed
+    /// first, like `wait` does, so a child reading it sees the end of its input.
+    pub async fn wait_with_output(mut self) -> io::Result<process::Output> {
+        self.stdin = None;
+        let stdout = self.stdout.take();
+        let stderr = self.stderr.take();
+        let (out, err) = Join::new(read_all(stdout), read_all(stderr)).await;
+        let status = self.wait().await?;
+        Ok(process::Output {
+            status,
+            stdout: out?,
+            stderr: err?,
+        })
+    }
+}
+
+async fn read_all(fd: Option<AsyncFd>) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(fd) = fd {
+        fd.read_to_end(&mut buf).await?;
+    }
+    Ok(buf)
+}
+
+// Polls two futures until both complete, so stdout and stderr are drained together and a child
+// that fills one pipe doesn't block while the other is being read.
+struct Join<A: Future, B: Future> {
+    a: Pin<Box<A>>,
+    b: Pin<Box<B>>,
+    a_out: Option<A::Output>,
+    b_out: Option<B::Output>,
+}
+
+impl<A: Future, B: Future> Join<A, B> {
+    fn new(a: A, b: B) -> Join<A, B> {
+        Join {
+            a: Box::pin(a),
+            b: Box::pin(b),
+            a_out: None,
+            b_out: None,
+        }
+    }
+}
+
+// The futures are boxed and the outputs are never pinned.
+impl<A: Future, B: Future> Unpin for Join<A, B> {}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if this.a_out.is_none() {
+            if let Poll::Ready(out) = this.a.as_mut().poll(cx) {
+                this.a_out = Some(out);
+            }
+        }
+        if this.b_out.is_none() {
+            if let Poll::Ready(out) = this.b.as_mut().poll(cx) {
+                this.b_out = Some(out);
+            }
+        }
+        if this.a_out.is_some() && this.b_out.is_some() {
+            return Poll::Ready((this.a_out.take().unwrap(), this.b_out.take().unwrap()));
+        }
+        Poll::Pending
+    }
+}
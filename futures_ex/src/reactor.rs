@@ -0,0 +1,169 @@
+//! Access to the running executor's `InterfaceState` without passing it to every future.
+//!
+//! `FdExecutor::run` makes its state the current reactor for the thread while it polls tasks, so
+//! leaf futures can find it from inside `poll`. A future polled anywhere else gets
+//! `Error::NoReactor` instead of a panic.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use crate::executor::{FdExecutorInterface, InterfaceState, WakerToken};
+
+thread_local! {
+    static REACTOR: RefCell<Option<Arc<RefCell<InterfaceState>>>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// A future that needs the reactor was polled outside of an `FdExecutor`.
+    NoReactor,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoReactor => write!(f, "future polled outside of an FdExecutor"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::other(e)
+    }
+}
+
+/// Restores the previous reactor when dropped, so nested executors and panics unwinding out of
+/// `run` leave the thread as they found it.
+pub(crate) struct ReactorGuard {
+    previous: Option<Arc<RefCell<InterfaceState>>>,
+}
+
+impl Drop for ReactorGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        REACTOR.with(|r| *r.borrow_mut() = previous);
+    }
+}
+
+/// Makes `state` the current reactor until the returned guard is dropped.
+pub(crate) fn enter(state: Arc<RefCell<InterfaceState>>) -> ReactorGuard {
+    let previous = REACTOR.with(|r| r.borrow_mut().replace(state));
+    ReactorGuard { previous }
+}
+
+/// Returns the state of the executor running on this thread.
+pub fn current() -> Result<Arc<RefCell<InterfaceState>>> {
+    REACTOR.with(|r| r.borrow().clone()).ok_or(Error::NoReactor)
+}
+
+/// Adds a top level future to the executor running on this thread.
+pub fn spawn(future: Pin<Box<dyn Future<Output = ()>>>) -> Result<()> {
+    current()?.borrow_mut().add_future(future);
+    Ok(())
+}
+
+/// A waker registered with the current reactor for a future waiting on an fd. Registering again
+/// replaces the previous waker, and dropping the registration removes it.
+#[derive(Default)]
+pub struct Registration(Option<(Arc<RefCell<InterfaceState>>, WakerToken)>);
+
+impl Registration {
+    pub fn new() -> Registration {
+        Registration(None)
+    }
+
+    /// Registers `waker` to be woken when `fd` becomes readable, or writable if `write` is set.
+    pub fn register(&mut self, fd: &dyn AsRawFd, write: bool, waker: &Waker) -> Result<()> {
+        let state = current()?;
+        let old = match self.0.take() {
+            Some((old_state, token)) if Arc::ptr_eq(&old_state, &state) => Some(token),
+            Some((old_state, token)) => {
+                old_state.borrow_mut().remove_waker(token);
+                None
+            }
+            None => None,
+        };
+        let token = state
+            .borrow_mut()
+            .replace_waker(old, fd, write, waker.clone());
+        self.0 = Some((state, token));
+        Ok(())
+    }
+
+    /// Returns true if the fd became ready since the last `register`. The registration is
+    /// cleared when it has.
+    pub fn fired(&mut self) -> bool {
+        let fired = match &self.0 {
+            Some((state, token)) => !state.borrow().is_waker_pending(*token),
+            None => false,
+        };
+        if fired {
+            self.0 = None;
+        }
+        fired
+    }
+
+    /// Removes the waker, if one is registered.
+    pub fn clear(&mut self) {
+        if let Some((state, token)) = self.0.take() {
+            state.borrow_mut().remove_waker(token);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Waits for `fd` to become readable. `fd` must stay open until the future is dropped.
+pub fn wait_readable(fd: &dyn AsRawFd) -> FdReady<'_> {
+    FdReady {
+        fd,
+        write: false,
+        registration: Registration::new(),
+    }
+}
+
+/// Waits for `fd` to become writable. `fd` must stay open until the future is dropped.
+pub fn wait_writable(fd: &dyn AsRawFd) -> FdReady<'_> {
+    FdReady {
+        fd,
+        write: true,
+        registration: Registration::new(),
+    }
+}
+
+/// Future returned by `wait_readable` and `wait_writable`.
+pub struct FdReady<'a> {
+    fd: &'a dyn AsRawFd,
+    write: bool,
+    registration: Registration,
+}
+
+impl<'a> Future for FdReady<'a> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.registration.fired() {
+            return Poll::Ready(Ok(()));
+        }
+        let (fd, write) = (self.fd, self.write);
+        match self.registration.register(fd, write, cx.waker()) {
+            Ok(()) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
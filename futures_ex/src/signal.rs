@@ -0,0 +1,154 @@
+//! Signals delivered through a signalfd watched by the executor.
+//!
+//! `signals` blocks the requested signals on the calling thread and reads them from a signalfd,
+//! so a task can wait for SIGTERM or SIGCHLD next to its other fds instead of relying on a signal
+//! handler. Blocking only affects the calling thread, create the stream before starting other
+//! threads so they inherit the mask, otherwise a process directed signal can be delivered to one
+//! of them instead.
+
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
+
+use crate::reactor::Registration;
+
+/// A signal read from the stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SignalInfo {
+    /// The signal number, for example `libc::SIGTERM`.
+    pub signal: c_int,
+    /// The pid of the sender, or of the child that changed state for SIGCHLD.
+    pub pid: u32,
+    /// The exit status or signal of the child for SIGCHLD.
+    pub status: i32,
+    /// One of the `CLD_*` codes for SIGCHLD, `SI_USER` and friends otherwise.
+    pub code: i32,
+}
+
+/// A stream of the signals passed to `signals`. Dropping it unblocks the signals it blocked.
+pub struct Signals {
+    fd: RawFd,
+    // The signals that weren't blocked before, unblocked again on drop.
+    blocked: libc::sigset_t,
+}
+
+/// Starts reading `signals` through a signalfd. The signals are blocked on the calling thread so
+/// they are only delivered to the stream.
+pub fn signals(signals: &[c_int]) -> io::Result<Signals> {
+    // Safe because the sigset functions only write to the sets passed to them, which are all
+    // initialized by `sigemptyset` first.
+    unsafe {
+        let mut mask: libc::sigset_t = mem::zeroed();
+        let mut old: libc::sigset_t = mem::zeroed();
+        let mut blocked: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        libc::sigemptyset(&mut blocked);
+        for &signal in signals {
+            if libc::sigaddset(&mut mask, signal) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, &mut old);
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        for &signal in signals {
+            if libc::sigismember(&old, signal) == 0 {
+                libc::sigaddset(&mut blocked, signal);
+            }
+        }
+        let fd = libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &blocked, ptr::null_mut());
+            return Err(err);
+        }
+        Ok(Signals { fd, blocked })
+    }
+}
+
+impl Signals {
+    /// Waits for the next signal. Must be polled from a task on an `FdExecutor`.
+    pub fn recv(&mut self) -> RecvSignal<'_> {
+        RecvSignal {
+            signals: self,
+            registration: Registration::new(),
+        }
+    }
+
+    /// Returns the next signal if one is already pending.
+    pub fn try_recv(&mut self) -> io::Result<Option<SignalInfo>> {
+        // Safe because signalfd_siginfo is plain data, all zeros is a valid value.
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+        // Safe because the kernel writes at most `size` bytes to `info`.
+        let ret = unsafe { libc::read(self.fd, &mut info as *mut _ as *mut libc::c_void, size) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        if ret as usize != size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(Some(SignalInfo {
+            signal: info.ssi_signo as c_int,
+            pid: info.ssi_pid,
+            status: info.ssi_status,
+            code: info.ssi_code,
+        }))
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        // Safe because this struct owns the fd and `blocked` was initialized in `signals`.
+        unsafe {
+            libc::close(self.fd);
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &self.blocked, ptr::null_mut());
+        }
+    }
+}
+
+/// Future returned by `Signals::recv`.
+pub struct RecvSignal<'a> {
+    signals: &'a mut Signals,
+    registration: Registration,
+}
+
+impl<'a> Future for RecvSignal<'a> {
+    type Output = io::Result<SignalInfo>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.signals.try_recv() {
+            Ok(Some(info)) => {
+                self.registration.clear();
+                return Poll::Ready(Ok(info));
+            }
+            Ok(None) => (),
+            Err(e) => {
+                self.registration.clear();
+                return Poll::Ready(Err(e));
+            }
+        }
+        let RecvSignal {
+            signals,
+            registration,
+        } = &mut *self;
+        registration.register(&**signals, false, cx.waker())?;
+        Poll::Pending
+    }
+}
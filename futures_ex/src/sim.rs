@@ -0,0 +1,396 @@
+//! A deterministic executor for tests.
+//!
+//! `SimExecutor` runs futures against a `SimState` instead of an `InterfaceState`. There is no
+//! epoll: fds are numbers handed out by `SimState::new_fd` and the test decides when they are
+//! readable or writable. Time is virtual, `sleep` only completes when the test advances the clock
+//! or every task is waiting on a timer. Woken tasks are polled in an order chosen by a seeded
+//! random number generator, so a test can run the same futures under many orderings and replay a
+//! failing one from its seed.
+//!
+//! Futures have to use the `FdExecutorInterface` passed to them, the leaf futures in `reactor`
+//! need a real `InterfaceState`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::executor::{
+    new_waker, FdExecutorInterface, ReadyQueue, Task, TaskSlab, TaskWaker, WakerToken,
+};
+use crate::stats::TaskStats;
+use crate::task::{self, TaskContext};
+
+// Simulated fds are numbered from here so they can't be mistaken for real ones.
+const FIRST_SIM_FD: RawFd = 1 << 20;
+
+/// An fd that only exists in a `SimState`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SimFd(RawFd);
+
+impl AsRawFd for SimFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+struct SimWaker {
+    fd: RawFd,
+    write: bool,
+    waker: Waker,
+}
+
+/// Simulated fds, timers and the futures added by tasks. Shared with the futures under test as
+/// their `FdExecutorInterface`.
+pub struct SimState {
+    next_fd: RawFd,
+    next_token: u64,
+    wakers: HashMap<WakerToken, SimWaker>,
+    readable: HashSet<RawFd>,
+    writable: HashSet<RawFd>,
+    now: Duration,
+    // Keyed by deadline, then by token so equal deadlines fire in the order they were added.
+    timers: BTreeMap<(Duration, u64), Waker>,
+    new_futures: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl SimState {
+    fn new() -> SimState {
+        SimState {
+            next_fd: FIRST_SIM_FD,
+            next_token: 0,
+            wakers: HashMap::new(),
+            readable: HashSet::new(),
+            writable: HashSet::new(),
+            now: Duration::from_secs(0),
+            timers: BTreeMap::new(),
+            new_futures: Vec::new(),
+        }
+    }
+
+    /// Creates an fd that is neither readable nor writable.
+    pub fn new_fd(&mut self) -> SimFd {
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        SimFd(fd)
+    }
+
+    /// Sets whether `fd` is readable. Like a level triggered epoll, wakers are woken when it
+    /// becomes readable and right away if they are added while it is.
+    pub fn set_readable(&mut self, fd: &dyn AsRawFd, readable: bool) {
+        let fd = fd.as_raw_fd();
+        if readable {
+            self.readable.insert(fd);
+            self.fire(fd, false);
+        } else {
+            self.readable.remove(&fd);
+        }
+    }
+
+    /// Sets whether `fd` is writable, the write side of `set_readable`.
+    pub fn set_writable(&mut self, fd: &dyn AsRawFd, writable: bool) {
+        let fd = fd.as_raw_fd();
+        if writable {
+            self.writable.insert(fd);
+            self.fire(fd, true);
+        } else {
+            self.writable.remove(&fd);
+        }
+    }
+
+    /// Returns the number of wakers waiting for a simulated fd.
+    pub fn waker_count(&self) -> usize {
+        self.wakers.len()
+    }
+
+    /// The virtual time since the executor was created.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    // Wakes and forgets the wakers waiting for `fd`, like `InterfaceState` does when it fires.
+    fn fire(&mut self, fd: RawFd, write: bool) {
+        self.wakers.retain(|_, w| {
+            let fire = w.fd == fd && w.write == write;
+            if fire {
+                w.waker.wake_by_ref();
+            }
+            !fire
+        });
+    }
+
+    fn add_sim_waker(&mut self, fd: &dyn AsRawFd, write: bool, waker: Waker) -> WakerToken {
+        let fd = fd.as_raw_fd();
+        let ready = if write {
+            self.writable.contains(&fd)
+        } else {
+            self.readable.contains(&fd)
+        };
+        let token = WakerToken(self.next_token);
+        self.next_token += 1;
+        if ready {
+            // Already fired, the token is never pending.
+            waker.wake();
+        } else {
+            self.wakers.insert(token, SimWaker { fd, write, waker });
+        }
+        token
+    }
+
+    /// Returns true if the waker for `token` is still waiting.
+    pub fn is_waker_pending(&self, token: WakerToken) -> bool {
+        self.wakers.contains_key(&token)
+    }
+
+    // Moves the clock to `now`, waking every timer that expires on the way.
+    fn advance_to(&mut self, now: Duration) {
+        if now > self.now {
+            self.now = now;
+        }
+        let later = self.timers.split_off(&(self.now, u64::MAX));
+        for (_, waker) in mem::replace(&mut self.timers, later) {
+            waker.wake();
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.timers.keys().next().map(|(deadline, _)| *deadline)
+    }
+}
+
+impl FdExecutorInterface for SimState {
+    fn add_waker(&mut self, fd: &dyn AsRawFd, waker: Waker) -> WakerToken {
+        self.add_sim_waker(fd, false, waker)
+    }
+
+    fn add_write_waker(&mut self, fd: &dyn AsRawFd, waker: Waker) -> WakerToken {
+        self.add_sim_waker(fd, true, waker)
+    }
+
+    fn remove_waker(&mut self, token: WakerToken) {
+        self.wakers.remove(&token);
+    }
+
+    fn add_future(&mut self, future: Pin<Box<dyn Future<Output = ()>>>) {
+        self.new_futures.push(future);
+    }
+}
+
+/// Completes once the virtual clock reaches its deadline.
+pub struct Sleep {
+    state: Rc<RefCell<SimState>>,
+    deadline: Duration,
+    timer: Option<(Duration, u64)>,
+}
+
+/// Waits for `duration` of virtual time on `state`.
+pub fn sleep(state: &Rc<RefCell<SimState>>, duration: Duration) -> Sleep {
+    let deadline = state.borrow().now + duration;
+    Sleep {
+        state: state.clone(),
+        deadline,
+        timer: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let state = self.state.clone();
+        let mut state = state.borrow_mut();
+        if let Some(timer) = self.timer.take() {
+            state.timers.remove(&timer);
+        }
+        if state.now >= self.deadline {
+            return Poll::Ready(());
+        }
+        let timer = (self.deadline, state.next_token);
+        state.next_token += 1;
+        state.timers.insert(timer, cx.waker().clone());
+        self.timer = Some(timer);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            self.state.borrow_mut().timers.remove(&timer);
+        }
+    }
+}
+
+/// Returned by `SimExecutor::run` when tasks remain but nothing can wake them: no task is
+/// queued, no timer is set and the remaining tasks wait on fds or wakers the test never fires.
+#[derive(Debug, PartialEq)]
+pub struct Stalled {
+    pub tasks: usize,
+}
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} tasks can't make progress", self.tasks)
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+// xorshift64*, plenty for shuffling and stable across platforms and releases.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Zero is a fixed point of xorshift.
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % n
+    }
+}
+
+/// Runs futures against a `SimState`, polling woken tasks in a seeded random order.
+pub struct SimExecutor {
+    tasks: TaskSlab,
+    ready: Arc<ReadyQueue>,
+    // Woken tasks not polled yet, picked from at random.
+    runnable: Vec<usize>,
+    state: Rc<RefCell<SimState>>,
+    rng: Rng,
+    next_task_id: u64,
+}
+
+impl SimExecutor {
+    /// Creates an executor whose polling order is determined by `seed`.
+    pub fn new(seed: u64) -> SimExecutor {
+        SimExecutor {
+            tasks: TaskSlab::new(),
+            ready: Arc::new(ReadyQueue::new()),
+            runnable: Vec::new(),
+            state: Rc::new(RefCell::new(SimState::new())),
+            rng: Rng::new(seed),
+            next_task_id: 0,
+        }
+    }
+
+    /// The state to hand to futures as their `FdExecutorInterface` and to drive fds with.
+    pub fn state(&self) -> Rc<RefCell<SimState>> {
+        self.state.clone()
+    }
+
+    /// Adds a top level future.
+    pub fn spawn(&mut self, future: Pin<Box<dyn Future<Output = ()>>>) {
+        let waker = Arc::new(TaskWaker {
+            index: self.tasks.next_index(),
+            queued: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+        waker.wake();
+        let stats = TaskStats::new(self.next_task_id);
+        let context = TaskContext::new(self.next_task_id);
+        self.next_task_id += 1;
+        self.tasks.insert(Task {
+            future,
+            waker,
+            stats,
+            context,
+        });
+    }
+
+    /// Returns the number of tasks that haven't completed.
+    pub fn task_count(&self) -> usize {
+        self.tasks.iter().count()
+    }
+
+    /// Polls tasks until none are woken, without moving the clock. Returns the number of polls.
+    pub fn run_until_stalled(&mut self) -> usize {
+        let mut polls = 0;
+        loop {
+            let new_futures = mem::take(&mut self.state.borrow_mut().new_futures);
+            for future in new_futures {
+                self.spawn(future);
+            }
+            self.runnable.extend(self.ready.take());
+            if self.runnable.is_empty() {
+                return polls;
+            }
+            let pick = self.rng.below(self.runnable.len());
+            let index = self.runnable.swap_remove(pick);
+            self.poll_task(index);
+            polls += 1;
+        }
+    }
+
+    /// Moves the virtual clock forward by `duration`, waking the timers that expire, and runs
+    /// the woken tasks.
+    pub fn advance(&mut self, duration: Duration) {
+        let now = self.state.borrow().now + duration;
+        self.state.borrow_mut().advance_to(now);
+        self.run_until_stalled();
+    }
+
+    /// Runs until every task completes, jumping the clock to the next timer whenever all tasks
+    /// are waiting. Fails if tasks remain with nothing left that could wake them.
+    pub fn run(&mut self) -> Result<(), Stalled> {
+        loop {
+            self.run_until_stalled();
+            if self.tasks.is_empty() {
+                return Ok(());
+            }
+            let next = self.state.borrow().next_deadline();
+            match next {
+                Some(deadline) => self.state.borrow_mut().advance_to(deadline),
+                None => {
+                    return Err(Stalled {
+                        tasks: self.task_count(),
+                    })
+                }
+            }
+        }
+    }
+
+    fn poll_task(&mut self, index: usize) {
+        let task = match self.tasks.get_mut(index) {
+            Some(task) => task,
+            None => return,
+        };
+        task.waker.queued.store(false, Ordering::Release);
+        let waker = new_waker(&task.waker);
+        let mut ctx = Context::from_waker(&waker);
+        task.stats.polls += 1;
+        let poll = {
+            let _task = task::enter(&mut task.context);
+            task.future.as_mut().poll(&mut ctx)
+        };
+        if let Poll::Ready(()) = poll {
+            if let Some(task) = self.tasks.remove(index) {
+                task.waker.done.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+impl Drop for SimExecutor {
+    fn drop(&mut self) {
+        // Futures hold the state, which holds their wakers. Drop them without the state borrowed.
+        let new_futures = mem::take(&mut self.state.borrow_mut().new_futures);
+        drop(new_futures);
+        for task in self.tasks.take_all() {
+            task.waker.done.store(true, Ordering::Release);
+            drop(task);
+        }
+    }
+}
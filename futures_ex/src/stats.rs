@@ -0,0 +1,118 @@
+//! Counters collected by `FdExecutor` when tracing is enabled.
+
+use std::fmt;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// What one task has cost so far.
+#[derive(Clone, Debug, Default)]
+pub struct TaskStats {
+    /// Identifies the task for as long as the executor lives, unlike its slot, which is reused.
+    pub id: u64,
+    /// Set with `task::named` or `task::set_name`.
+    pub name: Option<String>,
+    pub polls: u64,
+    /// Total time spent inside the task's `poll`.
+    pub poll_time: Duration,
+    pub longest_poll: Duration,
+    pub last_poll: Option<Instant>,
+}
+
+impl TaskStats {
+    pub(crate) fn new(id: u64) -> TaskStats {
+        TaskStats {
+            id,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn record_poll(&mut self, start: Instant, elapsed: Duration) {
+        self.polls += 1;
+        self.poll_time += elapsed;
+        self.longest_poll = self.longest_poll.max(elapsed);
+        self.last_poll = Some(start);
+    }
+}
+
+/// A snapshot of the executor's counters, returned by `FdExecutor::stats`.
+#[derive(Clone, Debug, Default)]
+pub struct ExecutorStats {
+    /// The tasks that haven't completed.
+    pub tasks: Vec<TaskStats>,
+    pub completed_tasks: u64,
+    /// Tasks dropped because they panicked, counted even with tracing off.
+    pub panicked_tasks: u64,
+    /// Polls of every task, including completed ones.
+    pub polls: u64,
+    /// Time spent blocked in `wait_wake_readable`.
+    pub blocked_time: Duration,
+    pub waits: u64,
+}
+
+/// Why a task isn't running, as listed by `FdExecutor::dump`.
+#[derive(Clone, Debug)]
+pub(crate) enum TaskWait {
+    /// Woken and waiting for its next poll.
+    Queued,
+    /// Waiting for fds, each with true if it waits for writable.
+    Fds(Vec<(RawFd, bool)>),
+    /// Not queued and not waiting on any fd the executor knows about. Something else, like a
+    /// channel or another thread, has to wake it, if nothing does it is stuck.
+    Other,
+}
+
+/// One line of the dump.
+pub(crate) struct TaskDump {
+    pub stats: TaskStats,
+    pub wait: TaskWait,
+}
+
+/// Formats the stats and what every task is waiting for, for debugging hangs.
+pub(crate) struct Dump {
+    pub stats: ExecutorStats,
+    pub tasks: Vec<TaskDump>,
+    pub now: Instant,
+}
+
+impl fmt::Display for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "FdExecutor: {} tasks, {} completed, {} panicked, {} polls, blocked {:?} in {} waits",
+            self.tasks.len(),
+            self.stats.completed_tasks,
+            self.stats.panicked_tasks,
+            self.stats.polls,
+            self.stats.blocked_time,
+            self.stats.waits
+        )?;
+        for task in &self.tasks {
+            let s = &task.stats;
+            write!(f, "  task {}", s.id)?;
+            if let Some(name) = &s.name {
+                write!(f, " ({})", name)?;
+            }
+            write!(
+                f,
+                ": {} polls, {:?} total, longest {:?}",
+                s.polls, s.poll_time, s.longest_poll
+            )?;
+            if let Some(last) = s.last_poll {
+                write!(f, ", last polled {:?} ago", self.now.duration_since(last))?;
+            }
+            match &task.wait {
+                TaskWait::Queued => writeln!(f, ", queued")?,
+                TaskWait::Fds(fds) => {
+                    write!(f, ", waiting on")?;
+                    for (fd, write) in fds {
+                        let interest = if *write { "write" } else { "read" };
+                        write!(f, " fd {} ({})", fd, interest)?;
+                    }
+                    writeln!(f)?;
+                }
+                TaskWait::Other => writeln!(f, ", not waiting on any fd")?,
+            }
+        }
+        Ok(())
+    }
+}
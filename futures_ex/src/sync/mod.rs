@@ -0,0 +1,90 @@
+//! Channels and synchronization primitives for tasks running on the `FdExecutor`.
+//!
+//! These are for tasks on a single executor thread and park by saving the task's `Waker`, the
+//! same way fd waits do. `ThreadNotify` is the exception, it uses an eventfd so that threads
+//! outside the executor can wake a task.
+
+pub mod mpsc;
+pub mod oneshot;
+
+mod mutex;
+mod notify;
+mod semaphore;
+mod thread_notify;
+
+pub use mutex::{Lock, Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit};
+pub use thread_notify::{ThreadNotified, ThreadNotify};
+
+use std::collections::VecDeque;
+use std::task::Waker;
+
+/// Tasks parked on a primitive, woken in the order they started waiting. A waiter that has been
+/// woken is no longer in the queue, which is how its future knows the wake was meant for it.
+pub(crate) struct WaitQueue {
+    waiters: VecDeque<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl WaitQueue {
+    pub(crate) fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds a waiter, or updates the waker of `id` if it is still queued. Returns the waiter's id.
+    pub(crate) fn register(&mut self, id: Option<u64>, waker: &Waker) -> u64 {
+        if let Some(id) = id {
+            if let Some(entry) = self.waiters.iter_mut().find(|(i, _)| *i == id) {
+                if !entry.1.will_wake(waker) {
+                    entry.1 = waker.clone();
+                }
+                return id;
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiters.push_back((id, waker.clone()));
+        id
+    }
+
+    /// Returns true if `id` is waiting and hasn't been woken.
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.waiters.iter().any(|(i, _)| *i == id)
+    }
+
+    /// Removes `id`, returning true if it was still waiting.
+    pub(crate) fn remove(&mut self, id: u64) -> bool {
+        match self.waiters.iter().position(|(i, _)| *i == id) {
+            Some(index) => {
+                self.waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes the longest waiting task. Returns false if nothing was waiting.
+    pub(crate) fn wake_one(&mut self) -> bool {
+        match self.waiters.pop_front() {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn wake_all(&mut self) {
+        for (_, waker) in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
@@ -0,0 +1,257 @@
+//! Multi-producer, single-consumer channels between tasks.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use super::WaitQueue;
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    // `None` for unbounded channels.
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_dropped: bool,
+    recv_waker: Option<Waker>,
+    // Senders waiting for room in a full channel.
+    send_waiters: WaitQueue,
+}
+
+impl<T> Shared<T> {
+    fn is_full(&self) -> bool {
+        matches!(self.capacity, Some(c) if self.queue.len() >= c)
+    }
+}
+
+/// Creates a channel that holds at most `capacity` values. `Sender::send` waits while it is full.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be non-zero");
+    new_channel(Some(capacity))
+}
+
+/// Creates a channel with no limit on the values it holds. `Sender::send` never waits.
+pub fn unbounded_channel<T>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None)
+}
+
+fn new_channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = Rc::new(RefCell::new(Shared {
+        queue: VecDeque::new(),
+        capacity,
+        senders: 1,
+        receiver_dropped: false,
+        recv_waker: None,
+        send_waiters: WaitQueue::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The receiver was dropped, the value that couldn't be sent is returned.
+#[derive(Debug, PartialEq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mpsc receiver dropped")
+    }
+}
+
+/// Errors from `Sender::try_send`.
+#[derive(Debug, PartialEq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// The receiver was dropped.
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "mpsc channel full"),
+            TrySendError::Closed(_) => write!(f, "mpsc receiver dropped"),
+        }
+    }
+}
+
+pub struct Sender<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting for room if the channel is full.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+            id: None,
+        }
+    }
+
+    /// Sends `value` if there is room for it right now.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.receiver_dropped {
+            return Err(TrySendError::Closed(value));
+        }
+        if shared.is_full() || !shared.send_waiters.is_empty() {
+            return Err(TrySendError::Full(value));
+        }
+        shared.queue.push_back(value);
+        let waker = shared.recv_waker.take();
+        drop(shared);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns true if the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.borrow().receiver_dropped
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.borrow_mut().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut shared = self.shared.borrow_mut();
+            shared.senders -= 1;
+            if shared.senders == 0 {
+                shared.recv_waker.take()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    id: Option<u64>,
+}
+
+// The value is only moved out, never pinned.
+impl<'a, T> Unpin for Send<'a, T> {}
+
+impl<'a, T> Future for Send<'a, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut shared = this.sender.shared.borrow_mut();
+        let value = this.value.take().expect("Send polled after completion");
+        if shared.receiver_dropped {
+            return Poll::Ready(Err(SendError(value)));
+        }
+        let my_turn = match this.id {
+            Some(id) => !shared.send_waiters.contains(id),
+            None => shared.send_waiters.is_empty(),
+        };
+        if my_turn && !shared.is_full() {
+            this.id = None;
+            shared.queue.push_back(value);
+            let waker = shared.recv_waker.take();
+            // There may be room for the next waiting sender too.
+            if !shared.is_full() {
+                shared.send_waiters.wake_one();
+            }
+            drop(shared);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        this.value = Some(value);
+        let id = if my_turn { None } else { this.id };
+        this.id = Some(shared.send_waiters.register(id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for Send<'a, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut shared = self.sender.shared.borrow_mut();
+            // If this sender was woken for a free slot, pass the wakeup on.
+            if !shared.send_waiters.remove(id) && !shared.is_full() {
+                shared.send_waiters.wake_one();
+            }
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value. Returns `None` once every sender is dropped and the channel is
+    /// empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Returns the next value if one is queued.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let mut shared = self.shared.borrow_mut();
+        let value = shared.queue.pop_front();
+        if value.is_some() {
+            shared.send_waiters.wake_one();
+        }
+        value
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.receiver_dropped = true;
+        shared.send_waiters.wake_all();
+    }
+}
+
+/// Future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Future for Recv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.receiver.shared.borrow_mut();
+        if let Some(value) = shared.queue.pop_front() {
+            shared.send_waiters.wake_one();
+            return Poll::Ready(Some(value));
+        }
+        if shared.senders == 0 {
+            return Poll::Ready(None);
+        }
+        shared.recv_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
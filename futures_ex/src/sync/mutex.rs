@@ -0,0 +1,119 @@
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::WaitQueue;
+
+/// A lock that can be held across `.await` points. Waiting tasks get the lock in the order they
+/// asked for it.
+pub struct Mutex<T> {
+    locked: Cell<bool>,
+    waiters: RefCell<WaitQueue>,
+    value: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: Cell::new(false),
+            waiters: RefCell::new(WaitQueue::new()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Waits for the lock and returns a guard that releases it when dropped.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            id: None,
+        }
+    }
+
+    /// Takes the lock if nobody holds it or is waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.locked.get() || !self.waiters.borrow().is_empty() {
+            return None;
+        }
+        self.locked.set(true);
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn unlock(&self) {
+        self.locked.set(false);
+        self.waiters.borrow_mut().wake_one();
+    }
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    id: Option<u64>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut waiters = mutex.waiters.borrow_mut();
+        // Tasks that got here first have priority unless this one was already woken for its turn.
+        let my_turn = match self.id {
+            Some(id) => !waiters.contains(id),
+            None => waiters.is_empty(),
+        };
+        if my_turn && !mutex.locked.get() {
+            mutex.locked.set(true);
+            self.id = None;
+            return Poll::Ready(MutexGuard { mutex });
+        }
+        // Woken but someone else took the lock in between, wait at the back again.
+        let id = if my_turn { None } else { self.id };
+        self.id = Some(waiters.register(id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for Lock<'a, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut waiters = self.mutex.waiters.borrow_mut();
+            // If this waiter was already woken, pass the wakeup on so it isn't lost.
+            if !waiters.remove(id) && !self.mutex.locked.get() {
+                waiters.wake_one();
+            }
+        }
+    }
+}
+
+/// Holds a `Mutex` locked, giving access to its value.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safe because the guard is the only way to reach the value while the lock is held.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safe because the guard is the only way to reach the value while the lock is held.
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,105 @@
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::WaitQueue;
+
+/// Wakes tasks waiting for something to happen without passing a value.
+///
+/// `notify_one` stores a single permit when nobody is waiting, so a task that calls `notified`
+/// right after the notification isn't left waiting for the next one.
+pub struct Notify {
+    permit: Cell<bool>,
+    waiters: RefCell<WaitQueue>,
+    // Counts calls to `notify_waiters` so a dropped waiter can tell which kind of wake it got.
+    generation: Cell<u64>,
+}
+
+impl Notify {
+    pub fn new() -> Notify {
+        Notify {
+            permit: Cell::new(false),
+            waiters: RefCell::new(WaitQueue::new()),
+            generation: Cell::new(0),
+        }
+    }
+
+    /// Waits for a call to `notify_one` or `notify_waiters`.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+            generation: 0,
+            done: false,
+        }
+    }
+
+    /// Wakes the task that has been waiting longest, or lets the next call to `notified` complete
+    /// right away if nothing is waiting.
+    pub fn notify_one(&self) {
+        if !self.waiters.borrow_mut().wake_one() {
+            self.permit.set(true);
+        }
+    }
+
+    /// Wakes every task that is currently waiting. Doesn't affect tasks that start waiting later.
+    pub fn notify_waiters(&self) {
+        self.generation.set(self.generation.get() + 1);
+        self.waiters.borrow_mut().wake_all();
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    id: Option<u64>,
+    // The value of `Notify::generation` when this started waiting.
+    generation: u64,
+    done: bool,
+}
+
+impl<'a> Future for Notified<'a> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let notify = self.notify;
+        let mut waiters = notify.waiters.borrow_mut();
+        match self.id {
+            Some(id) if !waiters.contains(id) => {
+                self.done = true;
+                return Poll::Ready(());
+            }
+            None if notify.permit.get() => {
+                notify.permit.set(false);
+                self.done = true;
+                return Poll::Ready(());
+            }
+            _ => (),
+        }
+        if self.id.is_none() {
+            self.generation = notify.generation.get();
+        }
+        self.id = Some(waiters.register(self.id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl<'a> Drop for Notified<'a> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let removed = self.notify.waiters.borrow_mut().remove(id);
+            // Woken by `notify_one` but never saw it, hand the notification to someone else.
+            let by_notify_one = self.generation == self.notify.generation.get();
+            if !removed && !self.done && by_notify_one {
+                self.notify.notify_one();
+            }
+        }
+    }
+}
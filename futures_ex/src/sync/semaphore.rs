@@ -0,0 +1,111 @@
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::WaitQueue;
+
+/// Limits how many tasks can be doing something at once, for example how many requests are in
+/// flight to a disk.
+pub struct Semaphore {
+    permits: Cell<usize>,
+    waiters: RefCell<WaitQueue>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Cell::new(permits),
+            waiters: RefCell::new(WaitQueue::new()),
+        }
+    }
+
+    /// Waits for a permit, which is returned when the `SemaphorePermit` is dropped.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            id: None,
+        }
+    }
+
+    /// Takes a permit if one is available and nobody is waiting for it.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        if self.permits.get() == 0 || !self.waiters.borrow().is_empty() {
+            return None;
+        }
+        self.permits.set(self.permits.get() - 1);
+        Some(SemaphorePermit { semaphore: self })
+    }
+
+    /// Adds `count` permits, waking tasks waiting for them.
+    pub fn add_permits(&self, count: usize) {
+        self.permits.set(self.permits.get() + count);
+        let mut waiters = self.waiters.borrow_mut();
+        for _ in 0..count {
+            if !waiters.wake_one() {
+                break;
+            }
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.get()
+    }
+}
+
+/// Future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let mut waiters = semaphore.waiters.borrow_mut();
+        let my_turn = match self.id {
+            Some(id) => !waiters.contains(id),
+            None => waiters.is_empty(),
+        };
+        if my_turn && semaphore.permits.get() > 0 {
+            semaphore.permits.set(semaphore.permits.get() - 1);
+            self.id = None;
+            return Poll::Ready(SemaphorePermit { semaphore });
+        }
+        let id = if my_turn { None } else { self.id };
+        self.id = Some(waiters.register(id, cx.waker()));
+        Poll::Pending
+    }
+}
+
+impl<'a> Drop for Acquire<'a> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut waiters = self.semaphore.waiters.borrow_mut();
+            // If this waiter was already woken, pass the wakeup on so it isn't lost.
+            if !waiters.remove(id) && self.semaphore.permits.get() > 0 {
+                waiters.wake_one();
+            }
+        }
+    }
+}
+
+/// A permit from a `Semaphore`, given back when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Keeps the permit taken, reducing the number of permits in the semaphore for good.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
@@ -0,0 +1,65 @@
+use std::future::Future;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use sys_util::EventFd;
+
+use crate::reactor::{self, Registration};
+
+/// Lets threads outside the executor wake a task. `notify` can be called from any thread, the
+/// task waits with `notified`, which watches an eventfd through the executor's poll context.
+/// Notifications that arrive while nobody is waiting are kept until the next wait.
+pub struct ThreadNotify {
+    event: EventFd,
+}
+
+impl ThreadNotify {
+    pub fn new() -> io::Result<ThreadNotify> {
+        let event = EventFd::new()?;
+        // `notified` reads the eventfd after every wake, a spurious one must not block.
+        // Safe because the fd is owned by `event` and F_SETFL doesn't touch memory.
+        let ret = unsafe { libc::fcntl(event.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ThreadNotify { event })
+    }
+
+    /// Wakes the task waiting in `notified`, or the next one to wait.
+    pub fn notify(&self) {
+        self.event.write(1).unwrap();
+    }
+
+    /// Waits for `notify`. Resolves to the number of notifications since the last wait. Must be
+    /// polled from a task on an `FdExecutor`.
+    pub fn notified(&self) -> ThreadNotified<'_> {
+        ThreadNotified {
+            notify: self,
+            registration: Registration::new(),
+        }
+    }
+}
+
+/// Future returned by `ThreadNotify::notified`.
+pub struct ThreadNotified<'a> {
+    notify: &'a ThreadNotify,
+    registration: Registration,
+}
+
+impl<'a> Future for ThreadNotified<'a> {
+    type Output = reactor::Result<u64>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Ok(count) = self.notify.event.read() {
+            self.registration.clear();
+            return Poll::Ready(Ok(count));
+        }
+        let notify = self.notify;
+        match self.registration.register(&notify.event, false, cx.waker()) {
+            Ok(()) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Task names and task-local values.
+//!
+//! Each task spawned on an `FdExecutor` has a context that the executor makes current while it
+//! polls the task, so any future nested inside it can read the task's name or the values stored
+//! with `set_local`, like the id of the device a worker serves. Names show up in `stats`, `dump`
+//! and in the `TaskPanic` a panicking task is reported with.
+//!
+//! A panicking task doesn't take the executor down. It is dropped and the panic is handed to the
+//! `JoinHandle` returned by `spawn`, or to the executor's panic hook if nobody is joining it.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use crate::reactor;
+
+thread_local! {
+    static CURRENT: RefCell<Option<TaskContext>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Called outside of a task being polled by an executor.
+    NoTask,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoTask => write!(f, "not called from a task"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The name and locals of one task, owned by the task and lent to the thread while it's polled.
+#[derive(Default)]
+pub(crate) struct TaskContext {
+    pub(crate) id: u64,
+    pub(crate) name: Option<String>,
+    locals: HashMap<TypeId, Box<dyn Any>>,
+}
+
+impl TaskContext {
+    pub(crate) fn new(id: u64) -> TaskContext {
+        TaskContext {
+            id,
+            ..Default::default()
+        }
+    }
+}
+
+/// Gives the context back to its task when dropped, also when the poll panics.
+pub(crate) struct TaskGuard<'a> {
+    context: &'a mut TaskContext,
+    previous: Option<TaskContext>,
+}
+
+impl<'a> Drop for TaskGuard<'a> {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        if let Some(context) = CURRENT.with(|c| mem::replace(&mut *c.borrow_mut(), previous)) {
+            *self.context = context;
+        }
+    }
+}
+
+/// Makes `context` current until the returned guard is dropped.
+pub(crate) fn enter(context: &mut TaskContext) -> TaskGuard<'_> {
+    let previous = CURRENT.with(|c| c.borrow_mut().replace(mem::take(context)));
+    TaskGuard { context, previous }
+}
+
+/// Returns the name of the task being polled, if it has one.
+pub fn name() -> Option<String> {
+    CURRENT.with(|c| c.borrow().as_ref().and_then(|t| t.name.clone()))
+}
+
+/// Returns the executor's id for the task being polled.
+pub fn id() -> Result<u64> {
+    CURRENT.with(|c| c.borrow().as_ref().map(|t| t.id).ok_or(Error::NoTask))
+}
+
+/// Names the task being polled.
+pub fn set_name<S: Into<String>>(name: S) -> Result<()> {
+    CURRENT.with(|c| match c.borrow_mut().as_mut() {
+        Some(t) => {
+            t.name = Some(name.into());
+            Ok(())
+        }
+        None => Err(Error::NoTask),
+    })
+}
+
+/// Stores `value` in the task being polled, replacing any earlier value of the same type.
+pub fn set_local<T: 'static>(value: T) -> Result<()> {
+    CURRENT.with(|c| match c.borrow_mut().as_mut() {
+        Some(t) => {
+            t.locals.insert(TypeId::of::<T>(), Box::new(value));
+            Ok(())
+        }
+        None => Err(Error::NoTask),
+    })
+}
+
+/// Calls `f` with the task's value of type `T`. Returns `None` if not in a task or the task
+/// hasn't stored a `T`. `f` must not set names or locals itself.
+pub fn with_local<T: 'static, R, F: FnOnce(&T) -> R>(f: F) -> Option<R> {
+    CURRENT.with(|c| {
+        let current = c.borrow();
+        let value = current.as_ref()?.locals.get(&TypeId::of::<T>())?;
+        value.downcast_ref::<T>().map(f)
+    })
+}
+
+/// Returns a clone of the task's value of type `T`.
+pub fn local<T: Clone + 'static>() -> Option<T> {
+    with_local(T::clone)
+}
+
+/// Future returned by `named`.
+pub struct Named<F> {
+    name: Option<String>,
+    future: Pin<Box<F>>,
+}
+
+/// Wraps `future` so the task it's spawned as is called `name`.
+pub fn named<S: Into<String>, F: Future>(name: S, future: F) -> Named<F> {
+    Named {
+        name: Some(name.into()),
+        future: Box::pin(future),
+    }
+}
+
+impl<F: Future> Future for Named<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(name) = self.name.take() {
+            // Outside of a task there is nothing to name, still run the future.
+            let _ = set_name(name);
+        }
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// A panic caught while polling a task.
+#[derive(Debug)]
+pub struct TaskPanic {
+    pub id: u64,
+    pub name: Option<String>,
+    /// The value passed to `panic!`, as `std::panic::catch_unwind` returns it.
+    pub payload: Box<dyn Any + Send>,
+}
+
+impl TaskPanic {
+    /// Builds the panic for the task being polled.
+    pub(crate) fn current(payload: Box<dyn Any + Send>) -> TaskPanic {
+        TaskPanic {
+            id: id().unwrap_or_default(),
+            name: name(),
+            payload,
+        }
+    }
+
+    /// Returns the panic message if the payload is a string, as it is for `panic!` with a message.
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&str>() {
+            Some(s) => Some(s),
+            None => self.payload.downcast_ref::<String>().map(String::as_str),
+        }
+    }
+}
+
+impl fmt::Display for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task {}", self.id)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({})", name)?;
+        }
+        write!(f, " panicked")?;
+        if let Some(message) = self.message() {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TaskPanic {}
+
+struct JoinState<T> {
+    output: Option<std::result::Result<T, TaskPanic>>,
+    waker: Option<Waker>,
+    detached: bool,
+}
+
+/// Resolves to the output of a task started with `spawn`, or to its panic. Dropping the handle
+/// detaches the task, its panic then goes to the executor's panic hook.
+pub struct JoinHandle<T> {
+    state: Rc<RefCell<JoinState<T>>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = std::result::Result<T, TaskPanic>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.borrow_mut();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        self.state.borrow_mut().detached = true;
+    }
+}
+
+// Polls the inner future, catching a panic instead of letting it unwind into the executor.
+struct CatchUnwind<F: Future> {
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = std::result::Result<F::Output, TaskPanic>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let future = self.future.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(TaskPanic::current(payload))),
+        }
+    }
+}
+
+/// Adds `future` as a task on the executor running on this thread and returns a handle to
+/// await its output.
+pub fn spawn<F>(future: F) -> reactor::Result<JoinHandle<F::Output>>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let state = Rc::new(RefCell::new(JoinState {
+        output: None,
+        waker: None,
+        detached: false,
+    }));
+    let task_state = state.clone();
+    reactor::spawn(Box::pin(async move {
+        let output = CatchUnwind {
+            future: Box::pin(future),
+        }
+        .await;
+        let mut state = task_state.borrow_mut();
+        if state.detached {
+            if let Err(p) = output {
+                drop(state);
+                panic::resume_unwind(p.payload);
+            }
+            return;
+        }
+        state.output = Some(output);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }))?;
+    Ok(JoinHandle { state })
+}
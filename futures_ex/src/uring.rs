@@ -0,0 +1,343 @@
+//! Completion based file I/O for the `FdExecutor`.
+//!
+//! Readiness from epoll says nothing useful about regular files, they always poll as readable and
+//! then block in the read. When the kernel supports it, `InterfaceState` owns an io_uring and the
+//! futures here submit their reads, writes and fsyncs to it. The ring signals an eventfd that sits
+//! in the same `PollContext` as every other fd, so `wait_wake_readable` wakes the futures whose
+//! operations completed. Without io_uring the operations are issued directly, waiting for
+//! readiness first when the fd is non-blocking.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use sys_util::EventFd;
+
+use crate::executor::{FdExecutorInterface, InterfaceState, SavedFd, WakerToken};
+use crate::reactor;
+
+const RING_ENTRIES: u32 = 256;
+
+struct PendingOp {
+    // The buffer the kernel reads from or writes to, kept here until the operation completes.
+    buf: Vec<u8>,
+    result: Option<i32>,
+    waker: Option<Waker>,
+    // The future waiting for this operation was dropped, free it once it completes.
+    abandoned: bool,
+}
+
+/// An io_uring and the operations that have been submitted to it.
+pub(crate) struct UringContext {
+    ring: IoUring,
+    eventfd: EventFd,
+    ops: HashMap<u64, PendingOp>,
+    next_token: u64,
+}
+
+impl UringContext {
+    /// Creates a ring that signals its eventfd each time an operation completes.
+    pub(crate) fn new() -> io::Result<UringContext> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let eventfd = EventFd::new()?;
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+        Ok(UringContext {
+            ring,
+            eventfd,
+            ops: HashMap::new(),
+            next_token: 0,
+        })
+    }
+
+    /// The eventfd that becomes readable when completions are available.
+    pub(crate) fn eventfd(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    /// Passes any queued submissions to the kernel.
+    pub(crate) fn submit(&mut self) -> io::Result<()> {
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Collects completed operations and wakes the futures waiting on them.
+    pub(crate) fn reap(&mut self) {
+        // Clear the eventfd before looking at the queue so a completion that lands in between
+        // signals it again.
+        let _ = self.eventfd.read();
+        for cqe in self.ring.completion() {
+            let token = cqe.user_data();
+            let abandoned = match self.ops.get_mut(&token) {
+                Some(op) => {
+                    op.result = Some(cqe.result());
+                    if let Some(waker) = op.waker.take() {
+                        waker.wake();
+                    }
+                    op.abandoned
+                }
+                None => false,
+            };
+            if abandoned {
+                self.ops.remove(&token);
+            }
+        }
+    }
+
+    fn queue(&mut self, entry: squeue::Entry, buf: Vec<u8>) -> io::Result<u64> {
+        while self.ops.contains_key(&self.next_token) {
+            self.next_token = self.next_token.wrapping_add(1);
+        }
+        let token = self.next_token;
+        let entry = entry.user_data(token);
+        // Safe because the buffer the entry points at is kept in `ops` until the kernel posts its
+        // completion.
+        if unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit()?;
+            unsafe { self.ring.submission().push(&entry) }
+                .map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))?;
+        }
+        self.ops.insert(
+            token,
+            PendingOp {
+                buf,
+                result: None,
+                waker: None,
+                abandoned: false,
+            },
+        );
+        Ok(token)
+    }
+
+    fn queue_op(
+        &mut self,
+        fd: RawFd,
+        offset: u64,
+        kind: OpKind,
+        mut buf: Vec<u8>,
+    ) -> io::Result<u64> {
+        let entry = match kind {
+            OpKind::Read => opcode::Read::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32)
+                .offset(offset)
+                .build(),
+            OpKind::Write => opcode::Write::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+                .offset(offset)
+                .build(),
+            OpKind::Fsync => opcode::Fsync::new(types::Fd(fd)).build(),
+        };
+        self.queue(entry, buf)
+    }
+
+    fn poll_op(&mut self, token: u64, waker: &Waker) -> Poll<(i32, Vec<u8>)> {
+        let op = match self.ops.get_mut(&token) {
+            Some(op) => op,
+            None => return Poll::Ready((-libc::ECANCELED, Vec::new())),
+        };
+        match op.result {
+            Some(result) => {
+                let op = self.ops.remove(&token).unwrap();
+                Poll::Ready((result, op.buf))
+            }
+            None => {
+                op.waker = Some(waker.clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn abandon_op(&mut self, token: u64) {
+        let done = match self.ops.get_mut(&token) {
+            Some(op) => {
+                op.abandoned = true;
+                op.waker = None;
+                op.result.is_some()
+            }
+            None => false,
+        };
+        if done {
+            self.ops.remove(&token);
+        }
+    }
+}
+
+impl Drop for UringContext {
+    fn drop(&mut self) {
+        // The kernel might still write to the buffers of operations that haven't completed after
+        // the ring is closed, leak them rather than free memory that is in use.
+        for (_, op) in self.ops.drain() {
+            if op.result.is_none() {
+                mem::forget(op.buf);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum OpKind {
+    Read,
+    Write,
+    Fsync,
+}
+
+enum OpStage {
+    Idle(Vec<u8>),
+    Submitted(u64),
+    Done,
+}
+
+/// A read, write or fsync started by `read_at`, `write_at` or `fsync`. Resolves to the number of
+/// bytes transferred and the buffer that was passed in.
+pub struct IoOp {
+    // The reactor the operation was started on, found when first polled.
+    state: Option<Arc<RefCell<InterfaceState>>>,
+    fd: RawFd,
+    offset: u64,
+    kind: OpKind,
+    stage: OpStage,
+    // Set while waiting for a non-blocking fd to become ready without a ring.
+    waker_token: Option<WakerToken>,
+}
+
+impl IoOp {
+    fn new(fd: &dyn AsRawFd, offset: u64, kind: OpKind, buf: Vec<u8>) -> IoOp {
+        IoOp {
+            state: None,
+            fd: fd.as_raw_fd(),
+            offset,
+            kind,
+            stage: OpStage::Idle(buf),
+            waker_token: None,
+        }
+    }
+
+    // Issues the operation directly, for when there is no ring.
+    fn run_sync(&self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        // Safe because the pointers and lengths come from `buf`, which outlives the calls.
+        let ret = unsafe {
+            match self.kind {
+                OpKind::Read => libc::pread64(
+                    self.fd,
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    self.offset as libc::off64_t,
+                ),
+                OpKind::Write => libc::pwrite64(
+                    self.fd,
+                    buf.as_ptr() as *const libc::c_void,
+                    buf.len(),
+                    self.offset as libc::off64_t,
+                ),
+                OpKind::Fsync => libc::fsync(self.fd) as isize,
+            }
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+}
+
+impl Future for IoOp {
+    type Output = io::Result<(usize, Vec<u8>)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let state = match &self.state {
+            Some(state) => state.clone(),
+            None => {
+                let state = reactor::current()?;
+                self.state = Some(state.clone());
+                state
+            }
+        };
+        let mut state = state.borrow_mut();
+
+        if let OpStage::Idle(_) = self.stage {
+            let buf = match mem::replace(&mut self.stage, OpStage::Done) {
+                OpStage::Idle(buf) => buf,
+                _ => unreachable!(),
+            };
+            match state.uring_mut() {
+                Some(uring) => {
+                    let token = uring.queue_op(self.fd, self.offset, self.kind, buf)?;
+                    self.stage = OpStage::Submitted(token);
+                }
+                None => {
+                    let mut buf = buf;
+                    return match self.run_sync(&mut buf) {
+                        Ok(count) => Poll::Ready(Ok((count, buf))),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            let fd = SavedFd(self.fd);
+                            let waker = cx.waker().clone();
+                            let write = matches!(self.kind, OpKind::Write);
+                            let old = self.waker_token.take();
+                            self.waker_token = Some(state.replace_waker(old, &fd, write, waker));
+                            self.stage = OpStage::Idle(buf);
+                            Poll::Pending
+                        }
+                        Err(e) => Poll::Ready(Err(e)),
+                    };
+                }
+            }
+        }
+
+        let token = match self.stage {
+            OpStage::Submitted(token) => token,
+            _ => panic!("IoOp polled after completion"),
+        };
+        let uring = state
+            .uring_mut()
+            .expect("operation submitted without a ring");
+        match uring.poll_op(token, cx.waker()) {
+            Poll::Ready((result, buf)) => {
+                self.stage = OpStage::Done;
+                if result < 0 {
+                    Poll::Ready(Err(io::Error::from_raw_os_error(-result)))
+                } else {
+                    Poll::Ready(Ok((result as usize, buf)))
+                }
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for IoOp {
+    fn drop(&mut self) {
+        let mut state = match &self.state {
+            Some(state) => state.borrow_mut(),
+            None => return,
+        };
+        if let Some(token) = self.waker_token.take() {
+            state.remove_waker(token);
+        }
+        if let OpStage::Submitted(token) = self.stage {
+            if let Some(uring) = state.uring_mut() {
+                uring.abandon_op(token);
+            }
+        }
+    }
+}
+
+/// Reads from `fd` at `offset` into `buf`. `fd` must stay open until the returned future resolves.
+/// Must be polled from a task on an `FdExecutor`.
+pub fn read_at(fd: &dyn AsRawFd, offset: u64, buf: Vec<u8>) -> IoOp {
+    IoOp::new(fd, offset, OpKind::Read, buf)
+}
+
+/// Writes `buf` to `fd` at `offset`. `fd` must stay open until the returned future resolves.
+/// Must be polled from a task on an `FdExecutor`.
+pub fn write_at(fd: &dyn AsRawFd, offset: u64, buf: Vec<u8>) -> IoOp {
+    IoOp::new(fd, offset, OpKind::Write, buf)
+}
+
+/// Flushes the data and metadata of `fd` to disk. `fd` must stay open until the returned future
+/// resolves. Must be polled from a task on an `FdExecutor`.
+pub fn fsync(fd: &dyn AsRawFd) -> IoOp {
+    IoOp::new(fd, 0, OpKind::Fsync, Vec::new())
+}
@@ -0,0 +1,173 @@
+// Helpers shared by the integration tests for driving the executor with pipes and sockets.
+
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use futures_ex::{FdExecutorInterface, InterfaceState, WakerToken};
+
+pub struct PipeFd(pub RawFd);
+
+impl AsRawFd for PipeFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for PipeFd {
+    fn drop(&mut self) {
+        // Safe because this struct owns the fd.
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+pub fn socketpair() -> (PipeFd, PipeFd) {
+    let mut fds = [0; 2];
+    // Safe because `fds` has room for the two fds socketpair fills in.
+    let ret = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    assert_eq!(ret, 0);
+    (PipeFd(fds[0]), PipeFd(fds[1]))
+}
+
+pub fn pipe() -> (PipeFd, PipeFd) {
+    let mut fds = [0; 2];
+    // Safe because `fds` has room for the two fds pipe fills in.
+    let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) };
+    assert_eq!(ret, 0);
+    (PipeFd(fds[0]), PipeFd(fds[1]))
+}
+
+pub fn write_byte(fd: &PipeFd, b: u8) {
+    // Safe because `b` is a valid one byte buffer.
+    let ret = unsafe { libc::write(fd.0, &b as *const u8 as *const _, 1) };
+    assert_eq!(ret, 1);
+}
+
+// Resolves to the next byte read from `fd`, waiting for it to become readable if needed.
+pub struct ReadByte<'a> {
+    fd: &'a PipeFd,
+    state: Arc<RefCell<InterfaceState>>,
+    token: Option<WakerToken>,
+}
+
+impl<'a> Drop for ReadByte<'a> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            self.state.borrow_mut().remove_waker(token);
+        }
+    }
+}
+
+impl<'a> Future for ReadByte<'a> {
+    type Output = u8;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut b = 0u8;
+        // Safe because `b` is a valid one byte buffer.
+        let ret = unsafe { libc::read(self.fd.0, &mut b as *mut u8 as *mut _, 1) };
+        if ret == 1 {
+            return Poll::Ready(b);
+        }
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EAGAIN)
+        );
+        let token = self
+            .state
+            .borrow_mut()
+            .add_waker(self.fd, cx.waker().clone());
+        if let Some(old_token) = self.token.replace(token) {
+            self.state.borrow_mut().remove_waker(old_token);
+        }
+        Poll::Pending
+    }
+}
+
+pub fn read_byte(fd: &PipeFd, state: Arc<RefCell<InterfaceState>>) -> ReadByte<'_> {
+    ReadByte {
+        fd,
+        state,
+        token: None,
+    }
+}
+
+// Waits for one wakeup from `fd` becoming writable.
+pub struct WaitWritable<'a> {
+    pub fd: &'a PipeFd,
+    pub state: Arc<RefCell<InterfaceState>>,
+    pub registered: bool,
+}
+
+impl<'a> Future for WaitWritable<'a> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.registered {
+            return Poll::Ready(());
+        }
+        self.state
+            .borrow_mut()
+            .add_write_waker(self.fd, cx.waker().clone());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+// Polls the wrapped future once and then drops it.
+pub struct PollOnce<F: Future>(pub Option<Pin<Box<F>>>);
+
+impl<F: Future> Future for PollOnce<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(mut inner) = self.0.take() {
+            let _ = inner.as_mut().poll(cx);
+        }
+        Poll::Ready(())
+    }
+}
+
+// Lets the other ready tasks run before continuing.
+pub struct YieldNow(pub bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pub fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+// A waker that does nothing, for polling futures by hand outside of an executor.
+pub fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &NOOP_VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    // Safe because the vtable functions ignore the data pointer.
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &NOOP_VTABLE)) }
+}
@@ -0,0 +1,295 @@
+// Drives `FdExecutor` with pipes so the wake paths can be tested without stdin.
+
+mod common;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use common::*;
+use futures_ex::{FdExecutor, FdExecutorInterface, InterfaceState, WakerToken};
+
+#[test]
+fn no_futures() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    FdExecutor::new(Vec::new(), state).run();
+}
+
+#[test]
+fn ready_future() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let ran = Rc::new(RefCell::new(false));
+    let ran_clone = ran.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        *ran_clone.borrow_mut() = true;
+    })];
+    FdExecutor::new(futures, state).run();
+    assert!(*ran.borrow());
+}
+
+#[test]
+fn read_from_pipe() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, tx) = pipe();
+    let result = Rc::new(RefCell::new(None));
+
+    let result_clone = result.clone();
+    let state_clone = state.clone();
+    let reader = async move {
+        let b = read_byte(&rx, state_clone).await;
+        *result_clone.borrow_mut() = Some(b);
+    };
+    let writer = async move {
+        write_byte(&tx, 7);
+    };
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(reader), Box::pin(writer)];
+    FdExecutor::new(futures, state).run();
+    assert_eq!(*result.borrow(), Some(7));
+}
+
+#[test]
+fn ping_pong() {
+    const ROUNDS: u8 = 20;
+
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (ping_rx, ping_tx) = pipe();
+    let (pong_rx, pong_tx) = pipe();
+
+    let state_clone = state.clone();
+    let pinger = async move {
+        for i in 0..ROUNDS {
+            write_byte(&ping_tx, i);
+            assert_eq!(read_byte(&pong_rx, state_clone.clone()).await, i);
+        }
+    };
+    let state_clone = state.clone();
+    let ponger = async move {
+        for _ in 0..ROUNDS {
+            let b = read_byte(&ping_rx, state_clone.clone()).await;
+            write_byte(&pong_tx, b);
+        }
+    };
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(pinger), Box::pin(ponger)];
+    FdExecutor::new(futures, state).run();
+}
+
+#[test]
+fn many_pipes_woken_in_reverse() {
+    const COUNT: usize = 50;
+
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let finished = Rc::new(RefCell::new(Vec::new()));
+    let mut writers = Vec::new();
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for i in 0..COUNT {
+        let (rx, tx) = pipe();
+        writers.push(tx);
+        let state_clone = state.clone();
+        let finished_clone = finished.clone();
+        futures.push(Box::pin(async move {
+            read_byte(&rx, state_clone).await;
+            finished_clone.borrow_mut().push(i);
+        }));
+    }
+    futures.push(Box::pin(async move {
+        for tx in writers.iter().rev() {
+            write_byte(tx, 0);
+        }
+    }));
+    FdExecutor::new(futures, state).run();
+
+    let mut finished = finished.borrow().clone();
+    finished.sort();
+    assert_eq!(finished, (0..COUNT).collect::<Vec<_>>());
+}
+
+#[test]
+fn add_future_from_task() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, tx) = pipe();
+    let result = Rc::new(RefCell::new(None));
+
+    let result_clone = result.clone();
+    let state_clone = state.clone();
+    let parent = async move {
+        let child_state = state_clone.clone();
+        state_clone.borrow_mut().add_future(Box::pin(async move {
+            let b = read_byte(&rx, child_state).await;
+            *result_clone.borrow_mut() = Some(b);
+        }));
+        write_byte(&tx, 3);
+    };
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(parent)];
+    FdExecutor::new(futures, state).run();
+    assert_eq!(*result.borrow(), Some(3));
+}
+
+#[test]
+fn multiple_readers_one_fd() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, tx) = pipe();
+    let rx = Rc::new(rx);
+    let results = Rc::new(RefCell::new(Vec::new()));
+
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for _ in 0..2 {
+        let rx = rx.clone();
+        let state_clone = state.clone();
+        let results_clone = results.clone();
+        futures.push(Box::pin(async move {
+            let b = read_byte(&rx, state_clone).await;
+            results_clone.borrow_mut().push(b);
+        }));
+    }
+    // Tasks are first polled in order, so both readers are waiting before this writes.
+    futures.push(Box::pin(async move {
+        write_byte(&tx, 1);
+        write_byte(&tx, 2);
+    }));
+    FdExecutor::new(futures, state.clone()).run();
+
+    let mut results = results.borrow().clone();
+    results.sort();
+    assert_eq!(results, vec![1, 2]);
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn repoll_before_ready() {
+    // Wakes itself a few times before the fd is written, registering the same fd each poll.
+    struct Repoll<'a> {
+        fd: &'a PipeFd,
+        state: Arc<RefCell<InterfaceState>>,
+        token: Option<WakerToken>,
+        polls: usize,
+    }
+
+    impl<'a> Future for Repoll<'a> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let mut b = 0u8;
+            // Safe because `b` is a valid one byte buffer.
+            let ret = unsafe { libc::read(self.fd.0, &mut b as *mut u8 as *mut _, 1) };
+            if ret == 1 {
+                return Poll::Ready(());
+            }
+            let token = self
+                .state
+                .borrow_mut()
+                .add_waker(self.fd, cx.waker().clone());
+            if let Some(old_token) = self.token.replace(token) {
+                self.state.borrow_mut().remove_waker(old_token);
+            }
+            assert_eq!(self.state.borrow().waker_count(), 1);
+            self.polls += 1;
+            if self.polls < 3 {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, tx) = pipe();
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        Repoll {
+            fd: &rx,
+            state: state_clone,
+            token: None,
+            polls: 0,
+        }
+        .await;
+    })];
+    write_byte(&tx, 0);
+    FdExecutor::new(futures, state).run();
+}
+
+#[test]
+fn dropping_one_of_joined_readers() {
+    // Polls both readers once from the same task, the way a join would.
+    struct PollBoth<'a, A: Future, B: Future>(Pin<&'a mut A>, Pin<&'a mut B>);
+
+    impl<'a, A: Future, B: Future> Future for PollBoth<'a, A, B> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            assert!(self.0.as_mut().poll(cx).is_pending());
+            assert!(self.1.as_mut().poll(cx).is_pending());
+            Poll::Ready(())
+        }
+    }
+
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, tx) = pipe();
+    let result = Rc::new(RefCell::new(None));
+    let result_clone = result.clone();
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![
+        Box::pin(async move {
+            let mut first = Box::pin(read_byte(&rx, state_clone.clone()));
+            let mut second = Box::pin(read_byte(&rx, state_clone.clone()));
+            PollBoth(first.as_mut(), second.as_mut()).await;
+            assert_eq!(state_clone.borrow().waker_count(), 2);
+            // The remaining reader still has to be woken.
+            drop(first);
+            assert_eq!(state_clone.borrow().waker_count(), 1);
+            *result_clone.borrow_mut() = Some(second.await);
+        }),
+        Box::pin(async move {
+            yield_now().await;
+            write_byte(&tx, 4);
+        }),
+    ];
+    FdExecutor::new(futures, state.clone()).run();
+    assert_eq!(*result.borrow(), Some(4));
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn dropped_future_unregisters() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, _tx) = pipe();
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        PollOnce(Some(Box::pin(read_byte(&rx, state_clone.clone())))).await;
+        assert_eq!(state_clone.borrow().waker_count(), 0);
+        // Registering the fd again after it was removed works.
+        PollOnce(Some(Box::pin(read_byte(&rx, state_clone.clone())))).await;
+    })];
+    FdExecutor::new(futures, state.clone()).run();
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn read_and_write_interest_same_fd() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (local, remote) = socketpair();
+    let local = Rc::new(local);
+    let result = Rc::new(RefCell::new(None));
+
+    let local_clone = local.clone();
+    let state_clone = state.clone();
+    let result_clone = result.clone();
+    let reader = async move {
+        let b = read_byte(&local_clone, state_clone).await;
+        *result_clone.borrow_mut() = Some(b);
+    };
+    let state_clone = state.clone();
+    let writer = async move {
+        WaitWritable {
+            fd: &local,
+            state: state_clone,
+            registered: false,
+        }
+        .await;
+        write_byte(&remote, 9);
+    };
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(reader), Box::pin(writer)];
+    FdExecutor::new(futures, state).run();
+    assert_eq!(*result.borrow(), Some(9));
+}
@@ -0,0 +1,162 @@
+// Unix and TCP sockets driven by the executor, over socketpairs and loopback.
+
+mod common;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use common::*;
+use futures_ex::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use futures_ex::{FdExecutor, InterfaceState};
+
+fn run(futures: Vec<Pin<Box<dyn Future<Output = ()>>>>) {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    FdExecutor::new(futures, state.clone()).run();
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn unix_pair_large_transfer() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let data: Vec<u8> = (0..500_000).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    let writer = async move {
+        a.write_all(&data).await.unwrap();
+        a.shutdown(Shutdown::Write).unwrap();
+    };
+    let received_clone = received.clone();
+    let reader = async move {
+        let mut buf = [0u8; 8192];
+        loop {
+            let count = b.read(&mut buf).await.unwrap();
+            if count == 0 {
+                break;
+            }
+            received_clone.borrow_mut().extend_from_slice(&buf[..count]);
+        }
+    };
+    run(vec![Box::pin(reader), Box::pin(writer)]);
+
+    assert!(*received.borrow() == expected);
+}
+
+#[test]
+fn unix_listener_accepts() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("control.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+
+    let server = async move {
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            request.reverse();
+            stream.write_all(&request).await.unwrap();
+        }
+    };
+    let client = async move {
+        for request in &[b"ping", b"stop"] {
+            let stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(*request).await.unwrap();
+            let mut reply = [0u8; 4];
+            stream.read_exact(&mut reply).await.unwrap();
+            let mut expected = **request;
+            expected.reverse();
+            assert_eq!(reply, expected);
+        }
+    };
+    run(vec![Box::pin(server), Box::pin(client)]);
+}
+
+#[test]
+fn pass_fds() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let (pipe_rx, pipe_tx) = pipe();
+
+    let pipe_tx_fd = pipe_tx.0;
+    let sender = async move {
+        a.send_with_fds(b"fd", &[pipe_tx_fd]).await.unwrap();
+        drop(pipe_tx);
+    };
+    let receiver = async move {
+        let mut buf = [0u8; 2];
+        let mut fds = [-1 as RawFd; 2];
+        let (count, fd_count) = b.recv_with_fds(&mut buf, &mut fds).await.unwrap();
+        assert_eq!(&buf[..count], b"fd");
+        assert_eq!(fd_count, 1);
+        // The received fd is a new copy of the pipe's write end.
+        let received = PipeFd(fds[0]);
+        write_byte(&received, 7);
+        let mut b = 0u8;
+        // Safe because `b` is a valid one byte buffer.
+        let ret = unsafe { libc::read(pipe_rx.0, &mut b as *mut u8 as *mut _, 1) };
+        assert_eq!(ret, 1);
+        assert_eq!(b, 7);
+    };
+    run(vec![Box::pin(receiver), Box::pin(sender)]);
+}
+
+#[test]
+fn tcp_loopback_echo() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let data: Vec<u8> = (0..300_000).map(|i| (i % 253) as u8).collect();
+    let expected = data.clone();
+    let echoed = Rc::new(RefCell::new(Vec::new()));
+
+    let server = async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        loop {
+            let count = stream.read(&mut buf).await.unwrap();
+            if count == 0 {
+                break;
+            }
+            stream.write_all(&buf[..count]).await.unwrap();
+        }
+    };
+    let echoed_clone = echoed.clone();
+    let client = async move {
+        let stream = Rc::new(TcpStream::connect(addr).await.unwrap());
+        stream.set_nodelay(true).unwrap();
+        // Write from a separate task so neither side stalls on a full socket buffer.
+        let writer = stream.clone();
+        futures_ex::reactor::spawn(Box::pin(async move {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        }))
+        .unwrap();
+        let mut buf = [0u8; 4096];
+        loop {
+            let count = stream.read(&mut buf).await.unwrap();
+            if count == 0 {
+                break;
+            }
+            echoed_clone.borrow_mut().extend_from_slice(&buf[..count]);
+        }
+    };
+    run(vec![Box::pin(server), Box::pin(client)]);
+
+    assert!(*echoed.borrow() == expected);
+}
+
+#[test]
+fn tcp_connect_refused() {
+    let addr = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+    run(vec![Box::pin(async move {
+        let err = TcpStream::connect(addr).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    })]);
+}
@@ -0,0 +1,119 @@
+// A panicking task is dropped without stopping the others.
+
+mod common;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use common::*;
+use futures_ex::task;
+use futures_ex::{FdExecutor, InterfaceState};
+
+fn executor(futures: Vec<Pin<Box<dyn Future<Output = ()>>>>) -> FdExecutor {
+    FdExecutor::new(futures, Arc::new(RefCell::new(InterfaceState::new())))
+}
+
+#[test]
+fn other_tasks_keep_running() {
+    let (rx, tx) = pipe();
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let done = Rc::new(RefCell::new(false));
+    let done_clone = done.clone();
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![
+        Box::pin(async move {
+            assert_eq!(read_byte(&rx, state_clone).await, 7);
+            *done_clone.borrow_mut() = true;
+        }),
+        Box::pin(task::named("bad", async move {
+            yield_now().await;
+            write_byte(&tx, 7);
+            panic!("device fell over");
+        })),
+    ];
+    let panics = Rc::new(RefCell::new(Vec::new()));
+    let panics_clone = panics.clone();
+    let mut ex = FdExecutor::new(futures, state);
+    ex.set_panic_hook(move |p| panics_clone.borrow_mut().push(p.to_string()));
+    ex.run();
+
+    assert!(*done.borrow());
+    assert_eq!(
+        *panics.borrow(),
+        vec!["task 1 (bad) panicked: device fell over"]
+    );
+    let stats = ex.stats();
+    assert_eq!(stats.panicked_tasks, 1);
+    assert_eq!(stats.completed_tasks, 1);
+}
+
+#[test]
+fn join_handle_gets_panic() {
+    let results = Rc::new(RefCell::new(Vec::new()));
+    let results_clone = results.clone();
+    let panics = Rc::new(RefCell::new(0));
+    let panics_clone = panics.clone();
+    let mut ex = executor(vec![Box::pin(async move {
+        let ok = task::spawn(async { 5 }).unwrap();
+        let bad = task::spawn(task::named("worker", async {
+            yield_now().await;
+            panic!("oops {}", 3);
+        }))
+        .unwrap();
+        let value = ok.await.unwrap();
+        results_clone.borrow_mut().push(value.to_string());
+        let p = bad.await.unwrap_err();
+        assert_eq!(p.message(), Some("oops 3"));
+        results_clone.borrow_mut().push(p.to_string());
+    })]);
+    ex.set_panic_hook(move |_| *panics_clone.borrow_mut() += 1);
+    ex.run();
+
+    assert_eq!(
+        *results.borrow(),
+        vec!["5", "task 2 (worker) panicked: oops 3"]
+    );
+    // Joined panics don't reach the hook.
+    assert_eq!(*panics.borrow(), 0);
+    assert_eq!(ex.stats().panicked_tasks, 0);
+}
+
+#[test]
+fn detached_panic_goes_to_hook() {
+    let panics = Rc::new(RefCell::new(Vec::new()));
+    let panics_clone = panics.clone();
+    let mut ex = executor(vec![Box::pin(async {
+        drop(task::spawn(async { panic!("detached") }).unwrap());
+    })]);
+    ex.set_panic_hook(move |p| {
+        panics_clone
+            .borrow_mut()
+            .push(p.message().map(String::from))
+    });
+    ex.run();
+
+    assert_eq!(*panics.borrow(), vec![Some("detached".to_string())]);
+}
+
+#[test]
+fn run_until_panicked_future() {
+    let mut ex = executor(Vec::new());
+    assert_eq!(ex.run_until(async { panic!("gone") }), None::<()>);
+    assert_eq!(ex.run_until(async { 1 }), Some(1));
+}
+
+#[test]
+fn run_until_panicked_future_with_pending_task() {
+    // Nothing is ever written, so the reader stays pending.
+    let (rx, _tx) = pipe();
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        read_byte(&rx, state_clone).await;
+    })];
+    let mut ex = FdExecutor::new(futures, state);
+    assert_eq!(ex.run_until(async { panic!("gone") }), None::<()>);
+}
@@ -0,0 +1,159 @@
+// Runs `Send` tasks on `PoolExecutor` worker threads.
+
+mod common;
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use common::*;
+use futures_ex::pool::{self, PoolExecutor};
+use futures_ex::reactor;
+
+// Sets the flag when dropped, to check that shutdown runs task destructors.
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn runs_all_tasks() {
+    let mut ex = PoolExecutor::new(4);
+    let count = Arc::new(AtomicUsize::new(0));
+    for _ in 0..100 {
+        let count = count.clone();
+        ex.spawn(async move {
+            yield_now().await;
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    ex.run();
+    assert_eq!(count.load(Ordering::SeqCst), 100);
+}
+
+#[test]
+fn spreads_across_workers() {
+    let mut ex = PoolExecutor::new(4);
+    let threads = Arc::new(Mutex::new(HashSet::new()));
+    for _ in 0..8 {
+        let threads = threads.clone();
+        ex.spawn(async move {
+            // Hog the worker so the others have to steal.
+            thread::sleep(Duration::from_millis(20));
+            threads.lock().unwrap().insert(thread::current().id());
+        });
+    }
+    ex.run();
+    assert!(threads.lock().unwrap().len() > 1);
+}
+
+#[test]
+fn spawn_from_task() {
+    let mut ex = PoolExecutor::new(2);
+    let handle = ex.handle();
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_clone = count.clone();
+    ex.spawn(async move {
+        for _ in 0..10 {
+            let count = count_clone.clone();
+            handle.spawn(async move {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    });
+    ex.run();
+    assert_eq!(count.load(Ordering::SeqCst), 10);
+}
+
+#[test]
+fn wait_for_pipes() {
+    const PIPES: usize = 8;
+
+    let mut ex = PoolExecutor::new(3);
+    let total = Arc::new(AtomicUsize::new(0));
+    let mut writers = Vec::new();
+    for i in 0..PIPES {
+        let (rx, tx) = pipe();
+        writers.push(tx);
+        let total = total.clone();
+        ex.spawn(async move {
+            pool::wait_readable(&rx).await.unwrap();
+            let mut b = 0u8;
+            // Safe because `b` is a valid one byte buffer.
+            let ret = unsafe { libc::read(rx.0, &mut b as *mut u8 as *mut _, 1) };
+            assert_eq!(ret, 1);
+            assert_eq!(b as usize, i);
+            total.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    let writer = thread::spawn(move || {
+        for (i, tx) in writers.iter().enumerate() {
+            thread::sleep(Duration::from_millis(2));
+            write_byte(tx, i as u8);
+        }
+    });
+    ex.run();
+    writer.join().unwrap();
+    assert_eq!(total.load(Ordering::SeqCst), PIPES);
+}
+
+#[test]
+fn shutdown_drops_tasks() {
+    let mut ex = PoolExecutor::new(2);
+    let handle = ex.handle();
+    let (rx, _tx) = pipe();
+    let dropped = Arc::new(AtomicBool::new(false));
+
+    let flag = DropFlag(dropped.clone());
+    ex.spawn(async move {
+        let _flag = flag;
+        // Never written, only shutdown ends this task.
+        let _ = pool::wait_readable(&rx).await;
+        panic!("pipe should never be readable");
+    });
+    let stopper = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        handle.shutdown();
+    });
+    ex.run();
+    stopper.join().unwrap();
+
+    assert!(ex.handle().is_shutdown());
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn panic_stops_pool() {
+    let mut ex = PoolExecutor::new(2);
+    let (rx, _tx) = pipe();
+    ex.spawn(async move {
+        let _ = pool::wait_readable(&rx).await;
+    });
+    ex.spawn(async {
+        panic!("task failed");
+    });
+    let result = panic::catch_unwind(AssertUnwindSafe(|| ex.run()));
+    assert!(result.is_err());
+    assert!(ex.handle().is_shutdown());
+}
+
+#[test]
+fn no_pool_outside_worker() {
+    let (rx, _tx) = pipe();
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut wait = pool::wait_readable(&rx);
+    assert_eq!(
+        Pin::new(&mut wait).poll(&mut cx),
+        Poll::Ready(Err(reactor::Error::NoReactor))
+    );
+}
@@ -0,0 +1,116 @@
+// Child processes spawned with `process::spawn`, awaited next to other tasks.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::os::unix::process::ExitStatusExt;
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures_ex::process::spawn;
+use futures_ex::reactor;
+use futures_ex::{FdExecutor, InterfaceState};
+
+fn run(futures: Vec<Pin<Box<dyn Future<Output = ()>>>>) -> Arc<RefCell<InterfaceState>> {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    FdExecutor::new(futures, state.clone()).run();
+    state
+}
+
+#[test]
+fn exit_code_and_output() {
+    let result = Rc::new(RefCell::new(None));
+    let result_clone = result.clone();
+    let state = run(vec![Box::pin(async move {
+        let child = spawn(
+            Command::new("sh")
+                .arg("-c")
+                .arg("echo out; echo err >&2; exit 3")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )
+        .unwrap();
+        *result_clone.borrow_mut() = Some(child.wait_with_output().await.unwrap());
+    })]);
+
+    let output = result.borrow_mut().take().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn pipe_through_stdin() {
+    let result = Rc::new(RefCell::new(Vec::new()));
+    let result_clone = result.clone();
+    run(vec![Box::pin(async move {
+        let mut child = spawn(
+            Command::new("cat")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped()),
+        )
+        .unwrap();
+        // Bigger than a pipe buffer so the write has to wait for cat to read.
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        reactor::spawn(Box::pin(async move {
+            stdin.write_all(&data).await.unwrap();
+        }))
+        .unwrap();
+        let mut out = Vec::new();
+        stdout.read_to_end(&mut out).await.unwrap();
+        assert!(child.wait().await.unwrap().success());
+        *result_clone.borrow_mut() = out;
+    })]);
+
+    let result = result.borrow();
+    assert_eq!(result.len(), 200_000);
+    assert!(result.iter().enumerate().all(|(i, &b)| b == i as u8));
+}
+
+#[test]
+fn wait_with_output_closes_stdin() {
+    let result = Rc::new(RefCell::new(None));
+    let result_clone = result.clone();
+    run(vec![Box::pin(async move {
+        // cat only exits once its stdin is closed.
+        let child = spawn(
+            Command::new("cat")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped()),
+        )
+        .unwrap();
+        *result_clone.borrow_mut() = Some(child.wait_with_output().await.unwrap());
+    })]);
+
+    let output = result.borrow_mut().take().unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn kill_and_wait_concurrently() {
+    let statuses = Rc::new(RefCell::new(Vec::new()));
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    let statuses_clone = statuses.clone();
+    futures.push(Box::pin(async move {
+        let mut child = spawn(Command::new("sleep").arg("10")).unwrap();
+        child.kill().unwrap();
+        let status = child.wait().await.unwrap();
+        statuses_clone.borrow_mut().push(status.signal());
+    }));
+    let statuses_clone = statuses.clone();
+    futures.push(Box::pin(async move {
+        let mut child = spawn(&mut Command::new("true")).unwrap();
+        let status = child.wait().await.unwrap();
+        statuses_clone.borrow_mut().push(status.code());
+    }));
+    run(futures);
+
+    let mut statuses = statuses.borrow().clone();
+    statuses.sort();
+    assert_eq!(statuses, vec![Some(0), Some(libc::SIGKILL)]);
+}
@@ -0,0 +1,93 @@
+// Leaf futures that find the executor through the thread's reactor context.
+
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use common::*;
+use futures_ex::reactor::{self, wait_readable, wait_writable};
+use futures_ex::{read_at, FdExecutor, InterfaceState};
+
+fn new_state() -> Arc<RefCell<InterfaceState>> {
+    Arc::new(RefCell::new(InterfaceState::new()))
+}
+
+#[test]
+fn wait_for_pipe() {
+    let state = new_state();
+    let (rx, tx) = pipe();
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    let order_clone = order.clone();
+    let reader = async move {
+        wait_readable(&rx).await.unwrap();
+        order_clone.borrow_mut().push("readable");
+    };
+    let order_clone = order.clone();
+    let writer = async move {
+        wait_writable(&tx).await.unwrap();
+        order_clone.borrow_mut().push("writable");
+        write_byte(&tx, 1);
+    };
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(reader), Box::pin(writer)];
+    FdExecutor::new(futures, state.clone()).run();
+
+    assert_eq!(*order.borrow(), vec!["writable", "readable"]);
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn spawn_from_task() {
+    let state = new_state();
+    let ran = Rc::new(Cell::new(false));
+
+    let ran_clone = ran.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        reactor::spawn(Box::pin(async move {
+            ran_clone.set(true);
+        }))
+        .unwrap();
+    })];
+    FdExecutor::new(futures, state).run();
+
+    assert!(ran.get());
+}
+
+#[test]
+fn no_reactor_outside_executor() {
+    let (rx, _tx) = pipe();
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    assert_eq!(reactor::current().err(), Some(reactor::Error::NoReactor));
+    assert_eq!(
+        reactor::spawn(Box::pin(async {})),
+        Err(reactor::Error::NoReactor)
+    );
+    let mut wait = wait_readable(&rx);
+    assert_eq!(
+        Pin::new(&mut wait).poll(&mut cx),
+        Poll::Ready(Err(reactor::Error::NoReactor))
+    );
+    let mut read = read_at(&rx, 0, vec![0u8; 1]);
+    match Pin::new(&mut read).poll(&mut cx) {
+        Poll::Ready(Err(e)) => assert!(e.to_string().contains("outside of an FdExecutor")),
+        _ => panic!("read_at should fail without a reactor"),
+    }
+}
+
+#[test]
+fn reactor_cleared_after_run() {
+    let state = new_state();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async {
+        assert!(reactor::current().is_ok());
+    })];
+    FdExecutor::new(futures, state).run();
+
+    assert!(reactor::current().is_err());
+}
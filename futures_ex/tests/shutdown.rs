@@ -0,0 +1,164 @@
+// Stopping the executor early: `ExecutorHandle::shutdown`, `run_until` and `CancellationToken`.
+
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Wake, Waker};
+use std::thread;
+use std::time::Duration;
+
+use common::*;
+use futures_ex::{CancellationToken, FdExecutor, FdExecutorInterface, InterfaceState};
+
+// Sets the flag when dropped, to check that shutdown runs task destructors.
+struct DropFlag(Rc<Cell<bool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.set(true);
+    }
+}
+
+#[test]
+fn shutdown_from_task() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, _tx) = pipe();
+    let dropped = Rc::new(Cell::new(false));
+
+    let mut ex = FdExecutor::new(Vec::new(), state.clone());
+    let handle = ex.handle();
+
+    let flag = DropFlag(dropped.clone());
+    let state_clone = state.clone();
+    let waiter = async move {
+        let _flag = flag;
+        // Never written, only shutdown ends this task.
+        read_byte(&rx, state_clone).await;
+        panic!("pipe should never be readable");
+    };
+    let stopper = async move {
+        handle.shutdown();
+    };
+    state.borrow_mut().add_future(Box::pin(waiter));
+    state.borrow_mut().add_future(Box::pin(stopper));
+    ex.run();
+
+    assert!(dropped.get());
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn shutdown_from_other_thread() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, _tx) = pipe();
+
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        read_byte(&rx, state_clone).await;
+    })];
+    let mut ex = FdExecutor::new(futures, state.clone());
+    let handle = ex.handle();
+    let stopper = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        handle.shutdown();
+    });
+    ex.run();
+    stopper.join().unwrap();
+
+    assert!(ex.handle().is_shutdown());
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn run_until_leaves_other_tasks() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, tx) = pipe();
+    let (other_rx, other_tx) = pipe();
+    let other_done = Rc::new(Cell::new(false));
+
+    let state_clone = state.clone();
+    let other_done_clone = other_done.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        read_byte(&other_rx, state_clone).await;
+        other_done_clone.set(true);
+    })];
+    let mut ex = FdExecutor::new(futures, state.clone());
+
+    write_byte(&tx, 5);
+    let state_clone = state.clone();
+    let value = ex.run_until(async move { read_byte(&rx, state_clone).await });
+    assert_eq!(value, Some(5));
+    assert!(!other_done.get());
+
+    // The other task is still there and finishes on the next run.
+    write_byte(&other_tx, 0);
+    ex.run();
+    assert!(other_done.get());
+}
+
+#[test]
+fn run_until_after_shutdown() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, _tx) = pipe();
+    let mut ex = FdExecutor::new(Vec::new(), state.clone());
+    let handle = ex.handle();
+
+    let state_clone = state.clone();
+    let value = ex.run_until(async move {
+        handle.shutdown();
+        read_byte(&rx, state_clone).await
+    });
+    assert_eq!(value, None);
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn cancellation_token() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let token = CancellationToken::new();
+    let stopped = Rc::new(Cell::new(0));
+
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for _ in 0..3 {
+        let token = token.clone();
+        let stopped = stopped.clone();
+        futures.push(Box::pin(async move {
+            token.cancelled().await;
+            stopped.set(stopped.get() + 1);
+        }));
+    }
+    let token_clone = token.clone();
+    futures.push(Box::pin(async move {
+        token_clone.cancel();
+    }));
+    FdExecutor::new(futures, state).run();
+
+    assert!(token.is_cancelled());
+    assert_eq!(stopped.get(), 3);
+}
+
+#[test]
+fn dropped_cancelled_releases_waker() {
+    // Counts live clones of itself, so a waker left behind by a dropped future shows up.
+    struct CountingWaker;
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let token = CancellationToken::new();
+    let counter = Arc::new(CountingWaker);
+    let waker = Waker::from(counter.clone());
+    for _ in 0..10 {
+        let mut cancelled = Box::pin(token.cancelled());
+        let mut cx = Context::from_waker(&waker);
+        assert!(cancelled.as_mut().poll(&mut cx).is_pending());
+        assert!(cancelled.as_mut().poll(&mut cx).is_pending());
+    }
+    drop(waker);
+    assert_eq!(Arc::strong_count(&counter), 1);
+}
@@ -0,0 +1,76 @@
+// Signals read through `signals` while the executor runs other tasks. Tests raise signals on their
+// own thread, the test harness runs other tests on threads that don't block them.
+
+mod common;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use common::*;
+use futures_ex::signal::signals;
+use futures_ex::{FdExecutor, InterfaceState};
+
+fn is_blocked(signal: libc::c_int) -> bool {
+    // Safe because the set is initialized by pthread_sigmask before it's read.
+    unsafe {
+        let mut mask: libc::sigset_t = mem::zeroed();
+        libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), &mut mask);
+        libc::sigismember(&mask, signal) == 1
+    }
+}
+
+#[test]
+fn wait_for_signal() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let mut stream = signals(&[libc::SIGUSR1, libc::SIGUSR2]).unwrap();
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    let received_clone = received.clone();
+    let waiter = async move {
+        for _ in 0..2 {
+            let info = stream.recv().await.unwrap();
+            received_clone.borrow_mut().push(info);
+        }
+    };
+    let raiser = async {
+        yield_now().await;
+        // Safe because both signals are blocked and read from the signalfd.
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+            libc::raise(libc::SIGUSR1);
+        }
+    };
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(waiter), Box::pin(raiser)];
+    FdExecutor::new(futures, state.clone()).run();
+
+    let received = received.borrow();
+    let mut signals: Vec<_> = received.iter().map(|info| info.signal).collect();
+    signals.sort();
+    assert_eq!(signals, vec![libc::SIGUSR1, libc::SIGUSR2]);
+    assert!(received.iter().all(|info| info.pid == std::process::id()));
+    assert_eq!(state.borrow().waker_count(), 0);
+}
+
+#[test]
+fn try_recv_and_unblock_on_drop() {
+    assert!(!is_blocked(libc::SIGUSR2));
+    let mut stream = signals(&[libc::SIGUSR2]).unwrap();
+    assert!(is_blocked(libc::SIGUSR2));
+    assert_eq!(stream.try_recv().unwrap(), None);
+
+    // Safe because the signal is blocked and read from the signalfd.
+    unsafe {
+        libc::raise(libc::SIGUSR2);
+    }
+    let info = stream.try_recv().unwrap().unwrap();
+    assert_eq!(info.signal, libc::SIGUSR2);
+    assert_eq!(stream.try_recv().unwrap(), None);
+
+    drop(stream);
+    assert!(!is_blocked(libc::SIGUSR2));
+}
@@ -0,0 +1,141 @@
+// The deterministic executor: seeded polling order, injected fd readiness and virtual time.
+
+mod common;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use common::*;
+use futures_ex::sim::{sleep, SimExecutor, SimFd, SimState, Stalled};
+use futures_ex::FdExecutorInterface;
+
+// Waits for one wakeup from `fd` becoming readable, talking only to the interface.
+struct WaitReadable {
+    fd: SimFd,
+    state: Rc<RefCell<SimState>>,
+    registered: bool,
+}
+
+impl Future for WaitReadable {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.registered {
+            return Poll::Ready(());
+        }
+        let fd = self.fd;
+        self.state.borrow_mut().add_waker(&fd, cx.waker().clone());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+// Runs three tasks that each log their number around a yield and returns the log.
+fn interleaving(seed: u64) -> Vec<u32> {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut ex = SimExecutor::new(seed);
+    for i in 0..3 {
+        let log = log.clone();
+        ex.spawn(Box::pin(async move {
+            log.borrow_mut().push(i);
+            yield_now().await;
+            log.borrow_mut().push(i + 10);
+        }));
+    }
+    ex.run().unwrap();
+    let log = log.borrow().clone();
+    log
+}
+
+#[test]
+fn seed_decides_order() {
+    assert_eq!(interleaving(7), interleaving(7));
+    let orders: Vec<Vec<u32>> = (0..16).map(interleaving).collect();
+    assert!(orders.iter().any(|order| *order != orders[0]));
+}
+
+#[test]
+fn injected_readiness() {
+    let mut ex = SimExecutor::new(0);
+    let state = ex.state();
+    let fd = state.borrow_mut().new_fd();
+    let done = Rc::new(RefCell::new(false));
+    let done_clone = done.clone();
+    let state_clone = state.clone();
+    ex.spawn(Box::pin(async move {
+        WaitReadable {
+            fd,
+            state: state_clone,
+            registered: false,
+        }
+        .await;
+        *done_clone.borrow_mut() = true;
+    }));
+
+    ex.run_until_stalled();
+    assert!(!*done.borrow());
+    assert_eq!(state.borrow().waker_count(), 1);
+
+    state.borrow_mut().set_readable(&fd, true);
+    ex.run_until_stalled();
+    assert!(*done.borrow());
+    assert_eq!(state.borrow().waker_count(), 0);
+    assert_eq!(ex.task_count(), 0);
+}
+
+#[test]
+fn virtual_time() {
+    let mut ex = SimExecutor::new(3);
+    let state = ex.state();
+    let woke = Rc::new(RefCell::new(Vec::new()));
+    for secs in [30, 10, 20] {
+        let state = state.clone();
+        let woke = woke.clone();
+        ex.spawn(Box::pin(async move {
+            sleep(&state, Duration::from_secs(secs)).await;
+            woke.borrow_mut().push((secs, state.borrow().now()));
+        }));
+    }
+
+    let start = Instant::now();
+    ex.run_until_stalled();
+    ex.advance(Duration::from_secs(15));
+    assert_eq!(*woke.borrow(), vec![(10, Duration::from_secs(15))]);
+    ex.run().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(
+        *woke.borrow(),
+        vec![
+            (10, Duration::from_secs(15)),
+            (20, Duration::from_secs(20)),
+            (30, Duration::from_secs(30)),
+        ]
+    );
+}
+
+#[test]
+fn stalled() {
+    let mut ex = SimExecutor::new(0);
+    let state = ex.state();
+    let fd = state.borrow_mut().new_fd();
+    ex.spawn(Box::pin(async {}));
+    ex.spawn(Box::pin(WaitReadable {
+        fd,
+        state: state.clone(),
+        registered: false,
+    }));
+    assert_eq!(ex.run(), Err(Stalled { tasks: 1 }));
+
+    // Readiness set before waiting wakes right away.
+    state.borrow_mut().set_readable(&fd, true);
+    ex.spawn(Box::pin(WaitReadable {
+        fd,
+        state,
+        registered: false,
+    }));
+    assert_eq!(ex.run(), Ok(()));
+}
@@ -0,0 +1,92 @@
+// Poll counts, wait times and the task dump collected with tracing enabled.
+
+mod common;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::*;
+use futures_ex::{FdExecutor, InterfaceState};
+
+#[test]
+fn counts_polls_and_waits() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, tx) = pipe();
+
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![
+        Box::pin(async {
+            yield_now().await;
+            yield_now().await;
+        }),
+        Box::pin(async move {
+            read_byte(&rx, state_clone).await;
+        }),
+    ];
+    let mut ex = FdExecutor::new(futures, state);
+    ex.set_tracing(true);
+    let writer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        write_byte(&tx, 1);
+    });
+    ex.run();
+    writer.join().unwrap();
+
+    let stats = ex.stats();
+    assert!(stats.tasks.is_empty());
+    assert_eq!(stats.completed_tasks, 2);
+    // Three polls for the yielding task, two for the reader.
+    assert_eq!(stats.polls, 5);
+    assert!(stats.waits >= 1);
+    assert!(stats.blocked_time >= Duration::from_millis(10));
+}
+
+#[test]
+fn tracing_off_by_default() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async {
+        yield_now().await;
+    })];
+    let mut ex = FdExecutor::new(futures, state);
+    ex.run();
+
+    let stats = ex.stats();
+    assert_eq!(stats.completed_tasks, 1);
+    assert_eq!(stats.polls, 0);
+}
+
+#[test]
+fn dump_shows_waits() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let (rx, _tx) = pipe();
+    let rx_fd = rx.0;
+
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![
+        // Waits on a pipe that is never written.
+        Box::pin(async move {
+            read_byte(&rx, state_clone).await;
+        }),
+        // Waits for a wake that never comes.
+        Box::pin(PollOnce(Some(Box::pin(std::future::pending::<()>())))),
+        Box::pin(std::future::pending::<()>()),
+    ];
+    let mut ex = FdExecutor::new(futures, state);
+    ex.set_tracing(true);
+    assert_eq!(ex.run_until(async { yield_now().await }), Some(()));
+
+    let stats = ex.stats();
+    let ids: Vec<_> = stats.tasks.iter().map(|t| t.id).collect();
+    assert_eq!(ids, vec![0, 2]);
+    assert!(stats.tasks.iter().all(|t| t.polls == 1));
+
+    let dump = ex.dump();
+    assert!(dump.contains("task 0: 1 polls"));
+    assert!(dump.contains(&format!("waiting on fd {} (read)", rx_fd)));
+    assert!(dump.contains("task 2: 1 polls"));
+    assert!(dump.contains("not waiting on any fd"));
+}
@@ -0,0 +1,213 @@
+// Channels and synchronization primitives shared between tasks on one executor.
+
+mod common;
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::*;
+use futures_ex::sync::{mpsc, oneshot, Mutex, Notify, Semaphore, ThreadNotify};
+use futures_ex::{FdExecutor, InterfaceState};
+
+fn run(futures: Vec<Pin<Box<dyn Future<Output = ()>>>>) {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    FdExecutor::new(futures, state).run();
+}
+
+#[test]
+fn mpsc_bounded_backpressure() {
+    let (tx, mut rx) = mpsc::channel(2);
+    let sent = Rc::new(Cell::new(0));
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    let sent_clone = sent.clone();
+    let producer = async move {
+        for i in 0..10 {
+            tx.send(i).await.unwrap();
+            sent_clone.set(sent_clone.get() + 1);
+        }
+    };
+    let sent_clone = sent.clone();
+    let received_clone = received.clone();
+    let consumer = async move {
+        // Let the producer fill the channel first.
+        yield_now().await;
+        assert_eq!(sent_clone.get(), 2);
+        while let Some(v) = rx.recv().await {
+            received_clone.borrow_mut().push(v);
+        }
+    };
+    run(vec![Box::pin(producer), Box::pin(consumer)]);
+
+    assert_eq!(*received.borrow(), (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn mpsc_multiple_senders() {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let total = Rc::new(Cell::new(0));
+
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for i in 1..=4 {
+        let tx = tx.clone();
+        futures.push(Box::pin(async move {
+            yield_now().await;
+            tx.send(i).await.unwrap();
+        }));
+    }
+    drop(tx);
+    let total_clone = total.clone();
+    futures.push(Box::pin(async move {
+        while let Some(v) = rx.recv().await {
+            total_clone.set(total_clone.get() + v);
+        }
+    }));
+    run(futures);
+
+    assert_eq!(total.get(), 10);
+}
+
+#[test]
+fn mpsc_receiver_dropped() {
+    let (tx, rx) = mpsc::channel(1);
+    drop(rx);
+    assert_eq!(tx.try_send(1), Err(mpsc::TrySendError::Closed(1)));
+    run(vec![Box::pin(async move {
+        assert_eq!(tx.send(2).await, Err(mpsc::SendError(2)));
+    })]);
+}
+
+#[test]
+fn oneshot_send_and_drop() {
+    let (tx, rx) = oneshot::channel();
+    let (dropped_tx, dropped_rx) = oneshot::channel::<u32>();
+    let result = Rc::new(RefCell::new(None));
+
+    let result_clone = result.clone();
+    let receiver = async move {
+        let value = rx.await.unwrap();
+        assert_eq!(dropped_rx.await, Err(oneshot::RecvError));
+        *result_clone.borrow_mut() = Some(value);
+    };
+    let sender = async move {
+        yield_now().await;
+        tx.send(42).unwrap();
+        drop(dropped_tx);
+    };
+    run(vec![Box::pin(receiver), Box::pin(sender)]);
+
+    assert_eq!(*result.borrow(), Some(42));
+}
+
+#[test]
+fn mutex_held_across_await() {
+    let mutex = Rc::new(Mutex::new(Vec::new()));
+
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for i in 0..3 {
+        let mutex = mutex.clone();
+        futures.push(Box::pin(async move {
+            let mut guard = mutex.lock().await;
+            guard.push(i);
+            // Other tasks run but can't take the lock until this one is done.
+            yield_now().await;
+            guard.push(i);
+        }));
+    }
+    run(futures);
+
+    let mutex = Rc::try_unwrap(mutex).ok().unwrap();
+    assert_eq!(mutex.into_inner(), vec![0, 0, 1, 1, 2, 2]);
+}
+
+#[test]
+fn semaphore_limits_concurrency() {
+    let semaphore = Rc::new(Semaphore::new(2));
+    let active = Rc::new(Cell::new(0));
+    let max_active = Rc::new(Cell::new(0));
+
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for _ in 0..6 {
+        let semaphore = semaphore.clone();
+        let active = active.clone();
+        let max_active = max_active.clone();
+        futures.push(Box::pin(async move {
+            let _permit = semaphore.acquire().await;
+            active.set(active.get() + 1);
+            max_active.set(max_active.get().max(active.get()));
+            yield_now().await;
+            active.set(active.get() - 1);
+        }));
+    }
+    run(futures);
+
+    assert_eq!(max_active.get(), 2);
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test]
+fn notify_one_and_waiters() {
+    let notify = Rc::new(Notify::new());
+    let woken = Rc::new(Cell::new(0));
+
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for _ in 0..3 {
+        let notify = notify.clone();
+        let woken = woken.clone();
+        futures.push(Box::pin(async move {
+            notify.notified().await;
+            woken.set(woken.get() + 1);
+        }));
+    }
+    let notify_clone = notify.clone();
+    let woken_clone = woken.clone();
+    futures.push(Box::pin(async move {
+        notify_clone.notify_one();
+        yield_now().await;
+        yield_now().await;
+        assert_eq!(woken_clone.get(), 1);
+        notify_clone.notify_waiters();
+    }));
+    run(futures);
+    assert_eq!(woken.get(), 3);
+
+    // A notification with nobody waiting is kept for the next wait.
+    notify.notify_one();
+    let notify_clone = notify.clone();
+    run(vec![Box::pin(async move {
+        notify_clone.notified().await;
+    })]);
+}
+
+#[test]
+fn thread_notify() {
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let notify = Arc::new(ThreadNotify::new().unwrap());
+    let count = Rc::new(Cell::new(0));
+
+    let notify_clone = notify.clone();
+    let notifier = thread::spawn(move || {
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(5));
+            notify_clone.notify();
+        }
+    });
+
+    let count_clone = count.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        while count_clone.get() < 3 {
+            let n = notify.notified().await.unwrap();
+            count_clone.set(count_clone.get() + n);
+        }
+    })];
+    FdExecutor::new(futures, state.clone()).run();
+    notifier.join().unwrap();
+
+    assert_eq!(count.get(), 3);
+    assert_eq!(state.borrow().waker_count(), 0);
+}
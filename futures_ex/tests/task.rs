@@ -0,0 +1,77 @@
+// Task names and task-local values seen from nested futures.
+
+mod common;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use common::*;
+use futures_ex::reactor;
+use futures_ex::task::{self, Error};
+use futures_ex::{FdExecutor, InterfaceState};
+
+#[derive(Clone, Debug, PartialEq)]
+struct DeviceId(u32);
+
+async fn device_id() -> Option<u32> {
+    yield_now().await;
+    task::with_local(|id: &DeviceId| id.0)
+}
+
+#[test]
+fn locals_are_per_task() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+    for i in 0..3 {
+        let seen = seen.clone();
+        futures.push(Box::pin(async move {
+            task::set_local(DeviceId(i)).unwrap();
+            yield_now().await;
+            let nested = device_id().await;
+            seen.borrow_mut().push((i, nested, task::local::<String>()));
+        }));
+    }
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    FdExecutor::new(futures, state).run();
+
+    let mut seen = seen.borrow().clone();
+    seen.sort();
+    assert_eq!(
+        seen,
+        vec![(0, Some(0), None), (1, Some(1), None), (2, Some(2), None)]
+    );
+}
+
+#[test]
+fn names_in_stats_and_dump() {
+    let (rx, _tx) = pipe();
+    let state = Arc::new(RefCell::new(InterfaceState::new()));
+    let state_clone = state.clone();
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(async move {
+        assert_eq!(task::name(), None);
+        reactor::spawn(Box::pin(task::named("blk0", async move {
+            assert_eq!(task::name().as_deref(), Some("blk0"));
+            read_byte(&rx, state_clone).await;
+        })))
+        .unwrap();
+    })];
+    let mut ex = FdExecutor::new(futures, state);
+    ex.run_until(yield_now());
+
+    let stats = ex.stats();
+    assert_eq!(stats.tasks.len(), 1);
+    assert_eq!(stats.tasks[0].name.as_deref(), Some("blk0"));
+    assert!(ex.dump().contains("(blk0)"), "{}", ex.dump());
+}
+
+#[test]
+fn outside_a_task() {
+    assert_eq!(task::set_local(DeviceId(1)), Err(Error::NoTask));
+    assert_eq!(task::set_name("x"), Err(Error::NoTask));
+    assert_eq!(task::id(), Err(Error::NoTask));
+    assert_eq!(task::local::<DeviceId>(), None);
+    assert_eq!(task::name(), None);
+}
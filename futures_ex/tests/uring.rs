@@ -0,0 +1,102 @@
+// File I/O through `read_at`, `write_at` and `fsync`, run against both the io_uring backend and the
+// epoll fallback.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures_ex::{fsync, read_at, write_at, FdExecutor, InterfaceState};
+use tempfile::tempfile;
+
+fn backends() -> Vec<InterfaceState> {
+    vec![InterfaceState::new(), InterfaceState::with_io_uring()]
+}
+
+fn run_one(state: Arc<RefCell<InterfaceState>>, future: impl Future<Output = ()> + 'static) {
+    let futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = vec![Box::pin(future)];
+    FdExecutor::new(futures, state).run();
+}
+
+#[test]
+fn write_then_read() {
+    for state in backends() {
+        let state = Arc::new(RefCell::new(state));
+        let file = tempfile().unwrap();
+        let result = Rc::new(RefCell::new(Vec::new()));
+
+        let result_clone = result.clone();
+        run_one(state.clone(), async move {
+            let data = vec![0x55u8; 4096];
+            let (count, _) = write_at(&file, 512, data).await.unwrap();
+            assert_eq!(count, 4096);
+            fsync(&file).await.unwrap();
+            let (count, buf) = read_at(&file, 0, vec![0u8; 1024]).await.unwrap();
+            assert_eq!(count, 1024);
+            *result_clone.borrow_mut() = buf;
+        });
+
+        let result = result.borrow();
+        assert!(result[..512].iter().all(|&b| b == 0));
+        assert!(result[512..].iter().all(|&b| b == 0x55));
+    }
+}
+
+#[test]
+fn read_past_end() {
+    for state in backends() {
+        let state = Arc::new(RefCell::new(state));
+        let mut file = tempfile().unwrap();
+        file.write_all(b"abcd").unwrap();
+
+        run_one(state.clone(), async move {
+            let (count, buf) = read_at(&file, 2, vec![0u8; 8]).await.unwrap();
+            assert_eq!(count, 2);
+            assert_eq!(&buf[..2], b"cd");
+            let (count, _) = read_at(&file, 100, vec![0u8; 8]).await.unwrap();
+            assert_eq!(count, 0);
+        });
+    }
+}
+
+#[test]
+fn bad_fd() {
+    struct BadFd;
+    impl AsRawFd for BadFd {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
+    for state in backends() {
+        let state = Arc::new(RefCell::new(state));
+        run_one(state.clone(), async move {
+            let err = read_at(&BadFd, 0, vec![0u8; 8]).await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        });
+    }
+}
+
+#[test]
+fn concurrent_ops() {
+    const COUNT: usize = 32;
+
+    for state in backends() {
+        let state = Arc::new(RefCell::new(state));
+        let file = Rc::new(tempfile().unwrap());
+        let mut futures: Vec<Pin<Box<dyn Future<Output = ()>>>> = Vec::new();
+        for i in 0..COUNT {
+            let file = file.clone();
+            futures.push(Box::pin(async move {
+                let offset = (i * 64) as u64;
+                write_at(&*file, offset, vec![i as u8; 64]).await.unwrap();
+                let (_, buf) = read_at(&*file, offset, vec![0u8; 64]).await.unwrap();
+                assert!(buf.iter().all(|&b| b == i as u8));
+            }));
+        }
+        FdExecutor::new(futures, state).run();
+    }
+}